@@ -11,61 +11,310 @@ extern crate docopt;
 extern crate time;
 
 use graphite::carbon;
-use whisper::{ WhisperCache, Schema };
+use graphite::carbon::{ Action, Listener, Protocol, BadLinePolicy, TcpMode, FlushStrategy, WhisperCache, StorageSchemas, StorageAggregation };
+use graphite::carbon::cache_writer::Shard;
+use graphite::carbon::relay::Destination;
+use graphite::carbon::signals::{ self, Signal };
+use graphite::graphite::{ self as graphite_http, CacheHolder, MetricIndex };
 
+use std::cmp;
 use std::path::Path;
+use std::process::exit;
+use std::sync::{ Arc, RwLock };
+use std::thread;
+use std::time::Duration;
 
 use docopt::Docopt;
 static USAGE: &'static str = "
 Carbon is the network service for writing data to disk
 
 Usage:
-  carbon [--port PORT] [--bind HOST] [--chan DEPTH] [--storage-path STORAGEPATH] [--cache-size CACHESIZE]
+  carbon [--port PORT] [--bind HOST] [--pickle-bind HOST] [--listen LISTENER]... [--listeners FILE] [--chan DEPTH] [--storage-path STORAGEPATH] [--cache-size CACHESIZE] [--storage-schemas SCHEMAFILE] [--storage-aggregation AGGFILE] [--http-bind HOST] [--http-workers WORKERS] [--shutdown-timeout SECONDS] [--carbon-metric-prefix PREFIX] [--carbon-metric-interval SECONDS] [--bad-lines POLICY] [--tcp-mode MODE] [--max-connections MAX] [--idle-timeout SECONDS] [--read-buffer BYTES] [--writers WRITERS] [--max-cache-size POINTS] [--max-updates-per-second UPDATES] [--flush-strategy STRATEGY] [--relay-destinations DESTINATIONS] [--replication-factor COPIES] [--no-diverse-replicas] [--relay-queue-size LINES]
   carbon --help
 
 Options:
   -h --help                   show this screen
   --bind HOST                 host to bind to [default: 0.0.0.0:2003]
+  --pickle-bind HOST          host to bind the pickle receiver to [default: 0.0.0.0:2004]
+  --listen LISTENER           PROTOCOL:ADDRESS to listen on, e.g. `tcp:[::]:2003`, `udp:127.0.0.1:2003` or `pickle:0.0.0.0:2004`. Replaces --bind and --pickle-bind, give it once per listener
+  --listeners FILE            a file of listeners to run, one [section] each with `protocol`, `bind` and `enabled`. Replaces --bind and --pickle-bind
   --chan DEPTH                how many carbon messages can be in-flight [default: 1000]
   --storage-path STORAGEPATH  where to find the whisper file [default: /tmp]
-  --cache-size CACHESIZE      max number of open files to keep in memory [default: 60000]
+  --cache-size CACHESIZE      max number of open files to keep in memory, shared between writers [default: 60000]
+  --writers WRITERS           how many threads write whisper files, each metric always goes to the same one [default: 1]
+  --max-cache-size POINTS     how many points to hold in memory before making senders wait, shared between writers [default: 100000]
+  --max-updates-per-second UPDATES  whisper file updates per second, shared between writers, 0 for no limit [default: 0]
+  --flush-strategy STRATEGY   which metric to write next: `sorted` by points waiting, `max` points waiting or `naive` oldest first [default: sorted]
+  --storage-schemas SCHEMAFILE  storage-schemas.conf for choosing retentions of new files
+  --storage-aggregation AGGFILE  storage-aggregation.conf for choosing how new files roll up
+  --http-bind HOST            also serve the graphite HTTP API (/metrics/find, /render) on this host
+  --http-workers WORKERS      how many HTTP requests to serve at once [default: 8]
+  --shutdown-timeout SECONDS  how long to keep writing after SIGTERM/SIGINT before giving up [default: 30]
+  --carbon-metric-prefix PREFIX  where carbon reports on itself, as PREFIX.<host>.* [default: carbon.agents]
+  --carbon-metric-interval SECONDS  how often carbon reports on itself, 0 to turn it off [default: 60]
+  --bad-lines POLICY          `skip` a line that doesn't parse or `disconnect` its sender [default: skip]
+  --tcp-mode MODE             `evented` serves every TCP connection from one thread, `threaded` gives each its own [default: evented]
+  --max-connections MAX       evented and TLS only: refuse TCP connections past this many, per listener [default: 10000]
+  --idle-timeout SECONDS      evented and TLS only: close TCP connections that send nothing for this long, 0 for never [default: 300]
+  --read-buffer BYTES         evented only: read size and longest line accepted per TCP connection [default: 16384]
+  --relay-destinations DESTINATIONS  relay instead of writing: comma separated `host:port[:instance]` carbons to pass lines on to, placed like carbon-relay's consistent hashing
+  --replication-factor COPIES  relay only: how many destinations get each metric [default: 1]
+  --no-diverse-replicas       relay only: let copies of a metric go to destinations on the same host, DIVERSE_REPLICAS = False
+  --relay-queue-size LINES    relay only: lines to hold per destination while it's slow or down [default: 10000]
 ";
 
 #[derive(RustcDecodable, Debug)]
 struct Args {
     flag_bind: String,
+    flag_pickle_bind: String,
+    flag_listen: Vec<String>,
+    flag_listeners: Option<String>,
     flag_chan: usize,
     flag_storage_path: String,
-    flag_cache_size: usize
+    flag_cache_size: usize,
+    flag_storage_schemas: Option<String>,
+    flag_storage_aggregation: Option<String>,
+    flag_http_bind: Option<String>,
+    flag_http_workers: usize,
+    flag_shutdown_timeout: u64,
+    flag_carbon_metric_prefix: String,
+    flag_carbon_metric_interval: u64,
+    flag_bad_lines: String,
+    flag_tcp_mode: String,
+    flag_max_connections: usize,
+    flag_idle_timeout: u64,
+    flag_read_buffer: usize,
+    flag_writers: usize,
+    flag_max_cache_size: usize,
+    flag_max_updates_per_second: u32,
+    flag_flush_strategy: String,
+    flag_relay_destinations: Option<String>,
+    flag_replication_factor: usize,
+    flag_no_diverse_replicas: bool,
+    flag_relay_queue_size: usize
 }
 
 pub fn main(){
     env_logger::init().unwrap();
+    carbon::shutdown::install();
+    signals::install();
     let args: Args = Docopt::new(USAGE)
                             .and_then(|d| d.decode())
                             .unwrap_or_else(|e| e.exit());
 
-    let bind_spec = unsafe {
-        args.flag_bind.slice_unchecked(0, args.flag_bind.len())
-    };
+    let bad_line_policy = BadLinePolicy::from_str(&args.flag_bad_lines).unwrap_or_else(|| {
+        println!("--bad-lines must be `skip` or `disconnect`, not `{}`", args.flag_bad_lines);
+        exit(1)
+    });
+
+    let tcp_mode = TcpMode::from_str(&args.flag_tcp_mode).unwrap_or_else(|| {
+        println!("--tcp-mode must be `evented` or `threaded`, not `{}`", args.flag_tcp_mode);
+        exit(1)
+    });
+
+    let flush_strategy = FlushStrategy::from_str(&args.flag_flush_strategy).unwrap_or_else(|| {
+        println!("--flush-strategy must be `sorted`, `max` or `naive`, not `{}`", args.flag_flush_strategy);
+        exit(1)
+    });
 
     let config = carbon::Config{
-        bind_spec: bind_spec,
+        listeners: listeners(&args),
         chan_depth: args.flag_chan,
         base_path: Path::new(&args.flag_storage_path),
-        cache_size: args.flag_cache_size
+        cache_size: args.flag_cache_size,
+        max_cache_size: args.flag_max_cache_size,
+        max_updates_per_second: args.flag_max_updates_per_second,
+        flush_strategy: flush_strategy,
+        bad_line_policy: bad_line_policy,
+        tcp_mode: tcp_mode,
+        max_connections: args.flag_max_connections,
+        idle_timeout: args.flag_idle_timeout,
+        read_buffer_size: args.flag_read_buffer
+    };
+
+    let relay_config = args.flag_relay_destinations.as_ref().map(|destinations| relay_config(&args, destinations));
+    if relay_config.is_some() && args.flag_http_bind.is_some() {
+        println!("--http-bind needs local whisper files, a relay has none");
+        exit(1)
+    }
+
+    // Only the HTTP side needs to know every metric name up front
+    let index = if args.flag_http_bind.is_some() {
+        info!("indexing metrics...");
+        let index = MetricIndex::scan(config.base_path).unwrap_or_else(|reason| {
+            println!("could not index {:?}: {}", config.base_path, reason);
+            exit(1)
+        });
+        Some(Arc::new( RwLock::new(index) ))
+    } else {
+        None
+    };
+
+    // A relay writes nothing itself, so it has no shards
+    let (tx, writer, shards) = match relay_config {
+        Some(relay_config) => {
+            let (tx, relay) = carbon::relay::spawn(relay_config, config.chan_depth);
+            (tx, relay, vec![])
+        },
+        None => {
+            info!("preparing whisper caches...");
+            let writers = if args.flag_writers > 0 { args.flag_writers } else { 1 };
+            let shards : Vec<Shard> = (0..writers).map(|_| {
+                let (schemas, aggregation) = storage_config(&args);
+                let mut whisper_cache = WhisperCache::new(&config.base_path.to_owned(), cmp::max(config.cache_size / writers, 1), schemas, aggregation);
+                if let Some(ref index) = index {
+                    whisper_cache = whisper_cache.with_index(index.clone());
+                }
+                Shard::new(whisper_cache)
+            }).collect();
+
+            let (tx, writer) = carbon::cache_writer::spawn(shards.clone(), &config);
+            (tx, writer, shards)
+        }
+    };
+
+    if args.flag_carbon_metric_interval > 0 {
+        let instrumentation_config = carbon::instrumentation::Config {
+            prefix: args.flag_carbon_metric_prefix.clone(),
+            interval: args.flag_carbon_metric_interval
+        };
+        carbon::instrumentation::spawn(tx.clone(), instrumentation_config, shards.clone());
+    }
+
+    let _http_workers = match (&args.flag_http_bind, index) {
+        (&Some(ref http_bind), Some(index)) => {
+            let http_config = graphite_http::Config{
+                bind_spec: http_bind,
+                base_path: config.base_path,
+                worker_threads: args.flag_http_workers
+            };
+            graphite_http::server::spawn(http_config, CacheHolder::with_shards(shards).with_index(index))
+        },
+        _ => vec![]
     };
 
-    info!("preparing whisper cache...");
-    let default_specs = vec!["5s:1y".to_string()];
-    let schema = Schema::new_from_retention_specs(default_specs);
-    let cache = WhisperCache::new(&config.base_path.to_owned(), config.cache_size, schema);
+    let enabled : Vec<&Listener> = config.listeners.iter().filter(|listener| listener.enabled).collect();
+    if enabled.is_empty() {
+        warn!("every listener is disabled, nothing will be received");
+    }
+    let servers : Vec<_> = enabled.into_iter().map(|listener| {
+        let server = listener.spawn(tx.clone(), &config).unwrap_or_else(|reason| {
+            println!("could not start [{}] {:?} listener on `{}`: {}", listener.name, listener.protocol, listener.bind_spec, reason);
+            exit(1)
+        });
+        (listener, server)
+    }).collect();
 
-    let (tx,_) = carbon::cache_writer::spawn(cache, &config);
+    // USR1/USR2/HUP go to the writer until we're told to stop
+    while !carbon::shutdown::requested() {
+        for signal in signals::take() {
+            info!("got {:?}", signal);
+            let action = match signal {
+                Signal::DumpState => {
+                    // The writer can only tell what it has taken off the channel
+                    println!("carbon: {} points queued for the writer", carbon::instrumentation::QUEUED_POINTS.get());
+                    Action::DumpState
+                },
+                Signal::Flush => Action::Flush,
+                Signal::Reload => Action::Reload
+            };
+            tx.send(action).unwrap();
+        }
+        thread::sleep(Duration::from_millis(carbon::shutdown::POLL_INTERVAL_MS));
+    }
+    drop(tx);
 
-    let udp_server = carbon::udp::run_server(tx.clone(), &config).unwrap();
-    let tcp_server = carbon::tcp::run_server(tx, &config).unwrap();
+    let shutdown_timeout = args.flag_shutdown_timeout;
+    info!("shutting down, writing what's left for at most {}s...", shutdown_timeout);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(shutdown_timeout));
+        error!("still writing after {}s, giving up", shutdown_timeout);
+        exit(1)
+    });
 
-    udp_server.join().unwrap();
-    tcp_server.join().unwrap();
+    // Each listener drops its senders once it has stopped reading, the
+    // writer finishes when the last one is gone
+    for (listener, server) in servers {
+        if let Err(reason) = server.join().unwrap() {
+            error!("{:?} listener on `{}` stopped with {:?}", listener.protocol, listener.bind_spec, reason);
+        }
+    }
+    writer.join().unwrap();
+    info!("all points written");
+}
+
+// --listeners and every --listen. With neither it's the line protocol over
+// UDP and TCP on --bind and pickles on --pickle-bind, as it always was.
+fn listeners(args: &Args) -> Vec<Listener> {
+    let mut listeners = match args.flag_listeners {
+        Some(ref listeners_path) => carbon::listeners::load(Path::new(listeners_path)).unwrap_or_else(|reason| {
+            println!("could not load listeners: {}", reason);
+            exit(1)
+        }),
+        None => vec![]
+    };
+    for spec in &args.flag_listen {
+        listeners.push(Listener::parse(spec).unwrap_or_else(|reason| {
+            println!("--listen: {}", reason);
+            exit(1)
+        }));
+    }
+
+    if listeners.is_empty() {
+        listeners = vec![
+            Listener::new("bind", Protocol::Udp, &args.flag_bind),
+            Listener::new("bind", Protocol::Tcp, &args.flag_bind),
+            Listener::new("pickle-bind", Protocol::Pickle, &args.flag_pickle_bind)
+        ];
+    }
+    listeners
+}
+
+fn relay_config(args: &Args, destinations: &str) -> carbon::relay::Config {
+    let destinations = Destination::parse_all(destinations).unwrap_or_else(|reason| {
+        println!("--relay-destinations: {}", reason);
+        exit(1)
+    });
+    if args.flag_replication_factor == 0 {
+        println!("--replication-factor must be at least 1");
+        exit(1)
+    }
+    if args.flag_replication_factor > destinations.len() {
+        println!("--replication-factor {} needs at least that many destinations, not {}", args.flag_replication_factor, destinations.len());
+        exit(1)
+    }
+    // Otherwise some metrics would quietly get fewer copies
+    let mut hosts : Vec<&str> = destinations.iter().map(|destination| &destination.host[..]).collect();
+    hosts.sort();
+    hosts.dedup();
+    if !args.flag_no_diverse_replicas && args.flag_replication_factor > hosts.len() {
+        println!("--replication-factor {} needs destinations on at least that many hosts, not {}, or --no-diverse-replicas", args.flag_replication_factor, hosts.len());
+        exit(1)
+    }
+
+    carbon::relay::Config {
+        destinations: destinations,
+        replication_factor: args.flag_replication_factor,
+        diverse_replicas: !args.flag_no_diverse_replicas,
+        queue_size: args.flag_relay_queue_size
+    }
+}
+
+// Every writer gets its own copy, SIGHUP has each of them read it again
+fn storage_config(args: &Args) -> (StorageSchemas, StorageAggregation) {
+    let schemas = match args.flag_storage_schemas {
+        Some(ref schemas_path) => StorageSchemas::load(Path::new(schemas_path)).unwrap_or_else(|reason| {
+            println!("could not load storage schemas: {}", reason);
+            exit(1)
+        }),
+        None => StorageSchemas::new_default()
+    };
+    let aggregation = match args.flag_storage_aggregation {
+        Some(ref aggregation_path) => StorageAggregation::load(Path::new(aggregation_path)).unwrap_or_else(|reason| {
+            println!("could not load storage aggregation: {}", reason);
+            exit(1)
+        }),
+        None => StorageAggregation::new_default()
+    };
+    (schemas, aggregation)
 }
@@ -8,22 +8,27 @@ extern crate docopt;
 extern crate time;
 
 use std::path::Path;
+use std::process::exit;
+use std::sync::{ Arc, RwLock };
+use std::thread;
+use std::time::Duration;
 
 use docopt::Docopt;
 static USAGE: &'static str = "
 Graphite is the HTTP REST API for querying data from the database
 
 Usage:
-    graphite server
+    graphite server [--bind HOST] [--storage-path STORAGEPATH] [--workers WORKERS] [--index-refresh SECONDS]
     graphite expand [--storage-path STORAGEPATH] <pattern>
-Options:
 
+Options:
     --bind HOST                 host to bind to [default: 0.0.0.0:8080]
     --storage-path STORAGEPATH  where to find the whisper file [default: /tmp]
+    --workers WORKERS           how many requests to serve at once [default: 8]
+    --index-refresh SECONDS     how often to rescan the storage path for new metrics, 0 for never [default: 60]
 ";
 
-use self::graphite::graphite::{ Config, server, expander };
-use self::graphite::whisper::Cache;
+use self::graphite::graphite::{ Config, CacheHolder, MetricIndex, server, expander };
 
 #[derive(RustcDecodable, Debug)]
 struct Args {
@@ -33,7 +38,9 @@ struct Args {
     arg_pattern: String,
 
     flag_bind: String,
-    flag_storage_path: String
+    flag_storage_path: String,
+    flag_workers: usize,
+    flag_index_refresh: u64
 }
 
 pub fn main(){
@@ -42,21 +49,44 @@ pub fn main(){
                             .and_then(|d| d.decode())
                             .unwrap_or_else(|e| e.exit());
 
-    let bind_spec : &str = unsafe {
-        args.flag_bind.slice_unchecked(0, args.flag_bind.len())
-    };
-
     let config = Config{
-        bind_spec: bind_spec,
-        base_path: Path::new(&args.flag_storage_path)
+        bind_spec: &args.flag_bind,
+        base_path: Path::new(&args.flag_storage_path),
+        worker_threads: args.flag_workers
     };
 
     if args.cmd_server {
-        let cache = Cache::new(config.base_path);
+        // carbon runs elsewhere and can't tell us about new files, so the
+        // index is rebuilt every so often instead
+        let index = MetricIndex::scan(config.base_path).unwrap_or_else(|reason| {
+            println!("could not index {:?}: {}", config.base_path, reason);
+            exit(1)
+        });
+        let index = Arc::new( RwLock::new(index) );
+        if args.flag_index_refresh > 0 {
+            let refresh_index = index.clone();
+            let refresh_path = config.base_path.to_path_buf();
+            let refresh_every = Duration::from_secs(args.flag_index_refresh);
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(refresh_every);
+                    // Files come and go under a running carbon, so a failed
+                    // rescan keeps serving the index we already have
+                    match MetricIndex::scan(&refresh_path) {
+                        Ok(fresh) => *refresh_index.write().unwrap() = fresh,
+                        Err(reason) => error!("could not rescan {:?}, keeping the previous index: {}", refresh_path, reason)
+                    }
+                }
+            });
+        }
+
+        let cache = CacheHolder::new(config.base_path).with_index(index);
         server::run(config, cache);
     } else if args.cmd_expand {
-        let cache = Cache::new(config.base_path);
-        expander::expand(&args.arg_pattern, &cache);
+        let cache = CacheHolder::new(config.base_path);
+        for node in expander::expand(&args.arg_pattern, &cache) {
+            println!("{}", node.metric_name());
+        }
     } else {
         println!("command not specified");
     }
@@ -1,4 +1,5 @@
 extern crate graphite;
+extern crate whisper;
 
 #[macro_use]
 extern crate log;
@@ -8,31 +9,41 @@ extern crate docopt;
 extern crate time;
 
 use docopt::Docopt;
-use graphite::whisper::{ WhisperFile, RefCellWhisperFile, Point };
-use graphite::whisper::schema::Schema;
+use whisper::{ WhisperFile, Schema };
+use graphite::whisper_io::{ self, AggregationMethod };
+use graphite::carbon::StorageAggregation;
+use graphite::carbon::storage_aggregation::parse_x_files_factor;
+use graphite::graphite::time_spec;
 
+use std::fs::{ File, OpenOptions };
 use std::path::Path;
+use std::process::exit;
 
 static USAGE: &'static str = "
 Whisper is the fast file manipulator
 
 Usage:
     whisper info <file>
-    whisper dump <file>
+    whisper dump [--from <from>] [--until <until>] <file>
+    whisper fetch [--from <from>] [--until <until>] <file>
     whisper update <file> <timestamp> <value>
     whisper mark <file> <value>
     whisper thrash <file> <value> <times>
-    whisper create <file> <timespec>...
+    whisper create [--xff <x_files_factor>] [--aggregation_method <method>] [--storage-aggregation <aggfile>] <file> <timespec>...
 
 Options:
-    --xff <x_files_factor>
-    --aggregation_method <method>
+    --from <from>                   graphite time spec like -6h, yesterday or 00:00_20150722
+    --until <until>                 graphite time spec, defaults to now
+    --xff <x_files_factor>          fraction of known points needed to roll up (0.5 unless a rule says otherwise)
+    --aggregation_method <method>   average, sum, last, max, min, avg_zero, absmax or absmin
+    --storage-aggregation <aggfile>  storage-aggregation.conf to match the file's metric name against
 ";
 
 #[derive(RustcDecodable, Debug)]
 struct Args {
     cmd_info: bool,
     cmd_dump: bool,
+    cmd_fetch: bool,
     cmd_update: bool,
     cmd_mark: bool,
     cmd_thrash: bool,
@@ -43,7 +54,13 @@ struct Args {
     arg_value: String,
     arg_times: String,
 
-    arg_timespec: Vec<String>
+    arg_timespec: Vec<String>,
+
+    flag_from: Option<String>,
+    flag_until: Option<String>,
+    flag_xff: Option<String>,
+    flag_aggregation_method: Option<String>,
+    flag_storage_aggregation: Option<String>
 }
 
 
@@ -54,18 +71,16 @@ pub fn main(){
                             .unwrap_or_else(|e| e.exit());
 
     let arg_file = args.arg_file.clone();
-    let path_str : &str = unsafe {
-        arg_file.slice_unchecked(0, args.arg_file.len())
-    };
-    let path = Path::new(path_str);
+    let path = Path::new(&arg_file);
 
-
-    let current_time = time::get_time().sec as u64;
+    let current_time = time::get_time().sec as u32;
 
     if args.cmd_info {
         cmd_info(path);
     } else if args.cmd_dump {
-        cmd_dump(path);
+        cmd_dump(&args, path, current_time);
+    } else if args.cmd_fetch {
+        cmd_fetch(&args, path, current_time);
     } else if args.cmd_update {
         cmd_update(args, path, current_time);
     } else if args.cmd_mark {
@@ -79,65 +94,156 @@ pub fn main(){
     }
 }
 
-fn cmd_info(path: &Path) {
-    let file_open = RefCellWhisperFile::open(path);
-    match file_open {
-        Ok(whisper_file) => println!("{}", whisper_file),
+fn open(path: &Path) -> (File, whisper_io::Metadata) {
+    let open_res = OpenOptions::new().read(true).write(true).open(path).and_then(|mut file| {
+        let metadata = try!( whisper_io::read_metadata(&mut file) );
+        Ok((file, metadata))
+    });
+
+    match open_res {
+        Ok(pair) => pair,
         Err(why) => {
-            println!("could create whisper file: {:?}", why)
+            println!("could not open whisper file: {:?}", why);
+            exit(1)
         }
     }
 }
 
-fn cmd_dump(path: &Path) {
-    let file_open = RefCellWhisperFile::open(path);
-    match file_open {
-        Ok(whisper_file) => println!("{:?}", whisper_file),
+fn cmd_info(path: &Path) {
+    let (_, metadata) = open(path);
+    println!("{}", metadata);
+}
+
+// `--from`/`--until` as epochs, dump shows everything when they're left off
+fn time_range(args: &Args, current_time: u32, default_from: u32) -> (u32, u32) {
+    let parse = |spec: &Option<String>, default: u32| match *spec {
+        Some(ref spec) => time_spec::parse(spec, current_time).unwrap_or_else(|reason| {
+            println!("{}", reason);
+            exit(1)
+        }),
+        None => default
+    };
+    (parse(&args.flag_from, default_from), parse(&args.flag_until, current_time))
+}
+
+fn cmd_dump(args: &Args, path: &Path, current_time: u32) {
+    let (mut file, metadata) = open(path);
+    println!("{}", metadata);
+
+    let (from, until) = match (&args.flag_from, &args.flag_until) {
+        (&None, &None) => (0, ::std::u32::MAX),
+        _ => time_range(args, current_time, 0)
+    };
+
+    for (index, archive) in metadata.archives.iter().enumerate() {
+        println!("Archive {} data:", index);
+        match whisper_io::read_archive(&mut file, archive) {
+            Ok(points) => {
+                for (points_index, point) in points.iter().enumerate() {
+                    if point.0 >= from && point.0 <= until {
+                        println!("{}: {}, {}", points_index, point.0, point.1);
+                    }
+                }
+            },
+            Err(why) => println!("could not read archive {}: {:?}", index, why)
+        }
+        println!("");
+    }
+}
+
+// Same output as whisper-fetch.py
+fn cmd_fetch(args: &Args, path: &Path, current_time: u32) {
+    let (mut file, metadata) = open(path);
+    let (from, until) = time_range(args, current_time, current_time.saturating_sub(24*60*60));
+
+    match whisper_io::fetch(&mut file, &metadata, from, until, current_time) {
+        Ok(Some(((start, _, step), values))) => {
+            for (index, value) in values.iter().enumerate() {
+                let timestamp = start + step * index as u32;
+                match *value {
+                    Some(value) => println!("{}\t{}", timestamp, value),
+                    None => println!("{}\tNone", timestamp)
+                }
+            }
+        },
+        Ok(None) => println!("no data between {} and {}", from, until),
         Err(why) => {
-            println!("could create whisper file: {:?}", why)
+            println!("Failed: {:?}", why);
+            exit(1)
         }
     }
 }
 
-fn cmd_update(args: Args, path: &Path, current_time: u64) {
-    let mut file = RefCellWhisperFile::open(path).unwrap();
-    let point = Point{
-        timestamp: args.arg_timestamp.parse::<u64>().unwrap(),
-        value: args.arg_value.parse::<f64>().unwrap()
-    };
-    debug!("Updating TS: {} with value: {}", point.timestamp, point.value);
+fn write_point(path: &Path, current_time: u32, timestamp: u32, value: f64) {
+    let (mut file, metadata) = open(path);
+    debug!("Updating TS: {} with value: {}", timestamp, value);
 
-    file.write(current_time, point);
+    if let Err(why) = whisper_io::update(&mut file, &metadata, current_time, timestamp, value) {
+        println!("Failed: {:?}", why);
+    }
 }
 
-fn cmd_mark(args: Args, path: &Path, current_time: u64) {
-    let mut file = RefCellWhisperFile::open(path).unwrap();
-    let point = Point{
-        timestamp: current_time,
-        value: args.arg_value.parse::<f64>().unwrap()
-    };
+fn cmd_update(args: Args, path: &Path, current_time: u32) {
+    let timestamp = args.arg_timestamp.parse::<u32>().unwrap();
+    let value = args.arg_value.parse::<f64>().unwrap();
+    write_point(path, current_time, timestamp, value);
+}
 
-    file.write(current_time, point);
+fn cmd_mark(args: Args, path: &Path, current_time: u32) {
+    let value = args.arg_value.parse::<f64>().unwrap();
+    write_point(path, current_time, current_time, value);
 }
 
-fn cmd_thrash(args: Args, path: &Path, current_time: u64) {
-    let times = args.arg_times.parse::<u64>().unwrap();
-    let mut file = RefCellWhisperFile::open(path).unwrap();
+fn cmd_thrash(args: Args, path: &Path, current_time: u32) {
+    let times = args.arg_times.parse::<u32>().unwrap();
+    let value = args.arg_value.parse::<f64>().unwrap();
+    let (mut file, metadata) = open(path);
     for index in 1..times {
-        let point = Point{
-            timestamp: current_time+index,
-            value: args.arg_value.parse::<f64>().unwrap()
-        };
-
-        file.write(current_time+index, point);
+        let timestamp = current_time + index;
+        whisper_io::update(&mut file, &metadata, timestamp, timestamp, value).unwrap();
     }
 }
 
 fn cmd_create(args: Args, path: &Path) {
+    // Rules from storage-aggregation.conf are matched against the metric name
+    // the path would have in carbon's tree, e.g. `stats/foo.count.wsp`.
+    let (rule_method, rule_xff) = match args.flag_storage_aggregation {
+        Some(ref aggregation_path) => {
+            let aggregation = StorageAggregation::load(Path::new(aggregation_path)).unwrap_or_else(|reason| {
+                println!("could not load storage aggregation: {}", reason);
+                exit(1)
+            });
+            let metric_name = path.to_string_lossy().trim_right_matches(".wsp").replace("/", ".");
+            aggregation.aggregation_for(&metric_name)
+        },
+        None => StorageAggregation::new_default().aggregation_for("")
+    };
+
+    let aggregation_method = match args.flag_aggregation_method {
+        Some(ref method) => AggregationMethod::from_str(method).unwrap_or_else(|| {
+            println!("unknown aggregation method `{}`", method);
+            exit(1)
+        }),
+        None => rule_method
+    };
+
+    let x_files_factor = match args.flag_xff {
+        Some(ref xff) => parse_x_files_factor(xff).unwrap_or_else(|reason| {
+            println!("{}", reason);
+            exit(1)
+        }),
+        None => rule_xff
+    };
+
     let schema = Schema::new_from_retention_specs(args.arg_timespec);
-    let new_result = RefCellWhisperFile::new(path, schema);
+    let new_result = WhisperFile::new(path, &schema).and_then(|whisper_file| {
+        drop(whisper_file);
+        let mut file = try!( OpenOptions::new().read(true).write(true).open(path) );
+        try!( whisper_io::write_aggregation(&mut file, aggregation_method, x_files_factor) );
+        whisper_io::read_metadata(&mut file)
+    });
     match new_result {
-        Ok(whisper_file) => println!("Success! {}", whisper_file),
+        Ok(metadata) => println!("Success!\n{}", metadata),
         Err(why) => println!("Failed: {:?}", why)
     }
 }
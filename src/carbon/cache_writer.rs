@@ -1,39 +1,440 @@
-use whisper::{ WhisperCache };
-
 use std::thread::{ self, JoinHandle };
 // extern crate time;
-use std::sync::mpsc::{ sync_channel, SyncSender };
+use std::cmp;
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ sync_channel, SyncSender, Receiver, TryRecvError, RecvTimeoutError };
+use std::time::{ Duration, Instant };
 
-use super::Config;
+use super::{ Config, FlushStrategy, WhisperCache, PendingPoints };
+use super::whisper_cache::metric_name;
 use super::handlers::Action;
+use super::instrumentation::{ COMMITTED_POINTS, UPDATE_OPERATIONS, ERRORS, QUEUED_POINTS };
+
+// One writer thread's open files and the points it has yet to write. Both
+// are shared so the graphite HTTP server can read through them when it
+// runs in the same process.
+#[derive(Clone)]
+pub struct Shard {
+    pub cache: Arc<Mutex<WhisperCache>>,
+    pub pending: Arc<Mutex<PendingPoints>>
+}
+
+impl Shard {
+    pub fn new(cache: WhisperCache) -> Shard {
+        Shard {
+            cache: Arc::new( Mutex::new(cache) ),
+            pending: Arc::new( Mutex::new( PendingPoints::new() ) )
+        }
+    }
+}
+
+// Which of `shards` writes `metric_rel_path`. Every point for a file goes
+// to the same writer so no two threads ever touch one file.
+pub fn shard_for(metric_rel_path: &Path, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    metric_rel_path.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+// A writer thread per shard. With more than one, a dispatcher thread in
+// front routes each point to its shard and hands every other action to all
+// of them. The returned handle finishes once all writers have drained.
+// The cache size and update rate limits are split evenly between writers.
+pub fn spawn(shards: Vec<Shard>, config: &Config) -> (SyncSender<Action>, JoinHandle<()>) {
+    let chan_depth = config.chan_depth;
+    let writer_config = WriterConfig {
+        chan_depth: chan_depth,
+        max_pending: cmp::max(config.max_cache_size / shards.len(), 1),
+        max_updates_per_second: config.max_updates_per_second as f64 / shards.len() as f64,
+        flush_strategy: config.flush_strategy
+    };
+    if shards.len() == 1 {
+        return spawn_writer(0, shards[0].clone(), &writer_config);
+    }
+
+    let (tx, rx) = sync_channel(chan_depth);
+    let (shard_txs, writers) : (Vec<_>, Vec<_>) = shards.into_iter().enumerate().map(|(id, shard)| {
+        spawn_writer(id, shard, &writer_config)
+    }).unzip();
+
+    info!("spawning dispatcher for {} writers...", shard_txs.len());
+    let dispatcher = thread::spawn(move || {
+        dispatch(rx, &shard_txs);
+        drop(shard_txs);
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        debug!("all writers are done");
+    });
+
+    (tx, dispatcher)
+}
+
+fn dispatch(rx: Receiver<Action>, shard_txs: &[SyncSender<Action>]) {
+    for action in rx.iter() {
+        match action {
+            Action::Write(named_point) => {
+                let shard = shard_for(&named_point.rel_path(), shard_txs.len());
+                shard_txs[shard].send(Action::Write(named_point)).unwrap();
+            },
+            Action::DumpState => for shard_tx in shard_txs { shard_tx.send(Action::DumpState).unwrap() },
+            Action::Flush => for shard_tx in shard_txs { shard_tx.send(Action::Flush).unwrap() },
+            Action::Reload => for shard_tx in shard_txs { shard_tx.send(Action::Reload).unwrap() }
+        }
+    }
+}
+
+struct WriterConfig {
+    chan_depth: usize,
+    max_pending: usize,
+    max_updates_per_second: f64,
+    flush_strategy: FlushStrategy
+}
+
+// Picks which file gets written next
+struct FlushOrder {
+    strategy: FlushStrategy,
+    // What's left of the last snapshot, for `FlushStrategy::Sorted`
+    sorted: VecDeque<PathBuf>
+}
 
-pub fn spawn(cache: WhisperCache, config: &Config) -> (SyncSender<Action>, JoinHandle<()>) {
+impl FlushOrder {
+    fn new(strategy: FlushStrategy) -> FlushOrder {
+        FlushOrder { strategy: strategy, sorted: VecDeque::new() }
+    }
+
+    fn next(&mut self, pending: &PendingPoints) -> Option<PathBuf> {
+        match self.strategy {
+            FlushStrategy::Naive => pending.oldest(),
+            FlushStrategy::Max => pending.largest(),
+            FlushStrategy::Sorted => {
+                if self.sorted.is_empty() {
+                    // Stable, so files with as many points stay oldest first
+                    let mut files = pending.files();
+                    files.sort_by(|a, b| b.1.cmp(&a.1));
+                    self.sorted = files.into_iter().map(|(metric_rel_path, _)| metric_rel_path).collect();
+                }
+                self.sorted.pop_front()
+            }
+        }
+    }
+}
+
+// A token bucket holding up to a second's worth of updates. A rate of 0
+// never throttles.
+struct Throttle {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl Throttle {
+    fn new(rate: f64) -> Throttle {
+        Throttle { rate: rate, tokens: rate.max(1.0), last_refill: Instant::now() }
+    }
+
+    // How long until the next update is allowed, None if it is now
+    fn wait(&mut self) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        self.last_refill = now;
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            let wait = (1.0 - self.tokens) / self.rate;
+            Some(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+        }
+    }
+
+    fn updated(&mut self) {
+        if self.rate > 0.0 {
+            self.tokens -= 1.0;
+        }
+    }
+}
+
+// Points are moved off the channel into `pending` as soon as they arrive,
+// where readers can see them, and written out one file at a time, all of
+// a file's points at once. `pending` holds at most `max_pending` points so a
+// slow or throttled disk still pushes back on the listeners. Once the
+// listeners are gone what's left is written without throttling.
+fn spawn_writer(id: usize, shard: Shard, config: &WriterConfig) -> (SyncSender<Action>, JoinHandle<()>) {
     let (tx, rx) = sync_channel(config.chan_depth);
+    let max_pending = config.max_pending;
+    let mut throttle = Throttle::new(config.max_updates_per_second);
+    let mut order = FlushOrder::new(config.flush_strategy);
+    let Shard { cache, pending } = shard;
 
-    info!("spawning file writer...");
-    let mut cache = cache; // gotta alias the value as mut!
+    info!("spawning file writer {}...", id);
 
     let writer = thread::spawn(move || {
+        let mut disconnected = false;
         loop {
-            let recv = rx.recv();
-            // let current_time = time::get_time().sec as u64;
+            // Nothing to write, wait for something
+            if !disconnected && pending.lock().unwrap().is_empty() {
+                match rx.recv() {
+                    Ok(action) => handle(id, action, &cache, &pending, &mut order),
+                    Err(_) => disconnected = true
+                }
+            }
 
-            match recv {
-                Ok(Action::Write(named_point)) => {
-                    let write_res = cache.write( named_point );
+            while !disconnected && pending.lock().unwrap().len() < max_pending {
+                match rx.try_recv() {
+                    Ok(action) => handle(id, action, &cache, &pending, &mut order),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => disconnected = true
+                }
+            }
 
-                    match write_res {
-                        Ok(()) => (),
-                        Err(reason) => debug!("err: {:?}", reason)
+            // Over the update rate, keep taking points in until the next write
+            if !disconnected {
+                if let Some(wait) = throttle.wait() {
+                    if pending.lock().unwrap().len() < max_pending {
+                        match rx.recv_timeout(wait) {
+                            Ok(action) => handle(id, action, &cache, &pending, &mut order),
+                            Err(RecvTimeoutError::Timeout) => (),
+                            Err(RecvTimeoutError::Disconnected) => disconnected = true
+                        }
+                    } else {
+                        thread::sleep(wait);
                     }
-                },
-                Err(_) => {
-                    debug!("shutting down writer thread");
-                    return ()
+                    continue;
                 }
             }
+
+            if write_next(&cache, &pending, &mut order) {
+                throttle.updated();
+            } else if disconnected {
+                debug!("channel drained, flushing open files");
+                flush(&cache);
+                debug!("shutting down writer thread {}", id);
+                return ()
+            }
         }
     });
 
     (tx,writer)
 }
+
+fn handle(id: usize, action: Action, cache: &Mutex<WhisperCache>, pending: &Mutex<PendingPoints>, order: &mut FlushOrder) {
+    match action {
+        Action::Write(named_point) => {
+            QUEUED_POINTS.sub(1);
+            let point = named_point.point();
+            let metric_rel_path = named_point.rel_path();
+            let first = pending.lock().unwrap().push(metric_rel_path.clone(), point.0, point.1);
+            if first {
+                cache.lock().unwrap().index_pending(&metric_rel_path);
+            }
+        },
+        Action::DumpState => {
+            print!("{}", dump_state(id, &*cache.lock().unwrap(), &*pending.lock().unwrap()));
+        },
+        // Everything sent before the flush is pending by now
+        Action::Flush => {
+            info!("flushing {} pending points", pending.lock().unwrap().len());
+            while write_next(cache, pending, order) {}
+            flush(cache);
+        },
+        Action::Reload => {
+            match cache.lock().unwrap().reload() {
+                Ok(()) => info!("reloaded storage schemas and aggregation"),
+                Err(reason) => error!("could not reload, keeping the old config: {}", reason)
+            }
+        }
+    }
+}
+
+// False when there was nothing to write
+fn write_next(cache: &Mutex<WhisperCache>, pending: &Mutex<PendingPoints>, order: &mut FlushOrder) -> bool {
+    let next = {
+        let pending = pending.lock().unwrap();
+        order.next(&pending).map(|metric_rel_path| {
+            let points = pending.get(&metric_rel_path);
+            (metric_rel_path, points)
+        })
+    };
+    match next {
+        Some((metric_rel_path, points)) => {
+            let write_res = cache.lock().unwrap().write(&metric_rel_path, &points);
+
+            match write_res {
+                Ok(written) => {
+                    COMMITTED_POINTS.add(written);
+                    UPDATE_OPERATIONS.increment();
+                },
+                Err(reason) => {
+                    ERRORS.increment();
+                    debug!("err: {:?}", reason)
+                }
+            }
+            // Only this thread adds points, so nothing new came in for it meanwhile
+            pending.lock().unwrap().remove(&metric_rel_path);
+            true
+        },
+        None => false
+    }
+}
+
+fn flush(cache: &Mutex<WhisperCache>) {
+    match cache.lock().unwrap().flush() {
+        Ok(()) => (),
+        Err(reason) => error!("could not flush open files: {:?}", reason)
+    }
+}
+
+fn dump_state(id: usize, cache: &WhisperCache, pending: &PendingPoints) -> String {
+    let files = pending.files();
+    let mut out = format!("carbon writer {}: {} pending points in {} metrics, {} open files\n",
+                          id, pending.len(), files.len(), cache.open_file_count());
+    for (metric_rel_path, points) in files {
+        out.push_str(&format!("  {} {}\n", metric_name(&metric_rel_path), points));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::{ Path, PathBuf };
+    use test::Bencher;
+    use test_support::TempDir;
+    use time;
+
+    use super::{ Shard, FlushOrder, Throttle, shard_for, spawn };
+    use super::super::{ Config, BadLinePolicy, TcpMode, FlushStrategy, NamedPoint, WhisperCache, PendingPoints, StorageSchemas, StorageAggregation };
+    use super::super::handlers::Action;
+    use whisper_io;
+
+    fn config(base_path: &Path) -> Config {
+        Config {
+            listeners: vec![],
+            chan_depth: 1000,
+            base_path: base_path,
+            cache_size: 100,
+            max_cache_size: 1000,
+            max_updates_per_second: 0,
+            flush_strategy: FlushStrategy::Sorted,
+            bad_line_policy: BadLinePolicy::Skip,
+            tcp_mode: TcpMode::Threaded,
+            max_connections: 0,
+            idle_timeout: 0,
+            read_buffer_size: 0
+        }
+    }
+
+    // `points` points spread over 100 metrics, through `writers` writers,
+    // returning once they're all on disk
+    fn write_through(base_path: &Path, writers: usize, points: u32) {
+        let shards = (0..writers).map(|_| {
+            let schemas = StorageSchemas::parse("[all]\npattern = .*\nretentions = 60s:1d\n").unwrap();
+            Shard::new(WhisperCache::new(base_path, 100, schemas, StorageAggregation::new_default()))
+        }).collect();
+        let (tx, writer) = spawn(shards, &config(base_path));
+
+        let now = time::get_time().sec as u32;
+        for i in 0..points {
+            let metric = format!("bench.m{}", i % 100);
+            tx.send(Action::Write(NamedPoint::new(metric, now - (i / 100) * 60, i as f64))).unwrap();
+        }
+        drop(tx);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn metrics_stay_on_one_shard(){
+        let path = Path::new("collectd/host1/load.wsp");
+        assert_eq!(shard_for(path, 4), shard_for(path, 4));
+        assert_eq!(shard_for(path, 1), 0);
+
+        let mut used = vec![false; 4];
+        for i in 0..100 {
+            used[shard_for(Path::new(&format!("m{}.wsp", i)), 4)] = true;
+        }
+        assert_eq!(used, vec![true; 4]);
+    }
+
+    #[test]
+    fn flush_strategies(){
+        let mut pending = PendingPoints::new();
+        for &(metric, points) in &[("a.wsp", 1), ("b.wsp", 3), ("c.wsp", 2), ("d.wsp", 3)] {
+            for i in 0..points {
+                pending.push(PathBuf::from(metric), i * 60, 1.0);
+            }
+        }
+        let flushed = |strategy| {
+            let mut pending = pending.clone();
+            let mut order = FlushOrder::new(strategy);
+            let mut flushed = vec![];
+            while let Some(metric_rel_path) = order.next(&pending) {
+                pending.remove(&metric_rel_path);
+                // Comes in after `sorted` took its snapshot
+                if metric_rel_path == Path::new("b.wsp") {
+                    pending.push(PathBuf::from("e.wsp"), 0, 1.0);
+                    pending.push(PathBuf::from("e.wsp"), 60, 1.0);
+                    pending.push(PathBuf::from("e.wsp"), 120, 1.0);
+                    pending.push(PathBuf::from("e.wsp"), 180, 1.0);
+                }
+                flushed.push(metric_rel_path.to_str().unwrap().to_string());
+            }
+            flushed
+        };
+
+        assert_eq!(flushed(FlushStrategy::Naive), vec!["a.wsp", "b.wsp", "c.wsp", "d.wsp", "e.wsp"]);
+        assert_eq!(flushed(FlushStrategy::Max), vec!["b.wsp", "e.wsp", "d.wsp", "c.wsp", "a.wsp"]);
+        assert_eq!(flushed(FlushStrategy::Sorted), vec!["b.wsp", "d.wsp", "c.wsp", "a.wsp", "e.wsp"]);
+    }
+
+    #[test]
+    fn throttle_waits_for_tokens(){
+        let mut unlimited = Throttle::new(0.0);
+        for _ in 0..1000 {
+            assert_eq!(unlimited.wait(), None);
+            unlimited.updated();
+        }
+
+        let mut throttle = Throttle::new(2.0);
+        for _ in 0..2 {
+            assert_eq!(throttle.wait(), None);
+            throttle.updated();
+        }
+        let wait = throttle.wait().unwrap();
+        assert!(wait.as_secs() == 0 && wait.subsec_nanos() <= 500_000_000);
+    }
+
+    #[test]
+    fn every_shard_writes(){
+        let dir = TempDir::new("carbon-cache-writer-every_shard_writes");
+        write_through(dir.path(), 3, 300);
+
+        let now = time::get_time().sec as u32;
+        for m in 0..100 {
+            let mut file = File::open(dir.path().join(format!("bench/m{}.wsp", m))).unwrap();
+            let metadata = whisper_io::read_metadata(&mut file).unwrap();
+            let (_, values) = whisper_io::fetch(&mut file, &metadata, now - 600, now, now).unwrap().unwrap();
+            assert_eq!(values.iter().filter(|value| value.is_some()).count(), 3);
+        }
+    }
+
+    #[bench]
+    fn one_writer(b: &mut Bencher){
+        let dir = TempDir::new("carbon-cache-writer-bench-1");
+        b.iter(|| write_through(dir.path(), 1, 10000));
+    }
+
+    #[bench]
+    fn four_writers(b: &mut Bencher){
+        let dir = TempDir::new("carbon-cache-writer-bench-4");
+        b.iter(|| write_through(dir.path(), 4, 10000));
+    }
+}
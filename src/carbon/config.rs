@@ -1,8 +1,83 @@
 use std::path::Path;
 
+use super::listeners::Listener;
+
 pub struct Config<'a> {
-    pub bind_spec: &'a str,
+    pub listeners: Vec<Listener>,
     pub chan_depth: usize,
     pub base_path: &'a Path,
-    pub cache_size: usize
+    pub cache_size: usize,
+    // Points held in memory across all writers before the listeners are made
+    // to wait, and whisper writes per second across all writers (0 for no limit)
+    pub max_cache_size: usize,
+    pub max_updates_per_second: u32,
+    pub flush_strategy: FlushStrategy,
+    pub bad_line_policy: BadLinePolicy,
+    pub tcp_mode: TcpMode,
+    // The rest only apply to `TcpMode::Evented`, the first two to TLS
+    // listeners too. An idle timeout of 0 means none.
+    pub max_connections: usize,
+    pub idle_timeout: u64,
+    pub read_buffer_size: usize
+}
+
+// Which metric the writer puts on disk next, like carbon's CACHE_WRITE_STRATEGY
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FlushStrategy {
+    // The one that has been waiting longest
+    Naive,
+    // The one with the most points waiting
+    Max,
+    // Every metric in a snapshot sorted by points waiting, most first, before
+    // taking a new snapshot
+    Sorted
+}
+
+impl FlushStrategy {
+    pub fn from_str(name: &str) -> Option<FlushStrategy> {
+        match name {
+            "naive" => Some(FlushStrategy::Naive),
+            "max" => Some(FlushStrategy::Max),
+            "sorted" => Some(FlushStrategy::Sorted),
+            _ => None
+        }
+    }
+}
+
+// How the plaintext TCP listener serves its connections
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TcpMode {
+    // A thread per connection
+    Threaded,
+    // All connections on one thread, over epoll
+    Evented
+}
+
+impl TcpMode {
+    pub fn from_str(name: &str) -> Option<TcpMode> {
+        match name {
+            "threaded" => Some(TcpMode::Threaded),
+            "evented" => Some(TcpMode::Evented),
+            _ => None
+        }
+    }
+}
+
+// What the line receiver does when a line doesn't parse
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BadLinePolicy {
+    // Log it, count it and carry on with the next line, like carbon
+    Skip,
+    // Log it, count it and hang up on the sender
+    Disconnect
+}
+
+impl BadLinePolicy {
+    pub fn from_str(name: &str) -> Option<BadLinePolicy> {
+        match name {
+            "skip" => Some(BadLinePolicy::Skip),
+            "disconnect" => Some(BadLinePolicy::Disconnect),
+            _ => None
+        }
+    }
 }
@@ -1,11 +1,267 @@
-use whisper::NamedPoint;
+use time;
+
+use std::fmt::Debug;
+use std::net::{ TcpListener, TcpStream };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::io::{ self, Error, ErrorKind };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time::Duration;
+
+use super::NamedPoint;
+use super::shutdown::{ self, Connections, Stream };
+use super::instrumentation::INVALID_LINES;
 
 pub mod udp;
 pub mod tcp;
+pub mod pickle;
+pub mod unix;
+mod sockets;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(target_os = "linux")]
+mod tcp_evented;
 
-// Room to add functionality such as
-// - USR1 signal print state of cache to STDOUT
-// - USR2 signal flush state of cache to DISK
+// What the writer thread can be asked to do. Everything but `Write` comes
+// from a signal, see `carbon::signals`.
 pub enum Action {
-    Write(NamedPoint)
+    Write(NamedPoint),
+    // USR1: print pending points and open files to STDOUT
+    DumpState,
+    // USR2: write out every pending point and fsync the open files
+    Flush,
+    // HUP: re-read storage-schemas.conf and storage-aggregation.conf
+    Reload
+}
+
+// What `serve` needs from a listening socket
+pub trait Listen: Debug {
+    type Stream: Stream + Send + 'static;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    // A blocking stream
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+}
+
+impl Listen for TcpListener {
+    type Stream = TcpStream;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        let (tcp_stream, _) = try!( self.accept() );
+        try!( tcp_stream.set_nonblocking(false) );
+        Ok(tcp_stream)
+    }
+}
+
+impl Listen for UnixListener {
+    type Stream = UnixStream;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        let (unix_stream, _) = try!( self.accept() );
+        try!( unix_stream.set_nonblocking(false) );
+        Ok(unix_stream)
+    }
+}
+
+// The accept loop shared by the stream listeners. Each connection gets its
+// own thread running `handle`. Once shutdown is requested the listener is
+// closed and every open connection stops reading, so their senders drop
+// as soon as they've passed on what was already sent.
+//
+// Failing to accept, or to take on what was accepted, mostly means we're
+// out of file descriptors or threads. That connection is dropped and the
+// loop waits a moment before trying again, the open ones carry on.
+pub fn serve<L, F>(listener: L, tx: SyncSender<Action>, handle: F) -> Result<(), Error>
+    where L: Listen, F: Fn(SyncSender<Action>, L::Stream) + Send + Sync + 'static
+{
+    serve_at_most(listener, tx, ::std::usize::MAX, handle)
+}
+
+// Like `serve`, but connections past `max_connections` are closed as soon
+// as they're accepted
+pub fn serve_at_most<L, F>(listener: L, tx: SyncSender<Action>, max_connections: usize, handle: F) -> Result<(), Error>
+    where L: Listen, F: Fn(SyncSender<Action>, L::Stream) + Send + Sync + 'static
+{
+    // Nonblocking so the flag gets looked at while nobody is connecting
+    try!( listener.set_nonblocking(true) );
+    let connections = Arc::new( Mutex::new( Connections::new() ) );
+    let handle = Arc::new(handle);
+
+    while !shutdown::requested() {
+        let stream = match listener.accept_stream() {
+            Ok(stream) => stream,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(shutdown::POLL_INTERVAL_MS));
+                continue
+            },
+            Err(err) => {
+                accept_failed(&err, connections.lock().unwrap().len());
+                thread::sleep(Duration::from_millis(ACCEPT_BACKOFF_MS));
+                continue
+            }
+        };
+
+        if connections.lock().unwrap().len() >= max_connections {
+            refuse("a connection", max_connections);
+            continue;
+        }
+        let id = match connections.lock().unwrap().add(&stream) {
+            Ok(id) => id,
+            Err(err) => {
+                accept_failed(&err, connections.lock().unwrap().len());
+                thread::sleep(Duration::from_millis(ACCEPT_BACKOFF_MS));
+                continue
+            }
+        };
+        let thread_tx = tx.clone();
+        let thread_connections = connections.clone();
+        let thread_handle = handle.clone();
+        debug!("handling new stream");
+        let spawned = thread::Builder::new().spawn(move || {
+            thread_handle(thread_tx, stream);
+            thread_connections.lock().unwrap().remove(id);
+        });
+        if let Err(err) = spawned {
+            // The stream went down with the closure, drop our clone too
+            connections.lock().unwrap().remove(id);
+            accept_failed(&err, connections.lock().unwrap().len());
+            thread::sleep(Duration::from_millis(ACCEPT_BACKOFF_MS));
+        }
+    }
+
+    info!("no longer accepting connections on {:?}", listener);
+    drop(listener);
+    connections.lock().unwrap().close_all();
+
+    Ok(())
+}
+
+// How long to leave the listener alone after accept fails
+pub const ACCEPT_BACKOFF_MS : u64 = 100;
+
+static ACCEPT_ERROR_LOG: RateLimit = RateLimit::new(10, 60);
+
+// Logs a connection that couldn't be accepted, with how many are open
+pub fn accept_failed(err: &Error, open: usize) {
+    let (log, suppressed) = ACCEPT_ERROR_LOG.check(time::get_time().sec as u64);
+    if suppressed > 0 {
+        warn!("{} more failed accepts were not logged", suppressed);
+    }
+    if log {
+        warn!("could not accept a connection, pausing for {}ms with {} open: {}", ACCEPT_BACKOFF_MS, open, err);
+    }
+}
+
+static REFUSED_LOG: RateLimit = RateLimit::new(10, 60);
+
+// Logs a connection turned away for being one too many
+pub fn refuse(peer: &str, max_connections: usize) {
+    let (log, suppressed) = REFUSED_LOG.check(time::get_time().sec as u64);
+    if suppressed > 0 {
+        warn!("{} more connections were refused", suppressed);
+    }
+    if log {
+        warn!("refusing {}, already at {} connections", peer, max_connections);
+    }
+}
+
+// A sender with a bug can send nothing but bad lines, so only the first
+// few of every minute make it to the log
+static BAD_LINE_LOG: RateLimit = RateLimit::new(10, 60);
+
+// Longer than any sane metric line, short enough for a log line
+const MAX_LOGGED_LINE : usize = 200;
+
+pub fn bad_line(peer: &str, line: &str, reason: &str) {
+    INVALID_LINES.increment();
+
+    let (log, suppressed) = BAD_LINE_LOG.check(time::get_time().sec as u64);
+    if suppressed > 0 {
+        warn!("{} more invalid lines were not logged", suppressed);
+    }
+    if log {
+        let line : String = line.chars().take(MAX_LOGGED_LINE).collect();
+        warn!("invalid line from {}: {:?} ({})", peer, line, reason);
+    }
+}
+
+// Lets `limit` events through per `window` seconds and counts the rest
+pub struct RateLimit {
+    limit: usize,
+    window: u64,
+    current_window: AtomicUsize,
+    seen: AtomicUsize
+}
+
+impl RateLimit {
+    pub const fn new(limit: usize, window: u64) -> RateLimit {
+        RateLimit {
+            limit: limit,
+            window: window,
+            current_window: AtomicUsize::new(0),
+            seen: AtomicUsize::new(0)
+        }
+    }
+
+    // Whether this one gets through, and how many didn't in the window
+    // before it, reported once when a new window starts
+    pub fn check(&self, now: u64) -> (bool, usize) {
+        let window = (now / self.window) as usize;
+        let mut suppressed = 0;
+        if self.current_window.swap(window, Ordering::SeqCst) != window {
+            suppressed = self.seen.swap(0, Ordering::SeqCst).saturating_sub(self.limit);
+        }
+        (self.seen.fetch_add(1, Ordering::SeqCst) < self.limit, suppressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{ TcpListener, TcpStream };
+    use std::sync::mpsc::{ channel, sync_channel };
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{ RateLimit, serve_at_most };
+
+    #[test]
+    fn refuses_connections_past_the_limit(){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, _rx) = sync_channel(1);
+        let (done_tx, done_rx) = channel::<()>();
+        let done_rx = ::std::sync::Mutex::new(done_rx);
+        // Each connection is held open until the test says so
+        thread::spawn(move || serve_at_most(listener, tx, 1, move |_, _| { let _ = done_rx.lock().unwrap().recv(); }));
+
+        let _first = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // Closed without a word
+        assert_eq!(second.read(&mut [0; 1]).unwrap(), 0);
+        drop(done_tx);
+    }
+
+    #[test]
+    fn rate_limit_per_window(){
+        let limit = RateLimit::new(2, 60);
+        assert_eq!(limit.check(600), (true, 0));
+        assert_eq!(limit.check(610), (true, 0));
+        assert_eq!(limit.check(620), (false, 0));
+        assert_eq!(limit.check(659), (false, 0));
+        assert_eq!(limit.check(660), (true, 2));
+        assert_eq!(limit.check(661), (true, 0));
+    }
 }
@@ -0,0 +1,85 @@
+use std::net::{ TcpListener, TcpStream };
+use std::io::{ Error, Read, BufReader };
+use byteorder::{ ReadBytesExt, BigEndian };
+
+use std::sync::mpsc::{ SyncSender };
+use std::thread::{ self, JoinHandle };
+
+use super::super::{ Config, NamedPoint };
+use super::{ Action, serve };
+use super::sockets::bind_tcp;
+use super::super::instrumentation::{ Counter, METRICS_RECEIVED, QUEUED_POINTS };
+use pickle;
+
+// Same ceiling as carbon's Int32StringReceiver. Anything bigger is
+// either a broken sender or someone poking at the port.
+const MAX_MESSAGE_LENGTH : u32 = 1024*1024;
+
+pub fn run_server(tx: SyncSender<Action>, bind_spec: &str, _: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    info!("pickle server binding to `{}`", bind_spec);
+    let listener = try!( bind_tcp(bind_spec) );
+
+    let accept_thread = thread::spawn(move ||{
+        debug!("waiting for incoming pickle streams");
+        serve(listener, tx, do_server)
+    });
+
+    debug!("cool, done booting pickle server");
+
+    Ok(accept_thread)
+}
+
+fn do_server(tx: SyncSender<Action>, tcp_stream: TcpStream) {
+    let peer = tcp_stream.peer_addr().map(|addr| addr.to_string()).unwrap_or("unknown peer".to_string());
+    read_pickles(tx, tcp_stream, &peer, None)
+}
+
+// Pickled batches off any stream until it closes. `received` counts this
+// sender's points on top of METRICS_RECEIVED.
+pub fn read_pickles<R: Read>(tx: SyncSender<Action>, stream: R, peer: &str, received: Option<&Counter>) {
+    let mut reader = BufReader::new(stream);
+    let mut message_buf : Vec<u8> = vec![];
+
+    loop {
+        let message_length = match reader.read_u32::<BigEndian>() {
+            Ok(len) => len,
+            Err(err) => {
+                debug!("pickle connection was closed: {:?}", err);
+                break;
+            }
+        };
+
+        if message_length > MAX_MESSAGE_LENGTH {
+            error!("pickle message of {} bytes from {} exceeds the {} byte limit. dropping connection.", message_length, peer, MAX_MESSAGE_LENGTH);
+            break;
+        }
+
+        message_buf.resize(message_length as usize, 0);
+        match reader.read_exact(&mut message_buf[..]) {
+            Ok(()) => (),
+            Err(err) => {
+                info!("pickle connection closed mid-message: {:?}", err);
+                break;
+            }
+        }
+
+        debug!("pickle listener read {} bytes", message_length);
+        let parsed_batch = pickle::unpickle(&message_buf[..]).and_then(|batch| pickle::datapoints(&batch));
+        match parsed_batch {
+            Ok(points) => {
+                METRICS_RECEIVED.add(points.len());
+                QUEUED_POINTS.add(points.len());
+                if let Some(received) = received {
+                    received.add(points.len());
+                }
+                for (path, timestamp, value) in points {
+                    tx.send(Action::Write(NamedPoint::new(path, timestamp, value))).unwrap();
+                }
+            },
+            Err(err) => {
+                error!("could not parse incoming pickle from {}: {:?}", peer, err);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Binding the TCP and UDP listeners. IPv6 sockets are made v6 only before
+// they're bound: on linux they otherwise take IPv4 too, so
+// `bind = 0.0.0.0:2003, [::]:2003` would fail with the address in use.
+
+use libc;
+
+use std::io::{ self, Error, ErrorKind };
+use std::mem;
+use std::net::{ SocketAddr, SocketAddrV6, TcpListener, ToSocketAddrs, UdpSocket };
+use std::os::unix::io::{ FromRawFd, RawFd };
+
+// Same as std's
+const BACKLOG : libc::c_int = 128;
+
+pub fn bind_tcp(bind_spec: &str) -> io::Result<TcpListener> {
+    each_addr(bind_spec, |addr| match addr {
+        SocketAddr::V4(_) => TcpListener::bind(addr),
+        SocketAddr::V6(addr) => {
+            let fd = try!( bind_v6_only(libc::SOCK_STREAM, &addr) );
+            if unsafe { libc::listen(fd, BACKLOG) } != 0 {
+                return Err(close_with_error(fd));
+            }
+            Ok(unsafe { TcpListener::from_raw_fd(fd) })
+        }
+    })
+}
+
+pub fn bind_udp(bind_spec: &str) -> io::Result<UdpSocket> {
+    each_addr(bind_spec, |addr| match addr {
+        SocketAddr::V4(_) => UdpSocket::bind(addr),
+        SocketAddr::V6(addr) => {
+            let fd = try!( bind_v6_only(libc::SOCK_DGRAM, &addr) );
+            Ok(unsafe { UdpSocket::from_raw_fd(fd) })
+        }
+    })
+}
+
+// Like std's bind, the first address `bind_spec` resolves to that works
+fn each_addr<S, F>(bind_spec: &str, mut bind: F) -> io::Result<S>
+    where F: FnMut(SocketAddr) -> io::Result<S>
+{
+    let mut last_err = None;
+    for addr in try!( bind_spec.to_socket_addrs() ) {
+        match bind(addr) {
+            Ok(socket) => return Ok(socket),
+            Err(err) => last_err = Some(err)
+        }
+    }
+    Err(last_err.unwrap_or(Error::new(ErrorKind::InvalidInput, format!("`{}` is no address", bind_spec))))
+}
+
+fn bind_v6_only(kind: libc::c_int, addr: &SocketAddrV6) -> io::Result<RawFd> {
+    let fd = try!( socket_v6(kind) );
+
+    let on : libc::c_int = 1;
+    let on_ptr = &on as *const libc::c_int as *const libc::c_void;
+    let on_len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe { libc::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, on_ptr, on_len) } != 0 {
+        return Err(close_with_error(fd));
+    }
+    // std sets it on its TCP listeners, so a restart can bind right away
+    if kind == libc::SOCK_STREAM && unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, on_ptr, on_len) } != 0 {
+        return Err(close_with_error(fd));
+    }
+
+    let mut sockaddr : libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sockaddr.sin6_port = addr.port().to_be();
+    sockaddr.sin6_flowinfo = addr.flowinfo();
+    sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+    sockaddr.sin6_scope_id = addr.scope_id();
+    let sockaddr_ptr = &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr;
+    if unsafe { libc::bind(fd, sockaddr_ptr, mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t) } != 0 {
+        return Err(close_with_error(fd));
+    }
+    Ok(fd)
+}
+
+// Close on exec like std's sockets, in one call where there's SOCK_CLOEXEC
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn socket_v6(kind: libc::c_int) -> io::Result<RawFd> {
+    let fd = unsafe { libc::socket(libc::AF_INET6, kind | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn socket_v6(kind: libc::c_int) -> io::Result<RawFd> {
+    let fd = unsafe { libc::socket(libc::AF_INET6, kind, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(close_with_error(fd));
+    }
+    Ok(fd)
+}
+
+fn close_with_error(fd: RawFd) -> Error {
+    let err = Error::last_os_error();
+    unsafe { libc::close(fd); }
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+    use super::{ bind_tcp, bind_udp };
+
+    #[test]
+    fn both_families_on_one_port(){
+        if let Err(err) = bind_tcp("[::]:0") {
+            println!("skipping, no IPv6 here: {}", err);
+            return;
+        }
+
+        let v4 = bind_tcp("0.0.0.0:0").unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind_tcp(&format!("[::]:{}", port)).unwrap();
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+        // Still only the one listener per address
+        assert!(bind_tcp(&format!("[::]:{}", port)).is_err());
+
+        let v4 = bind_udp("0.0.0.0:0").unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind_udp(&format!("[::]:{}", port)).unwrap();
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+        assert!(bind_udp(&format!("[::]:{}", port)).is_err());
+    }
+
+    #[test]
+    fn closed_on_exec(){
+        let listener = match bind_tcp("[::]:0") {
+            Ok(listener) => listener,
+            Err(err) => {
+                println!("skipping, no IPv6 here: {}", err);
+                return;
+            }
+        };
+        let flags = unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_GETFD) };
+        assert!(flags & libc::FD_CLOEXEC != 0);
+    }
+}
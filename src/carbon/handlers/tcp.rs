@@ -1,71 +1,118 @@
-use whisper::{ NamedPoint };
-
 use std::net::{ TcpListener, TcpStream };
-use std::io::{ Error, BufReader, BufRead };
+use std::io::{ Error, ErrorKind, Read, BufReader, BufRead };
 extern crate time;
 
 use std::sync::mpsc::{ sync_channel, SyncSender };
 use std::thread::{ self, JoinHandle };
 
-use super::super::Config;
-use super::Action;
+use super::super::{ Config, BadLinePolicy, NamedPoint, TcpMode };
+use super::{ Action, serve, bad_line };
+use super::sockets::bind_tcp;
+use super::super::instrumentation::{ Counter, METRICS_RECEIVED, QUEUED_POINTS };
 
-pub fn run_server(tx: SyncSender<Action>, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
-    info!("TCP server binding to `{}`", config.bind_spec);
-    let listener = try!( TcpListener::bind(config.bind_spec) );
+pub fn run_server(tx: SyncSender<Action>, bind_spec: &str, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    match config.tcp_mode {
+        TcpMode::Threaded => run_threaded(tx, bind_spec, config),
+        TcpMode::Evented => run_evented(tx, bind_spec, config)
+    }
+}
 
-    let listener_tx = tx.clone();
-    let accept_thread = thread::spawn(move ||{
-        let listener_tx = listener_tx;
+#[cfg(target_os = "linux")]
+fn run_evented(tx: SyncSender<Action>, bind_spec: &str, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    super::tcp_evented::run_server(tx, bind_spec, config)
+}
 
-        debug!("waiting for incoming streams");
-        for listen_result in listener.incoming() {
-            let tcp_stream = try!(listen_result);
-            let thread_tx = listener_tx.clone();
-            debug!("handling new stream");
-            thread::spawn(move || {
-                do_server(thread_tx, tcp_stream);
-            });
-        };
+// Evented is the default, so rather than refuse to start where there's no
+// epoll, serve the connections the old way
+#[cfg(not(target_os = "linux"))]
+fn run_evented(tx: SyncSender<Action>, bind_spec: &str, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    warn!("the evented TCP server needs epoll, falling back to a thread per connection");
+    run_threaded(tx, bind_spec, config)
+}
 
-        drop(listener);
+fn run_threaded(tx: SyncSender<Action>, bind_spec: &str, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    info!("TCP server binding to `{}`", bind_spec);
+    let listener = try!( bind_tcp(bind_spec) );
 
-        Ok(())
+    let bad_line_policy = config.bad_line_policy;
+    let accept_thread = thread::spawn(move ||{
+        debug!("waiting for incoming streams");
+        serve(listener, tx, move |tx, tcp_stream| do_server(tx, tcp_stream, bad_line_policy))
     });
-    
+
     debug!("cool, done booting TCP server");
 
     Ok(accept_thread)
 }
 
-fn do_server(tx: SyncSender<Action>, tcp_stream: TcpStream) {
+fn do_server(tx: SyncSender<Action>, tcp_stream: TcpStream, bad_line_policy: BadLinePolicy) {
+    let peer = tcp_stream.peer_addr().map(|addr| addr.to_string()).unwrap_or("unknown peer".to_string());
+    read_lines(tx, tcp_stream, &peer, None, bad_line_policy)
+}
+
+// The plaintext protocol off any stream until it closes. `received` counts
+// this sender's points on top of METRICS_RECEIVED.
+pub fn read_lines<R: Read>(tx: SyncSender<Action>, stream: R, peer: &str, received: Option<&Counter>, bad_line_policy: BadLinePolicy) {
     let mut line_buf = String::new();
-    let mut reader = BufReader::new(tcp_stream);
+    let mut reader = BufReader::new(stream);
 
     loop {
-        match reader.read_line(&mut line_buf) {
+        let bad = match reader.read_line(&mut line_buf) {
             Ok(bytes_read) => {
                 if bytes_read == 0 {
                     debug!("connection was closed");
                     break;
                 }
 
-                debug!("tcp listener read {} bytes", bytes_read);
-                let parsed_line = NamedPoint::parse_line(&(line_buf.trim_right())[..]);
-                match parsed_line {
-                    Ok(np) => tx.send(Action::Write(np)).unwrap(),
-                    Err(err) => {
-                        error!("could not parse incoming data: {:?}", err);
-                        break;
-                    }
+                debug!("line listener read {} bytes", bytes_read);
+                let parsed = receive_line(&tx, peer, line_buf.trim_right());
+                if let (true, Some(received)) = (parsed, received) {
+                    received.increment();
                 }
-
-                line_buf.clear();
+                !parsed
+            },
+            // read_line has already skipped past the line
+            Err(ref err) if err.kind() == ErrorKind::InvalidData => {
+                bad_line(peer, "", "not utf-8");
+                true
             },
             Err(err) => {
-                info!("shutting down tcp listener: {:?}", err);
+                info!("shutting down line listener: {:?}", err);
                 break
             }
         };
+
+        if bad && bad_line_policy == BadLinePolicy::Disconnect {
+            info!("disconnecting {} after an invalid line", peer);
+            break;
+        }
+        line_buf.clear();
+    }
+}
+
+// One line of the plaintext protocol, false if it didn't parse
+pub fn receive_line(tx: &SyncSender<Action>, peer: &str, line: &str) -> bool {
+    match accept_line(peer, line) {
+        Some(np) => {
+            tx.send(Action::Write(np)).unwrap();
+            true
+        },
+        None => false
+    }
+}
+
+// The point on `line`, counted as received and queued for the writer, for
+// the caller to send on. None if it didn't parse.
+pub fn accept_line(peer: &str, line: &str) -> Option<NamedPoint> {
+    match NamedPoint::parse_line(line) {
+        Ok(np) => {
+            METRICS_RECEIVED.increment();
+            QUEUED_POINTS.add(1);
+            Some(np)
+        },
+        Err(err) => {
+            bad_line(peer, line, &err);
+            None
+        }
     }
 }
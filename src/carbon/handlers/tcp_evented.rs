@@ -0,0 +1,651 @@
+// The plaintext line receiver with every connection on one thread, over
+// epoll, for when there are more senders than we'd want threads. Lines go
+// through `tcp::accept_line` same as the threaded one. On top of that it
+// caps the number of connections, hangs up on idle ones and bounds how much
+// of an unfinished line it holds for each.
+//
+// The loop never blocks on the writer: when its channel is full the
+// connection holds on to the point, stops being polled and is retried every
+// few milliseconds, so that sender waits while the others carry on.
+//
+// Nor does it give up when accepting fails, which mostly means we're out
+// of file descriptors. The listener stops being polled for a moment, or
+// until a connection closes, and the connections we have carry on.
+
+use libc;
+use time;
+
+use std::collections::{ HashMap, HashSet };
+use std::io::{ self, Error, ErrorKind, Read };
+use std::net::{ Shutdown, TcpListener, TcpStream };
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::ptr;
+use std::str;
+use std::sync::mpsc::{ SyncSender, TrySendError };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+
+use super::super::{ Config, BadLinePolicy };
+use super::super::shutdown;
+use super::{ Action, ACCEPT_BACKOFF_MS, accept_failed, bad_line, refuse };
+use super::sockets::bind_tcp;
+use super::tcp::accept_line;
+
+// epoll tokens: the listener, then one per connection
+const LISTENER : u64 = 0;
+const MAX_EVENTS : usize = 1024;
+// Reads per connection per wakeup, so one busy sender can't keep the loop
+// from the rest. Level triggered epoll brings us back for what's left.
+const MAX_READS_PER_EVENT : usize = 16;
+// How often connections waiting on a full writer channel try again
+const BLOCKED_RETRY_MS : i32 = 10;
+struct Limits {
+    max_connections: usize,
+    idle_timeout: u64,
+    read_buffer_size: usize,
+    bad_line_policy: BadLinePolicy
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer: String,
+    // What we've read past the last newline
+    partial: Vec<u8>,
+    last_read: u64,
+    // A point the writer had no room for yet
+    blocked: Option<Action>,
+    // The sender has hung up, close once `partial` is sent
+    eof: bool
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer: String) -> Connection {
+        Connection {
+            stream: stream,
+            peer: peer,
+            partial: vec![],
+            last_read: now(),
+            blocked: None,
+            eof: false
+        }
+    }
+}
+
+// Where `read_lines` left a connection
+#[derive(Debug, PartialEq)]
+enum Progress {
+    Open,
+    // Waiting for room in the writer channel, don't read from it until then
+    Blocked,
+    Closed
+}
+
+pub fn run_server(tx: SyncSender<Action>, bind_spec: &str, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    info!("evented TCP server binding to `{}`", bind_spec);
+    let listener = try!( bind_tcp(bind_spec) );
+    try!( listener.set_nonblocking(true) );
+    let epoll = try!( Epoll::new() );
+    try!( epoll.add(listener.as_raw_fd(), LISTENER) );
+
+    let limits = Limits {
+        max_connections: config.max_connections,
+        idle_timeout: config.idle_timeout,
+        read_buffer_size: config.read_buffer_size,
+        bad_line_policy: config.bad_line_policy
+    };
+
+    let event_loop = EventLoop {
+        epoll: epoll,
+        listener: Some(listener),
+        connections: HashMap::new(),
+        blocked: HashSet::new(),
+        accept_paused: None,
+        next_token: LISTENER + 1,
+        read_buf: vec![0; limits.read_buffer_size],
+        tx: tx,
+        limits: limits
+    };
+
+    Ok(thread::spawn(move || event_loop.run()))
+}
+
+struct EventLoop {
+    epoll: Epoll,
+    listener: Option<TcpListener>,
+    connections: HashMap<u64, Connection>,
+    // Connections taken out of epoll until the writer catches up
+    blocked: HashSet<u64>,
+    // Set while the listener is out of epoll after accept failed, to when
+    // it goes back in
+    accept_paused: Option<Instant>,
+    next_token: u64,
+    read_buf: Vec<u8>,
+    tx: SyncSender<Action>,
+    limits: Limits
+}
+
+impl EventLoop {
+    fn run(mut self) -> Result<(), Error> {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let mut last_sweep = now();
+
+        while !shutdown::requested() {
+            let blocked : Vec<u64> = self.blocked.iter().cloned().collect();
+            for token in blocked {
+                self.read(token);
+            }
+            if self.accept_paused.map_or(false, |until| Instant::now() >= until) {
+                self.resume_accepting();
+            }
+
+            let timeout_ms = if !self.blocked.is_empty() {
+                BLOCKED_RETRY_MS
+            } else if self.accept_paused.is_some() {
+                ACCEPT_BACKOFF_MS as i32
+            } else {
+                shutdown::POLL_INTERVAL_MS as i32
+            };
+            let ready = try!( self.epoll.wait(&mut events, timeout_ms) );
+            for event in &events[..ready] {
+                match event.u64 {
+                    LISTENER => self.accept(),
+                    token => { self.read(token); }
+                }
+            }
+
+            let now = now();
+            if now != last_sweep {
+                self.close_idle(now);
+                last_sweep = now;
+            }
+        }
+
+        // Like the threaded listener: stop accepting, read what's already
+        // been sent and let go of the sender. The writer is only draining its
+        // channel by now, so it's fine to wait on it.
+        info!("no longer accepting connections on {:?}", self.listener.as_ref().map(|listener| listener.local_addr()));
+        self.listener = None;
+        let tokens : Vec<u64> = self.connections.keys().cloned().collect();
+        for token in tokens {
+            if let Some(connection) = self.connections.get(&token) {
+                let _ = connection.stream.shutdown(Shutdown::Read);
+            }
+            while self.connections.contains_key(&token) {
+                if self.read(token) != Progress::Blocked {
+                    continue;
+                }
+                let action = self.connections.get_mut(&token).and_then(|connection| connection.blocked.take());
+                if let Some(action) = action {
+                    if self.tx.send(action).is_err() {
+                        self.close(token);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let accepted = match self.listener {
+                Some(ref listener) => listener.accept(),
+                None => return
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted || err.kind() == ErrorKind::ConnectionAborted => continue,
+                // EMFILE, ENFILE, ENOBUFS, ENOMEM
+                Err(err) => return self.pause_accepting(err)
+            };
+
+            if self.connections.len() >= self.limits.max_connections {
+                refuse(&peer.to_string(), self.limits.max_connections);
+                continue;
+            }
+
+            let token = self.next_token;
+            if let Err(err) = stream.set_nonblocking(true).and_then(|_| self.epoll.add(stream.as_raw_fd(), token)) {
+                info!("dropping connection from {}: {:?}", peer, err);
+                continue;
+            }
+            self.next_token += 1;
+
+            debug!("handling new stream from {}", peer);
+            self.connections.insert(token, Connection::new(stream, peer.to_string()));
+        }
+    }
+
+    // The connection that failed is still waiting and the listener is level
+    // triggered, so left in epoll it would wake us straight back up
+    fn pause_accepting(&mut self, err: Error) {
+        accept_failed(&err, self.connections.len());
+        if self.accept_paused.is_none() {
+            if let Some(ref listener) = self.listener {
+                let _ = self.epoll.delete(listener.as_raw_fd());
+            }
+        }
+        self.accept_paused = Some(Instant::now() + Duration::from_millis(ACCEPT_BACKOFF_MS));
+    }
+
+    fn resume_accepting(&mut self) {
+        if self.accept_paused.take().is_none() {
+            return;
+        }
+        let res = match self.listener {
+            Some(ref listener) => self.epoll.add(listener.as_raw_fd(), LISTENER),
+            None => return
+        };
+        if let Err(err) = res {
+            self.pause_accepting(err);
+        }
+    }
+
+    // What the socket has for us, then hang up if it's done or misbehaving,
+    // or stop polling it while the writer has no room
+    fn read(&mut self, token: u64) -> Progress {
+        let progress = match self.connections.get_mut(&token) {
+            Some(connection) => read_lines(connection, &mut self.read_buf, &self.tx, &self.limits),
+            None => return Progress::Closed
+        };
+        match progress {
+            Progress::Open => self.unblock(token),
+            Progress::Blocked => self.block(token),
+            Progress::Closed => self.close(token)
+        }
+        progress
+    }
+
+    fn block(&mut self, token: u64) {
+        if self.blocked.insert(token) {
+            debug!("writer is full, pausing {}", self.connections[&token].peer);
+            let _ = self.epoll.delete(self.connections[&token].stream.as_raw_fd());
+        }
+    }
+
+    fn unblock(&mut self, token: u64) {
+        if self.blocked.remove(&token) {
+            let connection = self.connections.get_mut(&token).unwrap();
+            connection.last_read = now();
+            if let Err(err) = self.epoll.add(connection.stream.as_raw_fd(), token) {
+                info!("dropping connection from {}: {:?}", connection.peer, err);
+                self.close(token);
+            }
+        }
+    }
+
+    fn close(&mut self, token: u64) {
+        if let Some(connection) = self.connections.remove(&token) {
+            debug!("closing connection from {}", connection.peer);
+            if !self.blocked.remove(&token) {
+                let _ = self.epoll.delete(connection.stream.as_raw_fd());
+            }
+            // That's a file descriptor free for the next one
+            self.resume_accepting();
+        }
+    }
+
+    fn close_idle(&mut self, now: u64) {
+        if self.limits.idle_timeout == 0 {
+            return;
+        }
+        let idle_timeout = self.limits.idle_timeout;
+        // Blocked ones are waiting on us, not the other way round
+        let idle : Vec<u64> = self.connections.iter()
+            .filter(|&(token, _)| !self.blocked.contains(token))
+            .filter(|&(_, connection)| now.saturating_sub(connection.last_read) >= idle_timeout)
+            .map(|(&token, _)| token)
+            .collect();
+        for token in idle {
+            info!("closing {}, nothing sent for {}s", self.connections[&token].peer, idle_timeout);
+            self.close(token);
+        }
+    }
+}
+
+// Sends on what's already buffered, then reads a bounded amount more
+fn read_lines(connection: &mut Connection, read_buf: &mut [u8], tx: &SyncSender<Action>, limits: &Limits) -> Progress {
+    if let Some(action) = connection.blocked.take() {
+        match send(connection, tx, action) {
+            Progress::Open => (),
+            progress => return progress
+        }
+    }
+
+    for _ in 0..MAX_READS_PER_EVENT {
+        match send_lines(connection, tx, limits) {
+            Progress::Open => (),
+            progress => return progress
+        }
+        if connection.eof {
+            return Progress::Closed
+        }
+
+        let bytes_read = match connection.stream.read(read_buf) {
+            Ok(0) => {
+                debug!("connection was closed");
+                // Like the threaded listener, a last line needn't end in a newline
+                if !connection.partial.is_empty() {
+                    connection.partial.push(b'\n');
+                }
+                connection.eof = true;
+                continue
+            },
+            Ok(bytes_read) => bytes_read,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Progress::Open,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                info!("dropping connection from {}: {:?}", connection.peer, err);
+                return Progress::Closed
+            }
+        };
+        connection.last_read = now();
+        connection.partial.extend_from_slice(&read_buf[..bytes_read]);
+
+        // Same as carbon's MAX_LENGTH, there's no telling where the next line starts
+        let unfinished = connection.partial.iter().rev().position(|&b| b == b'\n').unwrap_or(connection.partial.len());
+        if unfinished > limits.read_buffer_size {
+            bad_line(&connection.peer, &String::from_utf8_lossy(&connection.partial), "line too long");
+            return Progress::Closed
+        }
+    }
+
+    // Out of turns; what's left is sent on the next wakeup
+    match send_lines(connection, tx, limits) {
+        Progress::Open if connection.eof => Progress::Closed,
+        progress => progress
+    }
+}
+
+// Every complete line in `partial`, up to the first the writer has no room for
+fn send_lines(connection: &mut Connection, tx: &SyncSender<Action>, limits: &Limits) -> Progress {
+    let mut consumed = 0;
+    let mut progress = Progress::Open;
+    while let Some(newline) = connection.partial[consumed..].iter().position(|&b| b == b'\n') {
+        let point = match str::from_utf8(&connection.partial[consumed..consumed+newline]) {
+            Ok(line) => accept_line(&connection.peer, line.trim_right()),
+            Err(_) => {
+                bad_line(&connection.peer, "", "not utf-8");
+                None
+            }
+        };
+        consumed += newline + 1;
+
+        progress = match point {
+            Some(point) => send(connection, tx, Action::Write(point)),
+            None if limits.bad_line_policy == BadLinePolicy::Disconnect => {
+                info!("disconnecting {} after an invalid line", connection.peer);
+                Progress::Closed
+            },
+            None => Progress::Open
+        };
+        if progress != Progress::Open {
+            break
+        }
+    }
+    connection.partial.drain(..consumed);
+    progress
+}
+
+fn send(connection: &mut Connection, tx: &SyncSender<Action>, action: Action) -> Progress {
+    match tx.try_send(action) {
+        Ok(()) => Progress::Open,
+        Err(TrySendError::Full(action)) => {
+            connection.blocked = Some(action);
+            Progress::Blocked
+        },
+        Err(TrySendError::Disconnected(_)) => Progress::Closed
+    }
+}
+
+fn now() -> u64 {
+    time::get_time().sec as u64
+}
+
+struct Epoll(RawFd);
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Epoll(fd))
+    }
+
+    // Level triggered, readable
+    fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event { events: libc::EPOLLIN as u32, u64: token };
+        check(unsafe { libc::epoll_ctl(self.0, libc::EPOLL_CTL_ADD, fd, &mut event) })
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        check(unsafe { libc::epoll_ctl(self.0, libc::EPOLL_CTL_DEL, fd, ptr::null_mut()) })
+    }
+
+    // How many of `events` were filled in, none if a signal came first
+    fn wait(&self, events: &mut [libc::epoll_event], timeout_ms: i32) -> io::Result<usize> {
+        let ready = unsafe { libc::epoll_wait(self.0, events.as_mut_ptr(), events.len() as libc::c_int, timeout_ms) };
+        if ready < 0 {
+            let err = Error::last_os_error();
+            return if err.kind() == ErrorKind::Interrupted { Ok(0) } else { Err(err) };
+        }
+        Ok(ready as usize)
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::env;
+    use std::fs::{ self, File };
+    use std::io::Write;
+    use std::net::{ TcpListener, TcpStream };
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::mpsc::{ sync_channel, Receiver };
+    use std::thread;
+    use std::time::Duration;
+    use super::{ Connection, Limits, Progress, read_lines, run_server };
+    use super::super::super::{ Config, BadLinePolicy, FlushStrategy, TcpMode };
+    use super::super::Action;
+
+    fn accepted() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        (Connection::new(stream, peer.to_string()), sender)
+    }
+
+    fn limits(bad_line_policy: BadLinePolicy) -> Limits {
+        Limits { max_connections: 1, idle_timeout: 0, read_buffer_size: 64, bad_line_policy: bad_line_policy }
+    }
+
+    // What the event loop waits for before reading
+    fn readable(connection: &Connection) {
+        let mut fd = libc::pollfd { fd: connection.stream.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        assert_eq!(unsafe { libc::poll(&mut fd, 1, 5000) }, 1);
+    }
+
+    fn written(rx: &Receiver<Action>) -> Vec<String> {
+        rx.try_iter().map(|action| match action {
+            Action::Write(named_point) => format!("{:?} {}", named_point.rel_path(), named_point.point().1),
+            _ => panic!("expected a write")
+        }).collect()
+    }
+
+    #[test]
+    fn lines_across_reads(){
+        let (mut connection, mut sender) = accepted();
+        let (tx, rx) = sync_channel(10);
+        let mut read_buf = [0; 64];
+
+        sender.write_all(b"a.b 1 1437548400\na.c 2 1437").unwrap();
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Open);
+        assert_eq!(connection.partial, b"a.c 2 1437".to_vec());
+
+        sender.write_all(b"548400\r\nnonsense\na.d 3 1437548400\n").unwrap();
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Open);
+        assert!(connection.partial.is_empty());
+        assert_eq!(written(&rx), vec!["\"a/b.wsp\" 1", "\"a/c.wsp\" 2", "\"a/d.wsp\" 3"]);
+
+        drop(sender);
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Closed);
+    }
+
+    #[test]
+    fn last_line_without_newline(){
+        let (mut connection, mut sender) = accepted();
+        let (tx, rx) = sync_channel(10);
+        let mut read_buf = [0; 64];
+
+        sender.write_all(b"a.b 1 1437548400\na.c 2 1437548400").unwrap();
+        drop(sender);
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Closed);
+        assert_eq!(written(&rx), vec!["\"a/b.wsp\" 1", "\"a/c.wsp\" 2"]);
+    }
+
+    #[test]
+    fn waits_for_room_in_the_writer(){
+        let (mut connection, mut sender) = accepted();
+        let (tx, rx) = sync_channel(1);
+        let mut read_buf = [0; 64];
+
+        sender.write_all(b"a.b 1 1437548400\na.c 2 1437548400\na.d 3 1437548400\n").unwrap();
+        drop(sender);
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Blocked);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Blocked);
+        assert_eq!(written(&rx), vec!["\"a/b.wsp\" 1"]);
+
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Blocked);
+        assert_eq!(written(&rx), vec!["\"a/c.wsp\" 2"]);
+
+        // The last point goes out along with noticing the hang up
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Closed);
+        assert_eq!(written(&rx), vec!["\"a/d.wsp\" 3"]);
+    }
+
+    #[test]
+    fn takes_turns_with_other_connections(){
+        let (mut connection, mut sender) = accepted();
+        let (tx, rx) = sync_channel(100);
+        let mut read_buf = [0; 8];
+
+        let lines : Vec<u8> = (0..20).flat_map(|_| b"a.b 1 1437548400\n".to_vec()).collect();
+        sender.write_all(&lines).unwrap();
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Open);
+        let first_turn = written(&rx).len();
+        assert!(first_turn < 20);
+
+        readable(&connection);
+        read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip));
+        readable(&connection);
+        read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip));
+        assert_eq!(first_turn + written(&rx).len(), 20);
+    }
+
+    #[test]
+    fn hangs_up_on_bad_and_long_lines(){
+        let (tx, _rx) = sync_channel(10);
+        let mut read_buf = [0; 64];
+
+        let (mut connection, mut sender) = accepted();
+        sender.write_all(b"nonsense\n").unwrap();
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Disconnect)), Progress::Closed);
+
+        let (mut connection, mut sender) = accepted();
+        sender.write_all(&[b'a'; 200]).unwrap();
+        readable(&connection);
+        assert_eq!(read_lines(&mut connection, &mut read_buf, &tx, &limits(BadLinePolicy::Skip)), Progress::Closed);
+    }
+
+    // Lowering the fd limit would starve every other test running alongside,
+    // so the test binary runs this one again on its own
+    #[test]
+    fn keeps_going_without_file_descriptors(){
+        if env::var_os("CARBON_TEST_FD_LIMIT").is_none() {
+            let name = format!("{}::keeps_going_without_file_descriptors", module_path!().splitn(2, "::").nth(1).unwrap());
+            let status = Command::new(env::current_exe().unwrap())
+                .args(&["--exact", &name, "--test-threads", "1"])
+                .env("CARBON_TEST_FD_LIMIT", "1")
+                .status().unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (tx, rx) = sync_channel(10);
+        let config = Config {
+            listeners: vec![],
+            chan_depth: 10,
+            base_path: Path::new("/nonexistent"),
+            cache_size: 1,
+            max_cache_size: 1,
+            max_updates_per_second: 0,
+            flush_strategy: FlushStrategy::Sorted,
+            bad_line_policy: BadLinePolicy::Skip,
+            tcp_mode: TcpMode::Evented,
+            max_connections: 100,
+            idle_timeout: 0,
+            read_buffer_size: 1024
+        };
+        let _server = run_server(tx, &format!("127.0.0.1:{}", port), &config).unwrap();
+
+        // Room for a few more, then take all of it but the one the client needs
+        let highest = fs::read_dir("/proc/self/fd").unwrap()
+            .filter_map(|entry| entry.ok().and_then(|entry| entry.file_name().into_string().ok()))
+            .filter_map(|fd| fd.parse::<u64>().ok())
+            .max().unwrap();
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) }, 0);
+        limit.rlim_cur = highest + 4;
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+        let mut filler = vec![];
+        loop {
+            match File::open("/dev/null") {
+                Ok(file) => filler.push(file),
+                Err(ref err) if err.raw_os_error() == Some(libc::EMFILE) => break,
+                Err(err) => panic!("{:?}", err)
+            }
+        }
+        filler.pop();
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"a.b 1 1437548400\n").unwrap();
+
+        // Nothing to accept it with for a while
+        thread::sleep(Duration::from_millis(300));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(File::open("/dev/null").err().and_then(|err| err.raw_os_error()), Some(libc::EMFILE));
+
+        // Then there is, and the loop is still there to take it
+        filler.pop();
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Action::Write(named_point)) => assert_eq!(named_point.name(), "a.b"),
+            _ => panic!("the point never arrived")
+        }
+    }
+}
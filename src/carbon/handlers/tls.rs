@@ -0,0 +1,288 @@
+// TLS in front of the line and pickle receivers, a thread per connection.
+// The handshake happens on the connection's own thread so a slow client
+// doesn't hold up the accept loop. Like the evented TCP server it refuses
+// connections past `--max-connections` and hangs up on idle ones. When the listener asks for client
+// certificates the client is known by its CN, in the logs and in
+// `senders.<cn>.metricsReceived`.
+
+use openssl::nid::Nid;
+use openssl::ssl::{ SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode };
+
+use std::io::{ self, Error, ErrorKind };
+use std::net::{ TcpListener, TcpStream };
+use std::sync::mpsc::SyncSender;
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use super::super::{ Config, BadLinePolicy, Listener, Protocol, TlsConfig };
+use super::super::instrumentation;
+use super::{ Action, serve_at_most };
+use super::sockets::bind_tcp;
+use super::tcp::read_lines;
+use super::pickle::read_pickles;
+
+const HANDSHAKE_TIMEOUT_SECS : u64 = 10;
+
+pub fn run_server(tx: SyncSender<Action>, listener: &Listener, tls_config: &TlsConfig, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    let acceptor = try!( acceptor(tls_config) );
+    info!("TLS {:?} server binding to `{}`", listener.protocol, listener.bind_spec);
+    let tcp_listener = try!( bind_tcp(&listener.bind_spec) );
+
+    let protocol = listener.protocol;
+    let bad_line_policy = config.bad_line_policy;
+    let max_connections = config.max_connections;
+    let idle_timeout = if config.idle_timeout > 0 { Some(Duration::from_secs(config.idle_timeout)) } else { None };
+    Ok(thread::spawn(move || {
+        debug!("waiting for incoming TLS streams");
+        serve_at_most(tcp_listener, tx, max_connections, move |tx, tcp_stream| {
+            do_server(tx, &acceptor, tcp_stream, protocol, bad_line_policy, idle_timeout)
+        })
+    }))
+}
+
+pub fn acceptor(tls_config: &TlsConfig) -> io::Result<SslAcceptor> {
+    let mut builder = try!( SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(tls_error) );
+    try!( builder.set_certificate_chain_file(&tls_config.cert).map_err(tls_error) );
+    try!( builder.set_private_key_file(&tls_config.key, SslFiletype::PEM).map_err(tls_error) );
+    try!( builder.check_private_key().map_err(tls_error) );
+
+    if let Some(ref client_ca) = tls_config.client_ca {
+        try!( builder.set_ca_file(client_ca).map_err(tls_error) );
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(builder.build())
+}
+
+fn do_server(tx: SyncSender<Action>, acceptor: &SslAcceptor, tcp_stream: TcpStream, protocol: Protocol, bad_line_policy: BadLinePolicy, idle_timeout: Option<Duration>) {
+    let addr = tcp_stream.peer_addr().map(|addr| addr.to_string()).unwrap_or("unknown peer".to_string());
+    let tls_stream = match handshake(acceptor, tcp_stream, Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), idle_timeout) {
+        Ok(tls_stream) => tls_stream,
+        Err(err) => {
+            info!("TLS handshake with {} failed: {}", addr, err);
+            return
+        }
+    };
+
+    let (peer, received) = match client_cn(&tls_stream) {
+        Some(cn) => (format!("{} ({})", cn, addr), Some(instrumentation::sender(&cn))),
+        None => (addr, None)
+    };
+    debug!("TLS connection from {}", peer);
+
+    match protocol {
+        Protocol::Pickle => read_pickles(tx, tls_stream, &peer, received.as_ref().map(|received| &**received)),
+        _ => read_lines(tx, tls_stream, &peer, received.as_ref().map(|received| &**received), bad_line_policy)
+    }
+}
+
+// A client that connects and never finishes the handshake would otherwise
+// keep its thread forever. Once it's done, a read that waits longer than
+// `idle_timeout` ends the connection.
+fn handshake(acceptor: &SslAcceptor, tcp_stream: TcpStream, timeout: Duration, idle_timeout: Option<Duration>) -> io::Result<SslStream<TcpStream>> {
+    try!( tcp_stream.set_read_timeout(Some(timeout)) );
+    try!( tcp_stream.set_write_timeout(Some(timeout)) );
+    let tls_stream = try!( acceptor.accept(tcp_stream).map_err(tls_error) );
+    try!( tls_stream.get_ref().set_read_timeout(idle_timeout) );
+    try!( tls_stream.get_ref().set_write_timeout(None) );
+    Ok(tls_stream)
+}
+
+// Only there when the listener asked for a client certificate
+fn client_cn(tls_stream: &SslStream<TcpStream>) -> Option<String> {
+    let cert = match tls_stream.ssl().peer_certificate() {
+        Some(cert) => cert,
+        None => return None
+    };
+    let cn = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next().and_then(|entry| entry.data().as_utf8().ok());
+    cn.map(|cn| cn.to_string())
+}
+
+fn tls_error<E: ::std::fmt::Display>(err: E) -> Error {
+    Error::new(ErrorKind::Other, format!("TLS: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{ EcGroup, EcKey };
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{ PKey, Private };
+    use openssl::ssl::{ SslConnector, SslFiletype, SslMethod };
+    use openssl::x509::{ X509, X509NameBuilder };
+    use openssl::x509::extension::{ BasicConstraints, SubjectAlternativeName };
+
+    use std::fs::File;
+    use std::io::{ Read, Write };
+    use std::net::{ TcpListener, TcpStream };
+    use std::path::{ Path, PathBuf };
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+    use std::time::{ Duration, Instant };
+    use test_support::TempDir;
+
+    use super::{ acceptor, do_server, handshake };
+    use super::super::super::{ BadLinePolicy, Protocol, TlsConfig };
+    use super::super::super::instrumentation;
+    use super::super::Action;
+
+    // A certificate for `cn` signed by `issuer`, or self signed as a CA
+    fn certificate(cn: &str, serial: u32, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new().dns(cn).build(&builder.x509v3_context(Some(issuer_cert), None)).unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            },
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    fn write_pem(dir: &Path, name: &str, pem: Vec<u8>) -> PathBuf {
+        let path = dir.join(name);
+        File::create(&path).unwrap().write_all(&pem).unwrap();
+        path
+    }
+
+    // A CA, a server certificate for localhost and a client certificate,
+    // all as PEM files in `dir`
+    fn pki(dir: &Path) -> (TlsConfig, PathBuf, PathBuf) {
+        let (ca, ca_key) = certificate("carbon test CA", 1, None);
+        let (server, server_key) = certificate("localhost", 2, Some((&ca, &ca_key)));
+        let (client, client_key) = certificate("collector.example.com", 3, Some((&ca, &ca_key)));
+
+        let tls_config = TlsConfig {
+            cert: write_pem(dir, "server.pem", server.to_pem().unwrap()),
+            key: write_pem(dir, "server.key", server_key.private_key_to_pem_pkcs8().unwrap()),
+            client_ca: Some(write_pem(dir, "ca.pem", ca.to_pem().unwrap()))
+        };
+        let client_cert = write_pem(dir, "client.pem", client.to_pem().unwrap());
+        let client_key = write_pem(dir, "client.key", client_key.private_key_to_pem_pkcs8().unwrap());
+        (tls_config, client_cert, client_key)
+    }
+
+    // Connects a client, with or without its certificate, sends `payload`
+    // and returns what the server made of it
+    fn send(tls_config: &TlsConfig, client_cert: Option<(&Path, &Path)>, protocol: Protocol, payload: &'static [u8]) -> Vec<String> {
+        let acceptor = acceptor(tls_config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_ca_file(tls_config.client_ca.as_ref().unwrap()).unwrap();
+        if let Some((cert, key)) = client_cert {
+            connector.set_certificate_file(cert, SslFiletype::PEM).unwrap();
+            connector.set_private_key_file(key, SslFiletype::PEM).unwrap();
+        }
+        let connector = connector.build();
+        let client = thread::spawn(move || {
+            let tcp_stream = TcpStream::connect(addr).unwrap();
+            // Fails one way or another when the server turns us down
+            if let Ok(mut tls_stream) = connector.connect("localhost", tcp_stream) {
+                let _ = tls_stream.write_all(payload);
+                let _ = tls_stream.shutdown();
+            }
+        });
+
+        let (tcp_stream, _) = listener.accept().unwrap();
+        let (tx, rx) = sync_channel(10);
+        do_server(tx, &acceptor, tcp_stream, protocol, BadLinePolicy::Skip, None);
+        client.join().unwrap();
+
+        rx.iter().map(|action| match action {
+            Action::Write(named_point) => format!("{:?} {}", named_point.rel_path(), named_point.point().1),
+            _ => panic!("expected a write")
+        }).collect()
+    }
+
+    #[test]
+    fn lines_and_pickles_from_known_clients(){
+        let dir = TempDir::new("carbon-tls-lines_and_pickles_from_known_clients");
+        let (tls_config, client_cert, client_key) = pki(dir.path());
+        let client = Some((client_cert.as_path(), client_key.as_path()));
+
+        let lines = send(&tls_config, client, Protocol::Tcp, b"a.b 1 1437548400\nnonsense\na.c 2 1437548400\n");
+        assert_eq!(lines, vec!["\"a/b.wsp\" 1", "\"a/c.wsp\" 2"]);
+
+        // [("a.b", (1437548400, 3))] as pickle protocol 2
+        let pickled = b"\x00\x00\x00\x1e\x80\x02]q\x00X\x03\x00\x00\x00a.bq\x01Jp?\xafUK\x03\x86q\x02\x86q\x03a.";
+        let points = send(&tls_config, client, Protocol::Pickle, pickled);
+        assert_eq!(points, vec!["\"a/b.wsp\" 3"]);
+
+        assert_eq!(instrumentation::sender("collector.example.com").take(), 3);
+    }
+
+    #[test]
+    fn refuses_clients_without_a_certificate(){
+        let dir = TempDir::new("carbon-tls-refuses_clients_without_a_certificate");
+        let (tls_config, _, _) = pki(dir.path());
+
+        assert!(send(&tls_config, None, Protocol::Tcp, b"a.b 1 1437548400\n").is_empty());
+    }
+
+    #[test]
+    fn gives_up_on_silent_clients(){
+        let dir = TempDir::new("carbon-tls-gives_up_on_silent_clients");
+        let (tls_config, _, _) = pki(dir.path());
+        let acceptor = acceptor(&tls_config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        // Connected, but never says hello
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (tcp_stream, _) = listener.accept().unwrap();
+        let started = Instant::now();
+        assert!(handshake(&acceptor, tcp_stream, Duration::from_millis(100), None).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn hangs_up_on_idle_clients(){
+        let dir = TempDir::new("carbon-tls-hangs_up_on_idle_clients");
+        let (tls_config, _, _) = pki(dir.path());
+        // No client certificates, so this client isn't counted as a sender
+        let ca = tls_config.client_ca.clone().unwrap();
+        let acceptor = acceptor(&TlsConfig { client_ca: None, ..tls_config }).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_ca_file(&ca).unwrap();
+        let connector = connector.build();
+        // Says one line, then nothing until the server gives up on it
+        let client = thread::spawn(move || {
+            let mut tls_stream = connector.connect("localhost", TcpStream::connect(addr).unwrap()).unwrap();
+            tls_stream.write_all(b"a.b 1 1437548400\n").unwrap();
+            let _ = tls_stream.read(&mut [0; 1]);
+        });
+
+        let (tcp_stream, _) = listener.accept().unwrap();
+        let (tx, rx) = sync_channel(10);
+        let started = Instant::now();
+        do_server(tx, &acceptor, tcp_stream, Protocol::Tcp, BadLinePolicy::Skip, Some(Duration::from_millis(200)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        client.join().unwrap();
+        assert_eq!(rx.iter().count(), 1);
+    }
+}
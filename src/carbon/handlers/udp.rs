@@ -1,27 +1,32 @@
-use whisper::{ NamedPoint };
-
 use std::thread::{ self, JoinHandle };
 use std::net::UdpSocket;
-use std::io::Error;
+use std::io::{ Error, ErrorKind };
 use std::sync::mpsc::{ SyncSender };
+use std::time::Duration;
 
-use super::super::Config;
-use super::Action;
+use super::super::{ Config, NamedPoint };
+use super::{ Action, bad_line };
+use super::sockets::bind_udp;
+use super::super::shutdown;
+use super::super::instrumentation::{ METRICS_RECEIVED, QUEUED_POINTS };
 
-pub fn run_server<'a>(tx: SyncSender<Action>, config: &Config) -> Result<JoinHandle<()>,Error> {
-    info!("UDP server binding to `{}`", config.bind_spec);
+pub fn run_server(tx: SyncSender<Action>, bind_spec: &str, _: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    info!("UDP server binding to `{}`", bind_spec);
     let mut buf_box = create_buffer();
-    let socket = try!( UdpSocket::bind(config.bind_spec) );
+    let socket = try!( bind_udp(bind_spec) );
+    // Wake up now and then to see if we should stop
+    try!( socket.set_read_timeout(Some(Duration::from_millis(shutdown::POLL_INTERVAL_MS))) );
 
     let join_handle = thread::spawn(move ||{
-        loop {
-            let (bytes_read,_) = {
+        while !shutdown::requested() {
+            let (bytes_read, peer) = {
                 // debug!("waiting on recv from socket");
 
                 match socket.recv_from( &mut buf_box[..] ) {
                     Ok(res) => {
                         res
                     },
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => continue,
                     Err(err) => {
                         error!("error reading from socket: {:?}", err);
                         continue;
@@ -29,26 +34,35 @@ pub fn run_server<'a>(tx: SyncSender<Action>, config: &Config) -> Result<JoinHan
                 }
             };
 
-            debug!("parsing point...");
-
-            match NamedPoint::from_datagram(&buf_box[0..bytes_read]) {
-                Ok(named_points) => {
-                    // Dies if the receiver is closed
-                    debug!("putting message on tx");
-                    for named_point in named_points {
-                        tx.send(Action::Write(named_point)).unwrap();
-                    }
-                },
-                Err(err) => {
-                    debug!("wtf mate: {:?}", err);
-                }
-            };
+            receive_datagram(&tx, &peer.to_string(), &buf_box[0..bytes_read]);
         }
+        info!("UDP server no longer reading");
+        Ok(())
     });
     Ok(join_handle)
 }
 
-fn create_buffer() -> Box<[u8]> {
+// One datagram of plaintext lines
+pub fn receive_datagram(tx: &SyncSender<Action>, peer: &str, datagram: &[u8]) {
+    debug!("parsing point...");
+
+    match NamedPoint::from_datagram(datagram) {
+        Ok(named_points) => {
+            // Dies if the receiver is closed
+            debug!("putting message on tx");
+            METRICS_RECEIVED.add(named_points.len());
+            QUEUED_POINTS.add(named_points.len());
+            for named_point in named_points {
+                tx.send(Action::Write(named_point)).unwrap();
+            }
+        },
+        Err(err) => {
+            bad_line(peer, &String::from_utf8_lossy(datagram), &err);
+        }
+    };
+}
+
+pub fn create_buffer() -> Box<[u8]> {
     let buf : [u8; 8*1024] = [0; 8*1024];
     Box::new( buf )
 }
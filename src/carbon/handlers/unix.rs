@@ -0,0 +1,208 @@
+// The plaintext line protocol on unix sockets, for senders on the same
+// host. `unix` is a stream socket read like a TCP connection, `unix-dgram`
+// takes datagrams like the UDP listener. The socket file gets the listener's
+// mode, owner and group, and is removed again once we stop reading.
+
+use libc;
+
+use std::ffi::CString;
+use std::fs;
+use std::io::{ self, Error, ErrorKind };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{ DirBuilderExt, FileTypeExt, PermissionsExt };
+use std::os::unix::net::{ UnixListener, UnixDatagram };
+use std::path::{ Path, PathBuf };
+use std::process;
+use std::sync::mpsc::SyncSender;
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use super::super::{ Config, Listener };
+use super::super::shutdown;
+use super::{ Action, serve };
+use super::tcp::read_lines;
+use super::udp::{ receive_datagram, create_buffer };
+
+pub fn run_stream_server(tx: SyncSender<Action>, listener: &Listener, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    let path = PathBuf::from(&listener.bind_spec);
+    info!("unix stream server binding to `{}`", path.display());
+    let unix_listener = try!( bind(&path, listener, |path| UnixListener::bind(path)) );
+
+    let bad_line_policy = config.bad_line_policy;
+    let peer = format!("unix:{}", path.display());
+    Ok(thread::spawn(move || {
+        debug!("waiting for incoming unix streams");
+        let res = serve(unix_listener, tx, move |tx, unix_stream| read_lines(tx, unix_stream, &peer, None, bad_line_policy));
+        let _ = fs::remove_file(&path);
+        res
+    }))
+}
+
+pub fn run_datagram_server(tx: SyncSender<Action>, listener: &Listener, _: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    let path = PathBuf::from(&listener.bind_spec);
+    info!("unix datagram server binding to `{}`", path.display());
+    let socket = try!( bind(&path, listener, |path| UnixDatagram::bind(path)) );
+    // Wake up now and then to see if we should stop
+    try!( socket.set_read_timeout(Some(Duration::from_millis(shutdown::POLL_INTERVAL_MS))) );
+
+    let peer = format!("unix-dgram:{}", path.display());
+    Ok(thread::spawn(move || {
+        let mut buf_box = create_buffer();
+        while !shutdown::requested() {
+            let bytes_read = match socket.recv(&mut buf_box[..]) {
+                Ok(bytes_read) => bytes_read,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => continue,
+                Err(err) => {
+                    error!("error reading from socket: {:?}", err);
+                    continue;
+                }
+            };
+            receive_datagram(&tx, &peer, &buf_box[0..bytes_read]);
+        }
+        info!("unix datagram server no longer reading");
+        let _ = fs::remove_file(&path);
+        Ok(())
+    }))
+}
+
+// Binds over a socket file an earlier carbon left behind, with the
+// listener's mode and ownership. Anything else already at `path` makes the
+// bind fail.
+fn bind<S, F>(path: &Path, listener: &Listener, bind_socket: F) -> io::Result<S>
+    where F: FnOnce(&Path) -> io::Result<S>
+{
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            try!( fs::remove_file(path) );
+        }
+    }
+    if listener.mode.is_none() && listener.owner.is_none() && listener.group.is_none() {
+        return bind_socket(path);
+    }
+
+    // Until its mode and owner are right, the socket sits in a directory
+    // only we can get into, then it's moved to where senders look for it
+    let private_dir = private_dir(path);
+    let _ = fs::remove_dir_all(&private_dir);
+    try!( fs::DirBuilder::new().mode(0o700).create(&private_dir) );
+    let private_path = private_dir.join("socket");
+    let bound = bind_socket(&private_path)
+        .and_then(|socket| apply_permissions(&private_path, listener).map(|_| socket))
+        .and_then(|socket| fs::rename(&private_path, path).map(|_| socket));
+    let _ = fs::remove_dir_all(&private_dir);
+    bound
+}
+
+// Next to `path`, so the rename stays on one filesystem
+fn private_dir(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}", name, process::id()))
+}
+
+fn apply_permissions(path: &Path, listener: &Listener) -> io::Result<()> {
+    if let Some(mode) = listener.mode {
+        try!( fs::set_permissions(path, fs::Permissions::from_mode(mode)) );
+    }
+    if listener.owner.is_some() || listener.group.is_some() {
+        // -1 leaves that one as it is
+        let uid = match listener.owner {
+            Some(ref owner) => try!( user_id(owner) ),
+            None => !0
+        };
+        let gid = match listener.group {
+            Some(ref group) => try!( group_id(group) ),
+            None => !0
+        };
+        let c_path = try!( c_string(path.as_os_str().as_bytes()) );
+        if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// A user name or a numeric uid
+fn user_id(user: &str) -> io::Result<libc::uid_t> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = try!( c_string(user.as_bytes()) );
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(Error::new(ErrorKind::NotFound, format!("no user `{}`", user)));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+// A group name or a numeric gid
+fn group_id(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = try!( c_string(group.as_bytes()) );
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(Error::new(ErrorKind::NotFound, format!("no group `{}`", group)));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|_| Error::new(ErrorKind::InvalidInput, "contains a NUL byte"))
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::{ MetadataExt, PermissionsExt };
+    use std::os::unix::net::{ UnixListener, UnixStream };
+    use std::sync::mpsc::sync_channel;
+    use test_support::TempDir;
+    use super::{ bind, user_id, group_id };
+    use super::super::super::{ BadLinePolicy, Listener, Protocol };
+    use super::super::tcp::read_lines;
+    use super::super::Action;
+
+    #[test]
+    fn stream_socket_with_mode(){
+        let dir = TempDir::new("carbon-unix-stream_socket_with_mode");
+        let path = dir.path().join("carbon.sock");
+        // Left over from a previous run
+        drop(UnixListener::bind(&path).unwrap());
+
+        let gid = unsafe { libc::getgid() };
+        let mut listener = Listener::new("local", Protocol::Unix, path.to_str().unwrap());
+        listener.mode = Some(0o600);
+        listener.group = Some(gid.to_string());
+        let unix_listener = bind(&path, &listener, |path| UnixListener::bind(path)).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(metadata.gid(), gid);
+        // Nothing left of where it was bound
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut sender = UnixStream::connect(&path).unwrap();
+        sender.write_all(b"a.b 1 1437548400\nnonsense\n").unwrap();
+        drop(sender);
+        let (stream, _) = unix_listener.accept().unwrap();
+        let (tx, rx) = sync_channel(10);
+        read_lines(tx, stream, "test", None, BadLinePolicy::Skip);
+
+        let written : Vec<String> = rx.iter().map(|action| match action {
+            Action::Write(named_point) => format!("{:?} {}", named_point.rel_path(), named_point.point().1),
+            _ => panic!("expected a write")
+        }).collect();
+        assert_eq!(written, vec!["\"a/b.wsp\" 1"]);
+    }
+
+    #[test]
+    fn users_and_groups(){
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert_eq!(user_id("1234").unwrap(), 1234);
+        assert!(user_id("no-such-user-here").is_err());
+        assert_eq!(group_id("0").unwrap(), 0);
+        assert!(group_id("no-such-group-here").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+/*
+
+Graphite's `ConsistentHashRing` from carbon/hashing.py, with its default
+`carbon_ch` hash, so a relay here sends each metric to the same
+destinations a python carbon-relay with the same DESTINATIONS would.
+
+Every node goes on the ring 100 times, at the first 16 bits of the md5 of
+`"<python repr of (server, instance)>:<i>"`, bumped along by one while the
+spot is taken. A metric goes to the node at or after the md5 of its name,
+and its replicas to the next distinct nodes around the ring.
+
+*/
+
+use md5;
+
+pub const REPLICA_COUNT : usize = 100;
+
+// Python keys the ring on `(server, instance)`, without the port
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Node {
+    pub server: String,
+    pub instance: Option<String>
+}
+
+impl Node {
+    pub fn new(server: &str, instance: Option<&str>) -> Node {
+        Node {
+            server: server.to_string(),
+            instance: instance.map(|instance| instance.to_string())
+        }
+    }
+
+    // What `"%s" % (server, instance)` gives in python
+    fn python_repr(&self) -> String {
+        let instance = match self.instance {
+            Some(ref instance) => python_str_repr(instance),
+            None => "None".to_string()
+        };
+        format!("({}, {})", python_str_repr(&self.server), instance)
+    }
+}
+
+// Single quotes unless that would need escaping and double quotes wouldn't
+fn python_str_repr(s: &str) -> String {
+    let quote = if s.contains('\'') && !s.contains('"') { '"' } else { '\'' };
+    let mut repr = String::with_capacity(s.len() + 2);
+    repr.push(quote);
+    for c in s.chars() {
+        if c == '\\' || c == quote {
+            repr.push('\\');
+        }
+        repr.push(c);
+    }
+    repr.push(quote);
+    repr
+}
+
+pub struct ConsistentHashRing {
+    // (position, index into `nodes`), sorted by position
+    ring: Vec<(u32, usize)>,
+    nodes: Vec<Node>
+}
+
+impl ConsistentHashRing {
+    pub fn new(nodes: Vec<Node>) -> ConsistentHashRing {
+        let mut ring = ConsistentHashRing { ring: vec![], nodes: vec![] };
+        for node in nodes {
+            ring.add_node(node);
+        }
+        ring
+    }
+
+    // Like python, adding a node twice puts it on the ring twice as often.
+    // Nodes keep the index they were first added at.
+    pub fn add_node(&mut self, node: Node) {
+        let index = match self.nodes.iter().position(|known| *known == node) {
+            Some(index) => index,
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        let node_repr = self.nodes[index].python_repr();
+        for i in 0..REPLICA_COUNT {
+            let mut position = ring_position(&format!("{}:{}", node_repr, i));
+            loop {
+                match self.ring.binary_search_by_key(&position, |&(taken, _)| taken) {
+                    Ok(_) => position += 1,
+                    Err(insert_at) => {
+                        self.ring.insert(insert_at, (position, index));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    // The index of every distinct node, in the order `key`'s replicas go to
+    // them. Like python, the walk stops one short of going all the way around.
+    pub fn get_nodes(&self, key: &str) -> Vec<usize> {
+        if self.ring.is_empty() {
+            return vec![];
+        }
+
+        let position = ring_position(key);
+        let mut index = match self.ring.binary_search_by_key(&position, |&(taken, _)| taken) {
+            Ok(index) | Err(index) => index % self.ring.len()
+        };
+        if self.nodes.len() == 1 {
+            return vec![self.ring[index].1];
+        }
+
+        let last_index = (index + self.ring.len() - 1) % self.ring.len();
+        let mut nodes : Vec<usize> = Vec::with_capacity(self.nodes.len());
+        while nodes.len() < self.nodes.len() && index != last_index {
+            let node = self.ring[index].1;
+            if !nodes.contains(&node) {
+                nodes.push(node);
+            }
+            index = (index + 1) % self.ring.len();
+        }
+        nodes
+    }
+}
+
+// `int(md5(key).hexdigest()[:4], 16)`
+fn ring_position(key: &str) -> u32 {
+    let digest = md5::compute(key.as_bytes());
+    (digest[0] as u32) << 8 | digest[1] as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ ConsistentHashRing, Node, ring_position };
+
+    fn ring() -> ConsistentHashRing {
+        ConsistentHashRing::new(vec![
+            Node::new("127.0.0.1", Some("a")),
+            Node::new("127.0.0.1", Some("b")),
+            Node::new("10.0.0.2", None)
+        ])
+    }
+
+    fn placed(ring: &ConsistentHashRing, key: &str) -> Vec<String> {
+        ring.get_nodes(key).into_iter().map(|index| ring.node(index)).map(|node| format!("{}:{}", node.server, node.instance.as_ref().map(|instance| &instance[..]).unwrap_or("None"))).collect()
+    }
+
+    #[test]
+    fn node_keys_match_python(){
+        assert_eq!(Node::new("127.0.0.1", Some("a")).python_repr(), "('127.0.0.1', 'a')");
+        assert_eq!(Node::new("10.0.0.2", None).python_repr(), "('10.0.0.2', None)");
+        assert_eq!(Node::new("it's", Some("a\\b")).python_repr(), "(\"it's\", 'a\\\\b')");
+        assert_eq!(ring_position("('127.0.0.1', 'a'):0"), 24043);
+    }
+
+    // Expected placements come from carbon's hashing.py with the same nodes
+    #[test]
+    fn places_metrics_like_python(){
+        let ring = ring();
+        assert_eq!(ring.ring.len(), 300);
+        assert_eq!(&ring.ring[..3], &[(398, 0), (437, 2), (474, 0)]);
+        assert_eq!(ring.ring[299], (65162, 2));
+        // Both hash to 4618, the node added later gets bumped along
+        assert!(ring.ring.contains(&(4618, 0)) && ring.ring.contains(&(4619, 2)));
+
+        assert_eq!(placed(&ring, "carbon.agents.host1.cpuUsage"), vec!["127.0.0.1:a", "10.0.0.2:None", "127.0.0.1:b"]);
+        assert_eq!(placed(&ring, "collectd.web1.load.load.shortterm"), vec!["127.0.0.1:a", "10.0.0.2:None", "127.0.0.1:b"]);
+        assert_eq!(placed(&ring, "a.b"), vec!["10.0.0.2:None", "127.0.0.1:a", "127.0.0.1:b"]);
+        assert_eq!(placed(&ring, "m0"), vec!["127.0.0.1:b", "127.0.0.1:a", "10.0.0.2:None"]);
+
+        let single = ConsistentHashRing::new(vec![Node::new("10.0.0.9", None)]);
+        assert_eq!(placed(&single, "a.b"), vec!["10.0.0.9:None"]);
+        assert!(ConsistentHashRing::new(vec![]).get_nodes("a.b").is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+// Just enough of Python's ConfigParser to read carbon's config files.
+// Sections keep their order since graphite applies rules first-match-wins.
+
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<(String, String)>
+}
+
+impl Section {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|entry| entry.0 == key).map(|entry| &entry.1[..])
+    }
+}
+
+pub fn parse(contents: &str) -> Result<Vec<Section>, String> {
+    let mut sections : Vec<Section> = vec![];
+
+    for (index, raw_line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = raw_line.trim();
+
+        if line.len() == 0 || line.starts_with("#") || line.starts_with(";") {
+            continue;
+        }
+
+        if line.starts_with("[") {
+            if !line.ends_with("]") {
+                return Err(format!("line {}: unterminated section header `{}`", line_number, line));
+            }
+            sections.push(Section {
+                name: line[1..line.len()-1].trim().to_string(),
+                line: line_number,
+                entries: vec![]
+            });
+            continue;
+        }
+
+        let separator = match line.find(|c| c == '=' || c == ':') {
+            Some(pos) => pos,
+            None => return Err(format!("line {}: expected `key = value`, got `{}`", line_number, line))
+        };
+
+        let key = line[..separator].trim().to_lowercase();
+        let value = line[separator+1..].trim().to_string();
+
+        match sections.last_mut() {
+            Some(section) => section.entries.push((key, value)),
+            None => return Err(format!("line {}: `{}` appears before any [section]", line_number, key))
+        }
+    }
+
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn sections_in_order(){
+        let contents = "
+# comment
+[carbon]
+pattern = ^carbon\\.
+retentions = 60:90d
+
+[default]
+PATTERN = .*
+";
+        let sections = parse(contents).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].name, "carbon");
+        assert_eq!(sections[0].get("pattern"), Some("^carbon\\."));
+        assert_eq!(sections[0].get("retentions"), Some("60:90d"));
+        assert_eq!(sections[1].name, "default");
+        assert_eq!(sections[1].get("pattern"), Some(".*"));
+        assert_eq!(sections[1].line, 7);
+    }
+
+    #[test]
+    fn entry_before_section(){
+        assert!(parse("pattern = .*").is_err());
+    }
+}
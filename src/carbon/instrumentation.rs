@@ -0,0 +1,239 @@
+// Carbon's own metrics, `carbon.agents.<host>.*` like the python one. The
+// listeners and the writer bump the counters, and a reporter thread sends
+// them back through the writer channel every interval, as ordinary points.
+
+use libc;
+use time;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::SyncSender;
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use super::cache_writer::Shard;
+use super::NamedPoint;
+use super::handlers::Action;
+use super::shutdown;
+
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicUsize::new(0))
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // What was counted since the last call
+    pub fn take(&self) -> usize {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+// Goes up and down, unlike a Counter
+pub struct Gauge(AtomicUsize);
+
+impl Gauge {
+    pub const fn new() -> Gauge {
+        Gauge(AtomicUsize::new(0))
+    }
+
+    pub fn add(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, n: usize) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| Some(current.saturating_sub(n)));
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Points sent to the writer that it has yet to take off its channel
+pub static QUEUED_POINTS: Gauge = Gauge::new();
+// Points the listeners handed to the writer
+pub static METRICS_RECEIVED: Counter = Counter::new();
+// Points the writer put in whisper files, and how many writes that took
+pub static COMMITTED_POINTS: Counter = Counter::new();
+pub static UPDATE_OPERATIONS: Counter = Counter::new();
+// Whisper files created
+pub static CREATES: Counter = Counter::new();
+// Writes that failed
+pub static ERRORS: Counter = Counter::new();
+// Lines and datagrams that didn't parse
+pub static INVALID_LINES: Counter = Counter::new();
+// Counters there's one of per TLS client or relay destination, by the
+// name they're reported under
+static NAMED: Mutex<BTreeMap<String, Arc<Counter>>> = Mutex::new(BTreeMap::new());
+
+fn named(name: String) -> Arc<Counter> {
+    NAMED.lock().unwrap().entry(name).or_insert_with(|| Arc::new(Counter::new())).clone()
+}
+
+// Points from the client whose certificate has CN `cn`
+pub fn sender(cn: &str) -> Arc<Counter> {
+    named(format!("senders.{}.metricsReceived", metric_safe(cn)))
+}
+
+// `stat` for the relay destination `destination`, as carbon-relay names them
+pub fn destination(destination: &str, stat: &str) -> Arc<Counter> {
+    named(format!("destinations.{}.{}", metric_safe(destination), stat))
+}
+
+pub struct Config {
+    pub prefix: String,
+    pub interval: u64
+}
+
+// Reporting stops at shutdown so the writer isn't kept waiting on our sender
+pub fn spawn(tx: SyncSender<Action>, config: Config, shards: Vec<Shard>) -> JoinHandle<()> {
+    let prefix = format!("{}.{}", config.prefix, hostname());
+    info!("reporting carbon metrics as `{}.*` every {}s", prefix, config.interval);
+
+    thread::spawn(move || {
+        let mut last_cpu = cpu_seconds();
+        let mut last_time = time::precise_time_s();
+
+        loop {
+            let mut slept = 0;
+            while slept < config.interval * 1000 {
+                if shutdown::requested() {
+                    return ()
+                }
+                thread::sleep(Duration::from_millis(shutdown::POLL_INTERVAL_MS));
+                slept += shutdown::POLL_INTERVAL_MS;
+            }
+
+            let now = time::precise_time_s();
+            let cpu = cpu_seconds();
+            let cpu_usage = (cpu - last_cpu) / (now - last_time) * 100.0;
+            last_cpu = cpu;
+            last_time = now;
+
+            let committed_points = COMMITTED_POINTS.take();
+            let update_operations = UPDATE_OPERATIONS.take();
+            let (mut cache_size, mut cache_queues) = (0, 0);
+            for shard in &shards {
+                let pending = shard.pending.lock().unwrap();
+                cache_size += pending.len();
+                cache_queues += pending.file_count();
+            }
+
+            let mut stats = vec![
+                ("metricsReceived", METRICS_RECEIVED.take() as f64),
+                ("committedPoints", committed_points as f64),
+                ("updateOperations", update_operations as f64),
+                ("creates", CREATES.take() as f64),
+                ("errors", ERRORS.take() as f64),
+                ("invalidLines", INVALID_LINES.take() as f64),
+                ("cache.size", cache_size as f64),
+                ("cache.queues", cache_queues as f64),
+                ("cpuUsage", cpu_usage)
+            ];
+            if update_operations > 0 {
+                stats.push(("pointsPerUpdate", committed_points as f64 / update_operations as f64));
+            }
+            if let Some(mem_usage) = mem_usage() {
+                stats.push(("memUsage", mem_usage as f64));
+            }
+
+            let mut stats : Vec<(String, f64)> = stats.into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+            for (name, counter) in NAMED.lock().unwrap().iter() {
+                stats.push((name.clone(), counter.take() as f64));
+            }
+
+            let timestamp = time::get_time().sec as u32;
+            for (name, value) in stats {
+                let metric = format!("{}.{}", prefix, name);
+                QUEUED_POINTS.add(1);
+                if tx.send(Action::Write(NamedPoint::new(metric, timestamp, value))).is_err() {
+                    return ()
+                }
+            }
+        }
+    })
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    metric_safe(&String::from_utf8_lossy(&buf[..len]))
+}
+
+// Dots would split a host or CN into several nodes, and a space would end
+// the metric name
+fn metric_safe(name: &str) -> String {
+    name.replace(".", "_").replace(" ", "_")
+}
+
+// User plus system time
+fn cpu_seconds() -> f64 {
+    let mut usage : libc::rusage = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return 0.0;
+    }
+    let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0;
+    seconds(usage.ru_utime) + seconds(usage.ru_stime)
+}
+
+// Resident set size in bytes, where there's a /proc to ask
+fn mem_usage() -> Option<u64> {
+    let mut statm = String::new();
+    if File::open("/proc/self/statm").and_then(|mut file| file.read_to_string(&mut statm)).is_err() {
+        return None;
+    }
+    let resident_pages = match statm.split_whitespace().nth(1).and_then(|pages| pages.parse::<u64>().ok()) {
+        Some(pages) => pages,
+        None => return None
+    };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(resident_pages * page_size as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Counter, Gauge, metric_safe };
+
+    #[test]
+    fn counters_reset_when_reported(){
+        let counter = Counter::new();
+        counter.increment();
+        counter.add(4);
+        assert_eq!(counter.take(), 5);
+        assert_eq!(counter.take(), 0);
+    }
+
+    #[test]
+    fn gauges_stay_put_when_reported(){
+        let gauge = Gauge::new();
+        gauge.add(3);
+        gauge.sub(1);
+        assert_eq!(gauge.get(), 2);
+        assert_eq!(gauge.get(), 2);
+        // Writes sent straight to the writer, as tests do, never take it below 0
+        gauge.sub(5);
+        assert_eq!(gauge.get(), 0);
+    }
+
+    #[test]
+    fn host_is_one_node(){
+        assert_eq!(metric_safe("web1.example.com"), "web1_example_com");
+        assert_eq!(metric_safe("Web Tier"), "Web_Tier");
+    }
+}
@@ -0,0 +1,319 @@
+/*
+
+Which sockets carbon reads points from. Either `--listen PROTOCOL:ADDRESS`,
+as many times as needed, or a file in the same format as carbon's other
+config files:
+
+    [plaintext]
+    protocol = tcp
+    bind = 0.0.0.0:2003, [::]:2003
+
+    [plaintext-udp]
+    protocol = udp
+    bind = 127.0.0.1:2003
+    enabled = false
+
+    [pickle]
+    protocol = pickle
+    bind = 0.0.0.0:2004
+
+    [plaintext-tls]
+    protocol = tcp
+    bind = 0.0.0.0:2013
+    tls_cert = /etc/carbon/server.pem
+    tls_key = /etc/carbon/server.key
+    tls_client_ca = /etc/carbon/clients.pem
+
+    [local]
+    protocol = unix
+    bind = /run/carbon/carbon.sock
+    mode = 0660
+    owner = carbon
+    group = metrics
+
+Every address in `bind` gets its own listener. IPv6 ones take IPv6 only,
+so `0.0.0.0` and `[::]` can share a port. For the unix protocols the
+address is the socket's path, and `mode`, `owner` and `group` (names or
+ids) are applied to the socket file once it's created.
+
+`tls_cert` and `tls_key` make a `tcp` or `pickle` listener take TLS
+instead, which needs carbon built with the `tls` feature. With
+`tls_client_ca` too, clients have to present a certificate signed by it and
+are known by its CN in logs and in carbon's own metrics.
+
+*/
+
+use std::fs::File;
+use std::io::{ Error, Read };
+use std::path::{ Path, PathBuf };
+use std::sync::mpsc::SyncSender;
+use std::thread::JoinHandle;
+
+use super::Config;
+use super::handlers::{ tcp, udp, pickle, unix, Action };
+#[cfg(feature = "tls")]
+use super::handlers::tls;
+use super::ini;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Protocol {
+    // The plaintext line protocol over TCP
+    Tcp,
+    // The plaintext line protocol, a datagram at a time
+    Udp,
+    // Length prefixed pickles over TCP
+    Pickle,
+    // The plaintext line protocol over a unix stream socket
+    Unix,
+    // The plaintext line protocol over a unix datagram socket
+    UnixDatagram
+}
+
+impl Protocol {
+    pub fn from_str(name: &str) -> Option<Protocol> {
+        match name {
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            "pickle" => Some(Protocol::Pickle),
+            "unix" => Some(Protocol::Unix),
+            "unix-dgram" => Some(Protocol::UnixDatagram),
+            _ => None
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        *self == Protocol::Unix || *self == Protocol::UnixDatagram
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TlsConfig {
+    // PEM, the certificate followed by any intermediates
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // Clients must present a certificate signed by one of these when set
+    pub client_ca: Option<PathBuf>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Listener {
+    // The section it came from, or the `--listen` flag itself
+    pub name: String,
+    pub protocol: Protocol,
+    pub bind_spec: String,
+    pub enabled: bool,
+    // Unix sockets only. None leaves them as the umask and our user have them.
+    pub mode: Option<u32>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    // TCP and pickle only
+    pub tls: Option<TlsConfig>
+}
+
+impl Listener {
+    pub fn new(name: &str, protocol: Protocol, bind_spec: &str) -> Listener {
+        Listener {
+            name: name.to_string(),
+            protocol: protocol,
+            bind_spec: bind_spec.to_string(),
+            enabled: true,
+            mode: None,
+            owner: None,
+            group: None,
+            tls: None
+        }
+    }
+
+    // `--listen tcp:0.0.0.0:2003`, `--listen udp:[::1]:2003` or `--listen unix:/run/carbon.sock`
+    pub fn parse(spec: &str) -> Result<Listener, String> {
+        let separator = match spec.find(':') {
+            Some(pos) => pos,
+            None => return Err(format!("expected `protocol:address`, got `{}`", spec))
+        };
+        let protocol = match Protocol::from_str(&spec[..separator]) {
+            Some(protocol) => protocol,
+            None => return Err(format!("unknown protocol `{}` in `{}`", &spec[..separator], spec))
+        };
+        let bind_spec = &spec[separator+1..];
+        if bind_spec.is_empty() {
+            return Err(format!("no address in `{}`", spec));
+        }
+        Ok(Listener::new(spec, protocol, bind_spec))
+    }
+
+    pub fn spawn(&self, tx: SyncSender<Action>, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+        if let Some(ref tls_config) = self.tls {
+            return run_tls(tx, self, tls_config, config);
+        }
+        match self.protocol {
+            Protocol::Tcp => tcp::run_server(tx, &self.bind_spec, config),
+            Protocol::Udp => udp::run_server(tx, &self.bind_spec, config),
+            Protocol::Pickle => pickle::run_server(tx, &self.bind_spec, config),
+            Protocol::Unix => unix::run_stream_server(tx, self, config),
+            Protocol::UnixDatagram => unix::run_datagram_server(tx, self, config)
+        }
+    }
+}
+
+// Always a thread per connection, whatever `--tcp-mode` says
+#[cfg(feature = "tls")]
+fn run_tls(tx: SyncSender<Action>, listener: &Listener, tls_config: &TlsConfig, config: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    tls::run_server(tx, listener, tls_config, config)
+}
+
+#[cfg(not(feature = "tls"))]
+fn run_tls(_: SyncSender<Action>, _: &Listener, _: &TlsConfig, _: &Config) -> Result<JoinHandle<Result<(),Error>>,Error> {
+    Err(Error::new(::std::io::ErrorKind::Other, "TLS listeners need carbon built with the `tls` feature"))
+}
+
+pub fn load(path: &Path) -> Result<Vec<Listener>, String> {
+    let mut contents = String::new();
+    let read_res = File::open(path).and_then(|mut file| file.read_to_string(&mut contents));
+    if let Err(err) = read_res {
+        return Err(format!("could not read `{}`: {}", path.display(), err));
+    }
+    parse(&contents)
+}
+
+pub fn parse(contents: &str) -> Result<Vec<Listener>, String> {
+    let sections = try!( ini::parse(contents) );
+    let mut listeners = vec![];
+
+    for section in sections {
+        let protocol = match section.get("protocol") {
+            Some(name) => match Protocol::from_str(name) {
+                Some(protocol) => protocol,
+                None => return Err(format!("[{}]: unknown protocol `{}`", section.name, name))
+            },
+            None => return Err(format!("[{}]: missing `protocol`", section.name))
+        };
+
+        let enabled = match section.get("enabled") {
+            Some("true") | None => true,
+            Some("false") => false,
+            Some(other) => return Err(format!("[{}]: `enabled` must be true or false, not `{}`", section.name, other))
+        };
+
+        let bind_specs : Vec<&str> = match section.get("bind") {
+            Some(bind) => bind.split(',').map(|bind_spec| bind_spec.trim()).filter(|bind_spec| !bind_spec.is_empty()).collect(),
+            None => vec![]
+        };
+        if bind_specs.is_empty() {
+            return Err(format!("[{}]: missing `bind`", section.name));
+        }
+
+        let mode = match section.get("mode") {
+            Some(mode) => match u32::from_str_radix(mode, 8) {
+                Ok(mode) => Some(mode),
+                Err(_) => return Err(format!("[{}]: `mode` must be octal, like 0660, not `{}`", section.name, mode))
+            },
+            None => None
+        };
+        let owner = section.get("owner").map(|owner| owner.to_string());
+        let group = section.get("group").map(|group| group.to_string());
+        if !protocol.is_unix() && (mode.is_some() || owner.is_some() || group.is_some()) {
+            return Err(format!("[{}]: `mode`, `owner` and `group` only apply to unix sockets", section.name));
+        }
+
+        let tls = match (section.get("tls_cert"), section.get("tls_key")) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca: section.get("tls_client_ca").map(PathBuf::from)
+            }),
+            (None, None) if section.get("tls_client_ca").is_none() => None,
+            _ => return Err(format!("[{}]: TLS needs both `tls_cert` and `tls_key`", section.name))
+        };
+        if tls.is_some() && protocol != Protocol::Tcp && protocol != Protocol::Pickle {
+            return Err(format!("[{}]: only tcp and pickle listeners can use TLS", section.name));
+        }
+
+        for bind_spec in bind_specs {
+            let mut listener = Listener::new(&section.name, protocol, bind_spec);
+            listener.enabled = enabled;
+            listener.mode = mode;
+            listener.owner = owner.clone();
+            listener.group = group.clone();
+            listener.tls = tls.clone();
+            listeners.push(listener);
+        }
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{ Listener, Protocol, TlsConfig, parse };
+
+    #[test]
+    fn listen_flags(){
+        assert_eq!(Listener::parse("udp:[::1]:2003").unwrap(), Listener::new("udp:[::1]:2003", Protocol::Udp, "[::1]:2003"));
+        assert_eq!(Listener::parse("pickle:0.0.0.0:2004").unwrap().protocol, Protocol::Pickle);
+        assert!(Listener::parse("0.0.0.0:2003").is_err());
+        assert!(Listener::parse("tcp:").is_err());
+    }
+
+    #[test]
+    fn listeners_file(){
+        let listeners = parse("
+[plaintext]
+protocol = tcp
+bind = 0.0.0.0:2003, [::]:2003
+
+[plaintext-udp]
+protocol = udp
+bind = 127.0.0.1:2003
+enabled = false
+").unwrap();
+        assert_eq!(listeners.len(), 3);
+        assert_eq!(listeners[0], Listener::new("plaintext", Protocol::Tcp, "0.0.0.0:2003"));
+        assert_eq!(listeners[1], Listener::new("plaintext", Protocol::Tcp, "[::]:2003"));
+        assert_eq!(listeners[2].protocol, Protocol::Udp);
+        assert!(!listeners[2].enabled);
+
+        assert!(parse("[x]\nprotocol = sctp\nbind = :2003\n").is_err());
+        assert!(parse("[x]\nprotocol = tcp\n").is_err());
+        assert!(parse("[x]\nprotocol = tcp\nbind = :2003\nenabled = maybe\n").is_err());
+    }
+
+    #[test]
+    fn unix_socket_options(){
+        let listeners = parse("
+[local]
+protocol = unix-dgram
+bind = /run/carbon.sock
+mode = 0660
+group = metrics
+").unwrap();
+        assert_eq!(listeners[0].protocol, Protocol::UnixDatagram);
+        assert_eq!(listeners[0].bind_spec, "/run/carbon.sock");
+        assert_eq!(listeners[0].mode, Some(0o660));
+        assert_eq!(listeners[0].owner, None);
+        assert_eq!(listeners[0].group, Some("metrics".to_string()));
+
+        assert!(parse("[x]\nprotocol = unix\nbind = /a.sock\nmode = rw\n").is_err());
+        assert!(parse("[x]\nprotocol = tcp\nbind = :2003\nmode = 0660\n").is_err());
+    }
+
+    #[test]
+    fn tls_options(){
+        let listeners = parse("
+[pickle-tls]
+protocol = pickle
+bind = 0.0.0.0:2014
+tls_cert = /etc/carbon/server.pem
+tls_key = /etc/carbon/server.key
+").unwrap();
+        assert_eq!(listeners[0].tls, Some(TlsConfig {
+            cert: PathBuf::from("/etc/carbon/server.pem"),
+            key: PathBuf::from("/etc/carbon/server.key"),
+            client_ca: None
+        }));
+
+        assert!(parse("[x]\nprotocol = tcp\nbind = :2013\ntls_cert = a.pem\n").is_err());
+        assert!(parse("[x]\nprotocol = tcp\nbind = :2013\ntls_client_ca = ca.pem\n").is_err());
+        assert!(parse("[x]\nprotocol = udp\nbind = :2013\ntls_cert = a.pem\ntls_key = a.key\n").is_err());
+    }
+}
@@ -8,6 +8,24 @@ for
 mod handlers;
 pub mod cache_writer;
 mod config;
+mod ini;
+pub mod storage_schemas;
+pub mod storage_aggregation;
+pub mod whisper_cache;
+pub mod pending_points;
+pub mod shutdown;
+pub mod signals;
+pub mod instrumentation;
+pub mod listeners;
+pub mod hashing;
+pub mod relay;
+pub mod named_point;
 
-pub use self::handlers::{ tcp, udp };
-pub use self::config::Config;
+pub use self::handlers::{ tcp, udp, pickle, Action };
+pub use self::config::{ Config, BadLinePolicy, TcpMode, FlushStrategy };
+pub use self::listeners::{ Listener, Protocol, TlsConfig };
+pub use self::storage_schemas::StorageSchemas;
+pub use self::storage_aggregation::StorageAggregation;
+pub use self::whisper_cache::WhisperCache;
+pub use self::pending_points::PendingPoints;
+pub use self::named_point::NamedPoint;
@@ -0,0 +1,88 @@
+// A metric name and one point for it, as read off a plaintext line or a
+// datagram. whisper's `NamedPoint` only hands out the relative path it makes
+// of the name, which turns any `/` in it into a directory. The relay has to
+// pass names on as they came, so this one keeps the name and makes the path
+// when asked.
+
+use whisper::Point;
+
+use std::path::PathBuf;
+use std::str;
+
+#[derive(PartialEq, Debug)]
+pub struct NamedPoint {
+    name: String,
+    point: Point
+}
+
+impl NamedPoint {
+    pub fn new(name: String, timestamp: u32, value: f64) -> NamedPoint {
+        NamedPoint {
+            name: name,
+            point: Point(timestamp, value)
+        }
+    }
+
+    // `<name> <value> <timestamp>`. Unlike whisper's a value that isn't a
+    // number is an error, not a 0.
+    pub fn parse_line(line: &str) -> Result<NamedPoint, String> {
+        let parts : Vec<&str> = line.split(' ').collect();
+        if parts.len() != 3 {
+            return Err(format!("Datagram `{}` does not have 3 parts", line));
+        }
+        let value = try!( parts[1].parse::<f64>().map_err(|_| {
+            format!("Datagram value `{}` is not a float", parts[1])
+        }) );
+        let timestamp = try!( parts[2].parse::<u32>().map_err(|_| {
+            format!("Datagram value `{}` is not an unsigned integer", parts[2])
+        }) );
+        Ok(NamedPoint::new(parts[0].to_string(), timestamp, value))
+    }
+
+    // Every line or none of them, like whisper's
+    pub fn from_datagram(datagram: &[u8]) -> Result<Vec<NamedPoint>, String> {
+        let datagram = match str::from_utf8(datagram) {
+            Ok(body) => body,
+            Err(_) => return Err("invalid utf8 character".to_string())
+        };
+        let parsed : Result<Vec<NamedPoint>, String> = datagram.lines().map(NamedPoint::parse_line).collect();
+        parsed.map_err(|_| "datagram had invalid entries. skipping all.".to_string())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn rel_path(&self) -> PathBuf {
+        PathBuf::from(self.name.replace(".", "/") + ".wsp")
+    }
+
+    pub fn point(&self) -> &Point {
+        &self.point
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::NamedPoint;
+
+    #[test]
+    fn keeps_the_name(){
+        let named_point = NamedPoint::parse_line("web/1.cpu 2.5 1437548400").unwrap();
+        assert_eq!(named_point.name(), "web/1.cpu");
+        assert_eq!(named_point.rel_path(), PathBuf::from("web/1/cpu.wsp"));
+        assert_eq!((named_point.point().0, named_point.point().1), (1437548400, 2.5));
+
+        let named_points = NamedPoint::from_datagram(b"a.b 1 1437548400\r\na.c 2 1437548400\n").unwrap();
+        assert_eq!(named_points.iter().map(|named_point| named_point.name()).collect::<Vec<_>>(), vec!["a.b", "a.c"]);
+        assert!(NamedPoint::from_datagram(b"a.b 1 1437548400\nnonsense\n").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_values(){
+        assert!(NamedPoint::parse_line("foo.bar abc 1437548400").is_err());
+        assert!(NamedPoint::parse_line("foo.bar 1e3 1437548400").is_ok());
+        assert!(NamedPoint::from_datagram(b"a.b 1 1437548400\na.c abc 1437548400\n").is_err());
+    }
+}
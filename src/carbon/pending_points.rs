@@ -0,0 +1,131 @@
+// Points the writer has taken off the channel but not yet put on disk,
+// grouped by file. The graphite HTTP side reads these so the newest points
+// show up in renders before they've been written.
+
+use std::collections::{ BTreeMap, HashMap };
+use std::path::{ Path, PathBuf };
+
+#[derive(Debug, Default, Clone)]
+pub struct PendingPoints {
+    // Each file's points and its key in `order`
+    points: HashMap<PathBuf, (u64, Vec<(u32, f64)>)>,
+    // Files by when their first pending point arrived, oldest first. Keyed
+    // so a written file comes out without a scan.
+    order: BTreeMap<u64, PathBuf>,
+    arrivals: u64,
+    len: usize
+}
+
+impl PendingPoints {
+    pub fn new() -> PendingPoints {
+        PendingPoints::default()
+    }
+
+    // True for a file's first pending point
+    pub fn push(&mut self, metric_rel_path: PathBuf, timestamp: u32, value: f64) -> bool {
+        let first = !self.points.contains_key(&metric_rel_path);
+        if first {
+            self.arrivals += 1;
+            self.order.insert(self.arrivals, metric_rel_path.clone());
+        }
+        let arrival = self.arrivals;
+        self.points.entry(metric_rel_path).or_insert((arrival, vec![])).1.push((timestamp, value));
+        self.len += 1;
+        first
+    }
+
+    // Copies so the caller doesn't hold the lock while doing IO
+    pub fn get(&self, metric_rel_path: &Path) -> Vec<(u32, f64)> {
+        self.points.get(metric_rel_path).map(|&(_, ref points)| points.clone()).unwrap_or(vec![])
+    }
+
+    // The file that has been waiting longest
+    pub fn oldest(&self) -> Option<PathBuf> {
+        self.order.values().next().cloned()
+    }
+
+    // The file with the most points waiting, the longest waiting of those on a tie
+    pub fn largest(&self) -> Option<PathBuf> {
+        let mut largest : Option<&PathBuf> = None;
+        for metric_rel_path in self.order.values() {
+            if largest.map_or(true, |largest| self.points[metric_rel_path].1.len() > self.points[largest].1.len()) {
+                largest = Some(metric_rel_path);
+            }
+        }
+        largest.cloned()
+    }
+
+    #[cfg(test)]
+    fn contains(&self, metric_rel_path: &Path) -> bool {
+        self.points.contains_key(metric_rel_path)
+    }
+
+    // Called once a file's points are on disk
+    pub fn remove(&mut self, metric_rel_path: &Path) {
+        if let Some((arrival, points)) = self.points.remove(metric_rel_path) {
+            self.len -= points.len();
+            self.order.remove(&arrival);
+        }
+    }
+
+    // How many points each file has waiting, oldest first
+    pub fn files(&self) -> Vec<(PathBuf, usize)> {
+        self.order.values().map(|metric_rel_path| (metric_rel_path.clone(), self.points[metric_rel_path].1.len())).collect()
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.order.len()
+    }
+
+    // Number of points, not files
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{ Path, PathBuf };
+    use super::PendingPoints;
+
+    #[test]
+    fn oldest_file_first(){
+        let mut pending = PendingPoints::new();
+        pending.push(PathBuf::from("a/b.wsp"), 60, 1.0);
+        pending.push(PathBuf::from("c.wsp"), 60, 2.0);
+        pending.push(PathBuf::from("a/b.wsp"), 120, 3.0);
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending.get(Path::new("a/b.wsp")), vec![(60, 1.0), (120, 3.0)]);
+        assert_eq!(pending.files(), vec![(PathBuf::from("a/b.wsp"), 2), (PathBuf::from("c.wsp"), 1)]);
+
+        assert_eq!(pending.oldest(), Some(PathBuf::from("a/b.wsp")));
+        pending.remove(Path::new("a/b.wsp"));
+        assert!(!pending.contains(Path::new("a/b.wsp")));
+        assert_eq!(pending.oldest(), Some(PathBuf::from("c.wsp")));
+        // Written and back again, so it waits behind c
+        pending.push(PathBuf::from("a/b.wsp"), 180, 4.0);
+        assert_eq!(pending.oldest(), Some(PathBuf::from("c.wsp")));
+        pending.remove(Path::new("c.wsp"));
+        assert_eq!(pending.oldest(), Some(PathBuf::from("a/b.wsp")));
+        pending.remove(Path::new("a/b.wsp"));
+
+        assert!(pending.is_empty());
+        assert_eq!(pending.oldest(), None);
+        assert_eq!(pending.get(Path::new("c.wsp")), vec![]);
+    }
+
+    #[test]
+    fn largest_file(){
+        let mut pending = PendingPoints::new();
+        assert_eq!(pending.largest(), None);
+        pending.push(PathBuf::from("a.wsp"), 60, 1.0);
+        pending.push(PathBuf::from("b.wsp"), 60, 1.0);
+        assert_eq!(pending.largest(), Some(PathBuf::from("a.wsp")));
+        pending.push(PathBuf::from("b.wsp"), 120, 1.0);
+        assert_eq!(pending.largest(), Some(PathBuf::from("b.wsp")));
+    }
+}
@@ -0,0 +1,462 @@
+/*
+
+Relay mode: rather than writing points to whisper files, pass them on as
+plaintext lines to other carbons, like carbon-relay with
+`RELAY_METHOD = consistent-hashing`. Destinations are given the way
+DESTINATIONS spells them, `host:port[:instance]`, and each metric goes to
+the first `replication_factor` of them the ring picks for its name. With
+`diverse_replicas`, on by default as in carbon, that skips destinations on a
+host that already has a copy.
+
+Lines go out in the plaintext protocol, so destinations have to be line
+receivers. Python relays usually send pickles and their DESTINATIONS point
+at pickle ports (2004, or 2014 and up for carbon-cache instances); when
+swapping one for this relay, change those to the instances' line ports.
+
+Every destination has its own queue and sending thread, so one that's
+slow or down only holds up its own points. Its thread reconnects with a
+doubling delay, and while it's away new points queue up until the queue
+is full and then are dropped and counted. Once shutdown is requested a
+destination that can't be reached gets one more try, then whatever is left
+for it is dropped and counted so carbon can exit.
+
+*/
+
+use time;
+
+use std::cmp;
+use std::io::{ self, Error, ErrorKind, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::{ sync_channel, SyncSender, Receiver, TrySendError };
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use super::handlers::{ Action, RateLimit };
+use super::shutdown;
+use super::hashing::{ ConsistentHashRing, Node };
+use super::instrumentation::{ self, Counter };
+
+// How long to wait before reconnecting, doubling on every failed attempt
+const MIN_RECONNECT_DELAY_MS : u64 = 100;
+const MAX_RECONNECT_DELAY_MS : u64 = 30_000;
+
+// A destination that takes longer than this to accept or to take a batch
+// is treated as down
+const CONNECT_TIMEOUT_SECS : u64 = 5;
+const WRITE_TIMEOUT_SECS : u64 = 30;
+
+// Lines written per write call, at most
+const MAX_BATCH_LINES : usize = 500;
+
+static DROP_LOG: RateLimit = RateLimit::new(10, 60);
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+    pub instance: Option<String>
+}
+
+impl Destination {
+    // `host:port` or `host:port:instance`, with an IPv6 host in brackets
+    pub fn parse(spec: &str) -> Result<Destination, String> {
+        let spec = spec.trim();
+        let (host, rest) = if spec.starts_with("[") {
+            match spec.find(']') {
+                Some(end) => (&spec[1..end], &spec[end+1..]),
+                None => return Err(format!("unterminated `[` in `{}`", spec))
+            }
+        } else {
+            match spec.find(':') {
+                Some(separator) => (&spec[..separator], &spec[separator..]),
+                None => (spec, "")
+            }
+        };
+        if host.is_empty() || !rest.starts_with(":") {
+            return Err(format!("expected `host:port[:instance]`, got `{}`", spec));
+        }
+
+        let mut parts = rest[1..].splitn(2, ':');
+        let port = match parts.next().and_then(|port| port.parse().ok()) {
+            Some(port) => port,
+            None => return Err(format!("bad port in `{}`", spec))
+        };
+        let instance = parts.next().map(|instance| instance.to_string());
+
+        Ok(Destination { host: host.to_string(), port: port, instance: instance })
+    }
+
+    // A list of them, comma separated. carbon keys destinations on host and
+    // instance, so two that only differ in port would be one to the ring.
+    pub fn parse_all(specs: &str) -> Result<Vec<Destination>, String> {
+        let mut destinations : Vec<Destination> = vec![];
+        for spec in specs.split(',') {
+            let destination = try!( Destination::parse(spec) );
+            if destinations.iter().any(|other| other.node() == destination.node()) {
+                return Err(format!("`{}` has the same host and instance as another destination", spec.trim()));
+            }
+            destinations.push(destination);
+        }
+        Ok(destinations)
+    }
+
+    fn node(&self) -> Node {
+        Node::new(&self.host, self.instance.as_ref().map(|instance| &instance[..]))
+    }
+
+    fn addr(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    // carbon-relay's name for it in its own metrics
+    fn name(&self) -> String {
+        format!("{}:{}:{}", self.host, self.port, self.instance.as_ref().map(|instance| &instance[..]).unwrap_or("None"))
+    }
+}
+
+pub struct Config {
+    pub destinations: Vec<Destination>,
+    pub replication_factor: usize,
+    // Copies of a metric go to different hosts, DIVERSE_REPLICAS in carbon
+    pub diverse_replicas: bool,
+    // Lines each destination can have waiting
+    pub queue_size: usize
+}
+
+// A destination as the router sees it
+struct Queue {
+    destination: Destination,
+    tx: SyncSender<String>,
+    queued: Arc<AtomicUsize>,
+    full_queue_drops: Arc<Counter>
+}
+
+// Same shape as `cache_writer::spawn`, so the listeners don't know the
+// difference. The returned handle finishes once every destination has been
+// sent what was queued for it.
+pub fn spawn(config: Config, chan_depth: usize) -> (SyncSender<Action>, JoinHandle<()>) {
+    let (tx, rx) = sync_channel(chan_depth);
+    let ring = ConsistentHashRing::new(config.destinations.iter().map(|destination| destination.node()).collect());
+
+    let queue_size = config.queue_size;
+    let (queues, senders) : (Vec<_>, Vec<_>) = config.destinations.into_iter().map(|destination| {
+        let (queue_tx, queue_rx) = sync_channel(queue_size);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let sender = spawn_sender(destination.clone(), queue_rx, queued.clone());
+        let queue = Queue {
+            full_queue_drops: instrumentation::destination(&destination.name(), "fullQueueDrops"),
+            destination: destination,
+            tx: queue_tx,
+            queued: queued
+        };
+        (queue, sender)
+    }).unzip();
+
+    let replication_factor = config.replication_factor;
+    let diverse_replicas = config.diverse_replicas;
+    info!("relaying to {} destinations, {} copies of every point", queues.len(), replication_factor);
+    let router = thread::spawn(move || {
+        route(rx, &ring, &queues, replication_factor, diverse_replicas);
+        drop(queues);
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        debug!("all destinations are done");
+    });
+
+    (tx, router)
+}
+
+fn route(rx: Receiver<Action>, ring: &ConsistentHashRing, queues: &[Queue], replication_factor: usize, diverse_replicas: bool) {
+    for action in rx.iter() {
+        match action {
+            Action::Write(named_point) => {
+                instrumentation::QUEUED_POINTS.sub(1);
+                let point = named_point.point();
+                let line = format!("{} {} {}\n", named_point.name(), point.1, point.0);
+
+                for index in destinations_for(ring, named_point.name(), replication_factor, diverse_replicas) {
+                    enqueue(&queues[index], line.clone());
+                }
+            },
+            Action::DumpState => print!("{}", dump_state(queues)),
+            Action::Flush => info!("relaying, nothing to flush"),
+            Action::Reload => info!("relaying, nothing to reload")
+        }
+    }
+}
+
+// carbon's ConsistentHashingRouter.getDestinations. Destinations went on
+// the ring in order and no two share a node, so the ring's node indices are
+// indices into the destinations (and their queues) as well.
+fn destinations_for(ring: &ConsistentHashRing, name: &str, replication_factor: usize, diverse_replicas: bool) -> Vec<usize> {
+    let mut indices : Vec<usize> = vec![];
+    for index in ring.get_nodes(name) {
+        if diverse_replicas && indices.iter().any(|&used| ring.node(used).server == ring.node(index).server) {
+            continue;
+        }
+        indices.push(index);
+        if indices.len() >= replication_factor {
+            break;
+        }
+    }
+    indices
+}
+
+fn enqueue(queue: &Queue, line: String) {
+    match queue.tx.try_send(line) {
+        Ok(()) => {
+            queue.queued.fetch_add(1, Ordering::SeqCst);
+        },
+        Err(TrySendError::Full(line)) => {
+            queue.full_queue_drops.increment();
+            let (log, suppressed) = DROP_LOG.check(time::get_time().sec as u64);
+            if suppressed > 0 {
+                warn!("{} more dropped points were not logged", suppressed);
+            }
+            if log {
+                warn!("queue for {} is full, dropping {:?}", queue.destination.addr(), line.trim_right());
+            }
+        },
+        Err(TrySendError::Disconnected(_)) => unreachable!("destination threads outlive the router")
+    }
+}
+
+fn spawn_sender(destination: Destination, rx: Receiver<String>, queued: Arc<AtomicUsize>) -> JoinHandle<()> {
+    let sent = instrumentation::destination(&destination.name(), "sent");
+    let shutdown_drops = instrumentation::destination(&destination.name(), "shutdownDrops");
+    info!("spawning sender for {}...", destination.addr());
+
+    thread::spawn(move || {
+        let addr = destination.addr();
+        let mut connection : Option<TcpStream> = None;
+        let mut reconnect_delay = MIN_RECONNECT_DELAY_MS;
+        // Lines taken off the queue but not yet written
+        let mut batch = String::new();
+        let mut batch_lines = 0;
+
+        loop {
+            if batch_lines == 0 {
+                match rx.recv() {
+                    Ok(line) => {
+                        batch.push_str(&line);
+                        batch_lines = 1;
+                    },
+                    // The router is gone and everything it queued is sent
+                    Err(_) => break
+                }
+            }
+            while batch_lines < MAX_BATCH_LINES {
+                match rx.try_recv() {
+                    Ok(line) => {
+                        batch.push_str(&line);
+                        batch_lines += 1;
+                    },
+                    Err(_) => break
+                }
+            }
+
+            if connection.is_none() {
+                match connect(&addr) {
+                    Ok(stream) => {
+                        info!("connected to {}", addr);
+                        connection = Some(stream);
+                    },
+                    Err(err) => {
+                        if shutdown::requested() {
+                            warn!("could not connect to {} while shutting down: {}", addr, err);
+                            break;
+                        }
+                        warn!("could not connect to {}, trying again in {}ms: {}", addr, reconnect_delay, err);
+                        sleep_unless_shutdown(reconnect_delay);
+                        reconnect_delay = cmp::min(reconnect_delay * 2, MAX_RECONNECT_DELAY_MS);
+                        continue;
+                    }
+                }
+            }
+
+            // The whole batch goes again on a new connection if this fails,
+            // so the destination may see some lines twice
+            let write_res = connection.as_mut().unwrap().write_all(batch.as_bytes());
+            match write_res {
+                Ok(()) => {
+                    sent.add(batch_lines);
+                    queued.fetch_sub(batch_lines, Ordering::SeqCst);
+                    batch.clear();
+                    batch_lines = 0;
+                    reconnect_delay = MIN_RECONNECT_DELAY_MS;
+                },
+                Err(err) => {
+                    warn!("lost connection to {}: {}", addr, err);
+                    connection = None;
+                    if shutdown::requested() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Only left early when shutting down, the router may still be
+        // sending until the listeners have let go
+        if batch_lines > 0 || shutdown::requested() {
+            let mut dropped = batch_lines;
+            for _ in rx.iter() {
+                dropped += 1;
+            }
+            if dropped > 0 {
+                shutdown_drops.add(dropped);
+                queued.fetch_sub(dropped, Ordering::SeqCst);
+                warn!("shutting down, dropped {} points for {}", dropped, addr);
+            }
+        }
+        debug!("sender for {} is done", addr);
+    })
+}
+
+// The first of `addr`'s addresses that takes a connection in time
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for socket_addr in try!( addr.to_socket_addrs() ) {
+        match TcpStream::connect_timeout(&socket_addr, Duration::from_secs(CONNECT_TIMEOUT_SECS)) {
+            Ok(stream) => {
+                try!( stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS))) );
+                return Ok(stream);
+            },
+            Err(err) => last_err = Some(err)
+        }
+    }
+    Err(last_err.unwrap_or(Error::new(ErrorKind::InvalidInput, format!("`{}` is no address", addr))))
+}
+
+fn sleep_unless_shutdown(ms: u64) {
+    let mut slept = 0;
+    while slept < ms && !shutdown::requested() {
+        let nap = cmp::min(shutdown::POLL_INTERVAL_MS, ms - slept);
+        thread::sleep(Duration::from_millis(nap));
+        slept += nap;
+    }
+}
+
+fn dump_state(queues: &[Queue]) -> String {
+    let mut out = format!("carbon relay: {} destinations\n", queues.len());
+    for queue in queues {
+        out.push_str(&format!("  {} {} queued\n", queue.destination.name(), queue.queued.load(Ordering::SeqCst)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread::{ self, JoinHandle };
+    use std::time::Duration;
+
+    use super::{ Config, Destination, destinations_for, spawn };
+    use super::super::NamedPoint;
+    use super::super::handlers::Action;
+    use super::super::hashing::{ ConsistentHashRing, Node };
+
+    // Takes one connection and returns everything sent on it
+    fn stand_in(listener: TcpListener) -> JoinHandle<String> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).unwrap();
+            received
+        })
+    }
+
+    #[test]
+    fn destinations(){
+        assert_eq!(Destination::parse("127.0.0.1:2004:a").unwrap(),
+                   Destination { host: "127.0.0.1".to_string(), port: 2004, instance: Some("a".to_string()) });
+        assert_eq!(Destination::parse(" carbon1:2003 ").unwrap(),
+                   Destination { host: "carbon1".to_string(), port: 2003, instance: None });
+        let v6 = Destination::parse("[::1]:2003:b").unwrap();
+        assert_eq!((&v6.host[..], v6.port, v6.addr()), ("::1", 2003, "[::1]:2003".to_string()));
+
+        assert!(Destination::parse("carbon1").is_err());
+        assert!(Destination::parse("carbon1:port").is_err());
+        assert!(Destination::parse("[::1:2003").is_err());
+
+        assert_eq!(Destination::parse_all("carbon1:2003:a, carbon1:2003:b").unwrap().len(), 2);
+        assert!(Destination::parse_all("carbon1:2003:a,carbon1:2103:a").is_err());
+        assert!(Destination::parse_all("carbon1:2003,carbon1:2103").is_err());
+    }
+
+    #[test]
+    fn copies_on_different_hosts(){
+        let destinations = Destination::parse_all("10.0.0.1:2003:a,10.0.0.1:2103:b,10.0.0.2:2003:c,10.0.0.2:2103:d").unwrap();
+        let ring = ConsistentHashRing::new(destinations.iter().map(|destination| destination.node()).collect());
+        let node = |server: &str, instance: &str| Node::new(server, Some(instance));
+
+        // From carbon's hashing.py and ConsistentHashingRouter, REPLICATION_FACTOR = 2
+        let placements = [
+            ("servers.web0.cpu", [node("10.0.0.2", "d"), node("10.0.0.1", "b")], [node("10.0.0.2", "d"), node("10.0.0.1", "b")]),
+            ("servers.web2.cpu", [node("10.0.0.1", "b"), node("10.0.0.2", "d")], [node("10.0.0.1", "b"), node("10.0.0.1", "a")]),
+            ("servers.web9.cpu", [node("10.0.0.2", "d"), node("10.0.0.1", "b")], [node("10.0.0.2", "d"), node("10.0.0.2", "c")]),
+            ("servers.web18.cpu", [node("10.0.0.1", "a"), node("10.0.0.2", "d")], [node("10.0.0.1", "a"), node("10.0.0.1", "b")]),
+            ("servers.web21.cpu", [node("10.0.0.2", "d"), node("10.0.0.1", "a")], [node("10.0.0.2", "d"), node("10.0.0.2", "c")]),
+            ("servers.web23.cpu", [node("10.0.0.1", "a"), node("10.0.0.2", "c")], [node("10.0.0.1", "a"), node("10.0.0.1", "b")])
+        ];
+        let nodes = |indices: Vec<usize>| indices.into_iter().map(|index| ring.node(index).clone()).collect::<Vec<_>>();
+        for &(metric, ref diverse, ref same_host) in placements.iter() {
+            assert_eq!(nodes(destinations_for(&ring, metric, 2, true)), diverse.to_vec(), "{}", metric);
+            assert_eq!(nodes(destinations_for(&ring, metric, 2, false)), same_host.to_vec(), "{}", metric);
+        }
+    }
+
+    #[test]
+    fn each_metric_goes_where_the_ring_says(){
+        let listeners : Vec<TcpListener> = (0..3).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let destinations : Vec<Destination> = listeners.iter().zip(["a", "b", "c"].iter()).map(|(listener, instance)| {
+            Destination { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port(), instance: Some(instance.to_string()) }
+        }).collect();
+        let stand_ins : Vec<_> = listeners.into_iter().map(stand_in).collect();
+
+        // All on one host, so diverse replicas would leave one copy
+        let config = Config { destinations: destinations.clone(), replication_factor: 2, diverse_replicas: false, queue_size: 100 };
+        let (tx, relay) = spawn(config, 100);
+        // Passed on as they came, slashes and all
+        let metrics : Vec<String> = (0..20).map(|i| format!("relay.test/{}.m{}", i % 2, i)).collect();
+        for metric in &metrics {
+            tx.send(Action::Write(NamedPoint::new(metric.clone(), 1437548400, 1.5))).unwrap();
+        }
+        drop(tx);
+        relay.join().unwrap();
+
+        let received : Vec<String> = stand_ins.into_iter().map(|stand_in| stand_in.join().unwrap()).collect();
+        let ring = ConsistentHashRing::new(destinations.iter().map(|destination| destination.node()).collect());
+        for metric in &metrics {
+            let line = format!("{} 1.5 1437548400\n", metric);
+            let expected = destinations_for(&ring, metric, 2, false);
+            for (index, lines) in received.iter().enumerate() {
+                assert_eq!(lines.contains(&line), expected.contains(&index), "{} at {:?}", metric, destinations[index]);
+            }
+        }
+    }
+
+    #[test]
+    fn reconnects_to_a_late_destination(){
+        // Find a free port, then leave nothing listening on it for a while
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let destination = Destination { host: "127.0.0.1".to_string(), port: port, instance: None };
+
+        let config = Config { destinations: vec![destination], replication_factor: 1, diverse_replicas: true, queue_size: 100 };
+        let (tx, relay) = spawn(config, 100);
+        tx.send(Action::Write(NamedPoint::new("late.metric".to_string(), 1437548400, 2.0))).unwrap();
+        thread::sleep(Duration::from_millis(250));
+
+        let stand_in = stand_in(TcpListener::bind(("127.0.0.1", port)).unwrap());
+        drop(tx);
+        relay.join().unwrap();
+        assert_eq!(stand_in.join().unwrap(), "late.metric 2 1437548400\n");
+    }
+}
@@ -0,0 +1,91 @@
+// SIGTERM/SIGINT handling. The signal handler only flips a flag; listeners
+// poll it, stop accepting and stop reading, and once they've all let go of
+// their senders the writer drains what's left and exits.
+
+use libc;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{ Shutdown, TcpStream };
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// How long a listener blocks before looking at the flag again
+pub const POLL_INTERVAL_MS: u64 = 100;
+
+extern "C" fn handle_signal(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn install() {
+    unsafe {
+        libc::signal(libc::SIGTERM, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+// A connection `Connections` can tell to stop reading
+pub trait Stream: Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown_read(&self) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown_read(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Read)
+    }
+}
+
+// A listener's open connections, so they can all be told to stop reading.
+// Their threads see EOF once they're through what's already buffered.
+pub struct Connections<S: Stream> {
+    next_id: usize,
+    streams: HashMap<usize, S>
+}
+
+impl<S: Stream> Connections<S> {
+    pub fn new() -> Connections<S> {
+        Connections { next_id: 0, streams: HashMap::new() }
+    }
+
+    pub fn add(&mut self, stream: &S) -> io::Result<usize> {
+        let id = self.next_id;
+        self.streams.insert(id, try!( stream.try_clone() ));
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        self.streams.remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn close_all(&mut self) {
+        for (_, stream) in self.streams.drain() {
+            let _ = stream.shutdown_read();
+        }
+    }
+}
@@ -0,0 +1,47 @@
+// SIGUSR1, SIGUSR2 and SIGHUP. Like shutdown, the handler only sets a flag;
+// the main thread picks them up and turns them into `Action`s so the writer
+// sees them in order with the points around them.
+
+use libc;
+
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+static DUMP_STATE: AtomicBool = AtomicBool::new(false);
+static FLUSH: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Signal {
+    // USR1
+    DumpState,
+    // USR2
+    Flush,
+    // HUP
+    Reload
+}
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    let flag = match signal {
+        libc::SIGUSR1 => &DUMP_STATE,
+        libc::SIGUSR2 => &FLUSH,
+        libc::SIGHUP => &RELOAD,
+        _ => return
+    };
+    flag.store(true, Ordering::SeqCst);
+}
+
+pub fn install() {
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler);
+        libc::signal(libc::SIGUSR2, handler);
+        libc::signal(libc::SIGHUP, handler);
+    }
+}
+
+// Whatever came in since the last call. The same signal twice in between
+// counts once.
+pub fn take() -> Vec<Signal> {
+    let flags = [(&DUMP_STATE, Signal::DumpState), (&FLUSH, Signal::Flush), (&RELOAD, Signal::Reload)];
+    flags.iter().filter(|&&(flag, _)| flag.swap(false, Ordering::SeqCst)).map(|&(_, signal)| signal).collect()
+}
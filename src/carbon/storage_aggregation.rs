@@ -0,0 +1,174 @@
+/*
+
+Reads graphite's `storage-aggregation.conf`:
+
+    [count]
+    pattern = \.count$
+    xFilesFactor = 0
+    aggregationMethod = sum
+
+    [default_average]
+    pattern = .*
+    xFilesFactor = 0.5
+    aggregationMethod = average
+
+Like storage-schemas.conf the first matching rule wins. It is only consulted
+when a whisper file is created; the choice is stored in the file's header.
+
+*/
+
+use regex::Regex;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+
+use super::ini;
+use whisper_io::AggregationMethod;
+
+pub const DEFAULT_X_FILES_FACTOR : f32 = 0.5;
+pub const DEFAULT_AGGREGATION_METHOD : AggregationMethod = AggregationMethod::Average;
+
+pub struct AggregationRule {
+    pub name: String,
+    pub pattern: Regex,
+    pub x_files_factor: f32,
+    pub aggregation_method: AggregationMethod
+}
+
+pub struct StorageAggregation {
+    pub path: Option<PathBuf>,
+    rules: Vec<AggregationRule>
+}
+
+impl StorageAggregation {
+    pub fn new_default() -> StorageAggregation {
+        StorageAggregation {
+            path: None,
+            rules: vec![]
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<StorageAggregation, String> {
+        let mut contents = String::new();
+        let read_res = File::open(path).and_then(|mut file| file.read_to_string(&mut contents));
+        if let Err(err) = read_res {
+            return Err(format!("could not read `{}`: {}", path.display(), err));
+        }
+
+        let mut aggregation = try!( StorageAggregation::parse(&contents) );
+        aggregation.path = Some(path.to_path_buf());
+        Ok(aggregation)
+    }
+
+    pub fn parse(contents: &str) -> Result<StorageAggregation, String> {
+        let sections = try!( ini::parse(contents) );
+        let mut rules = Vec::with_capacity(sections.len());
+
+        for section in sections {
+            let pattern = match section.get("pattern") {
+                Some(pattern) => match Regex::new(pattern) {
+                    Ok(regex) => regex,
+                    Err(err) => return Err(format!("[{}]: invalid pattern `{}`: {}", section.name, pattern, err))
+                },
+                None => return Err(format!("[{}]: missing `pattern`", section.name))
+            };
+
+            let x_files_factor = match section.get("xfilesfactor") {
+                Some(xff) => try!( parse_x_files_factor(xff).map_err(|err| format!("[{}]: {}", section.name, err)) ),
+                None => DEFAULT_X_FILES_FACTOR
+            };
+
+            let aggregation_method = match section.get("aggregationmethod") {
+                Some(method) => match AggregationMethod::from_str(method) {
+                    Some(method) => method,
+                    None => return Err(format!("[{}]: unknown aggregationMethod `{}`", section.name, method))
+                },
+                None => DEFAULT_AGGREGATION_METHOD
+            };
+
+            rules.push(AggregationRule {
+                name: section.name.clone(),
+                pattern: pattern,
+                x_files_factor: x_files_factor,
+                aggregation_method: aggregation_method
+            });
+        }
+
+        Ok(StorageAggregation {
+            path: None,
+            rules: rules
+        })
+    }
+
+    pub fn rule_for(&self, metric_name: &str) -> Option<&AggregationRule> {
+        self.rules.iter().find(|rule| rule.pattern.is_match(metric_name))
+    }
+
+    pub fn aggregation_for(&self, metric_name: &str) -> (AggregationMethod, f32) {
+        match self.rule_for(metric_name) {
+            Some(rule) => {
+                debug!("`{}` matched storage aggregation [{}]", metric_name, rule.name);
+                (rule.aggregation_method, rule.x_files_factor)
+            },
+            None => (DEFAULT_AGGREGATION_METHOD, DEFAULT_X_FILES_FACTOR)
+        }
+    }
+}
+
+pub fn parse_x_files_factor(xff: &str) -> Result<f32, String> {
+    match xff.trim().parse::<f32>() {
+        Ok(val) if val >= 0.0 && val <= 1.0 => Ok(val),
+        _ => Err(format!("xFilesFactor `{}` must be a number between 0 and 1", xff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ StorageAggregation, DEFAULT_X_FILES_FACTOR };
+    use whisper_io::AggregationMethod;
+
+    const CONF : &'static str = "
+[min]
+pattern = \\.min$
+xFilesFactor = 0.1
+aggregationMethod = min
+
+[max]
+pattern = \\.max$
+xFilesFactor = 0.1
+aggregationMethod = max
+
+[count]
+pattern = \\.count$
+xFilesFactor = 0
+aggregationMethod = sum
+
+[default_average]
+pattern = .*
+xFilesFactor = 0.5
+aggregationMethod = average
+";
+
+    #[test]
+    fn first_matching_rule_wins(){
+        let aggregation = StorageAggregation::parse(CONF).unwrap();
+        assert_eq!(aggregation.aggregation_for("stats.requests.count"), (AggregationMethod::Sum, 0.0));
+        assert_eq!(aggregation.aggregation_for("stats.latency.max"), (AggregationMethod::Max, 0.1));
+        assert_eq!(aggregation.aggregation_for("stats.latency.min"), (AggregationMethod::Min, 0.1));
+        assert_eq!(aggregation.aggregation_for("stats.latency.mean"), (AggregationMethod::Average, 0.5));
+    }
+
+    #[test]
+    fn defaults_without_rules(){
+        let aggregation = StorageAggregation::new_default();
+        assert_eq!(aggregation.aggregation_for("anything"), (AggregationMethod::Average, DEFAULT_X_FILES_FACTOR));
+    }
+
+    #[test]
+    fn rejects_bad_rules(){
+        assert!(StorageAggregation::parse("[a]\npattern = .*\naggregationMethod = median\n").is_err());
+        assert!(StorageAggregation::parse("[a]\npattern = .*\nxFilesFactor = 2\n").is_err());
+        assert!(StorageAggregation::parse("[a]\nxFilesFactor = 0\n").is_err());
+    }
+}
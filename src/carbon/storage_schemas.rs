@@ -0,0 +1,206 @@
+/*
+
+Reads graphite's `storage-schemas.conf`:
+
+    [carbon]
+    pattern = ^carbon\.
+    retentions = 60:90d
+
+    [default]
+    pattern = .*
+    retentions = 5s:1y
+
+Rules are tried top to bottom and the first matching `pattern` decides
+the archives of a newly created whisper file.
+
+*/
+
+use whisper::Schema;
+use regex::Regex;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+
+use super::ini;
+
+pub const DEFAULT_RETENTIONS : &'static str = "5s:1y";
+
+pub struct SchemaRule {
+    pub name: String,
+    pub pattern: Regex,
+    pub retentions: Vec<String>,
+    schema: Schema
+}
+
+pub struct StorageSchemas {
+    pub path: Option<PathBuf>,
+    rules: Vec<SchemaRule>,
+    default_schema: Schema
+}
+
+impl StorageSchemas {
+    // What carbon did before it knew about storage-schemas.conf
+    pub fn new_default() -> StorageSchemas {
+        StorageSchemas {
+            path: None,
+            rules: vec![],
+            default_schema: Schema::new_from_retention_specs(vec![DEFAULT_RETENTIONS.to_string()])
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<StorageSchemas, String> {
+        let mut contents = String::new();
+        let read_res = File::open(path).and_then(|mut file| file.read_to_string(&mut contents));
+        if let Err(err) = read_res {
+            return Err(format!("could not read `{}`: {}", path.display(), err));
+        }
+
+        let mut schemas = try!( StorageSchemas::parse(&contents) );
+        schemas.path = Some(path.to_path_buf());
+        Ok(schemas)
+    }
+
+    pub fn parse(contents: &str) -> Result<StorageSchemas, String> {
+        let sections = try!( ini::parse(contents) );
+        let mut rules = Vec::with_capacity(sections.len());
+
+        for section in sections {
+            let pattern = match section.get("pattern") {
+                Some(pattern) => match Regex::new(pattern) {
+                    Ok(regex) => regex,
+                    Err(err) => return Err(format!("[{}]: invalid pattern `{}`: {}", section.name, pattern, err))
+                },
+                None => return Err(format!("[{}]: missing `pattern`", section.name))
+            };
+
+            let retentions = match section.get("retentions") {
+                Some(retentions) => try!( parse_retentions(retentions).map_err(|err| format!("[{}]: {}", section.name, err)) ),
+                None => return Err(format!("[{}]: missing `retentions`", section.name))
+            };
+
+            rules.push(SchemaRule {
+                name: section.name.clone(),
+                pattern: pattern,
+                schema: Schema::new_from_retention_specs(retentions.clone()),
+                retentions: retentions
+            });
+        }
+
+        let mut schemas = StorageSchemas::new_default();
+        schemas.rules = rules;
+        Ok(schemas)
+    }
+
+    pub fn rule_for(&self, metric_name: &str) -> Option<&SchemaRule> {
+        self.rules.iter().find(|rule| rule.pattern.is_match(metric_name))
+    }
+
+    // Falls back to the old hardcoded retention when no rule matches
+    pub fn schema_for(&self, metric_name: &str) -> &Schema {
+        match self.rule_for(metric_name) {
+            Some(rule) => {
+                debug!("`{}` matched storage schema [{}]", metric_name, rule.name);
+                &rule.schema
+            },
+            None => &self.default_schema
+        }
+    }
+}
+
+// Turns `10s:6h, 1min:7d,10m:5y` into specs the whisper crate understands.
+// whisper.py accepts any prefix of a unit name ("min", "minutes") while
+// `RetentionPolicy` only wants the single letter, so normalize here.
+fn parse_retentions(retentions: &str) -> Result<Vec<String>, String> {
+    let mut specs = vec![];
+
+    for raw_spec in retentions.split(',') {
+        let spec = raw_spec.trim();
+        let parts : Vec<&str> = spec.split(':').collect();
+        if parts.len() != 2 {
+            return Err(format!("invalid retention `{}`", spec));
+        }
+
+        let precision = try!( normalize_retention_part(parts[0]).map_err(|err| format!("invalid retention `{}`: {}", spec, err)) );
+        let points = try!( normalize_retention_part(parts[1]).map_err(|err| format!("invalid retention `{}`: {}", spec, err)) );
+        specs.push(format!("{}:{}", precision, points));
+    }
+
+    Ok(specs)
+}
+
+fn normalize_retention_part(part: &str) -> Result<String, String> {
+    let digits_end = part.find(|c: char| !c.is_digit(10)).unwrap_or(part.len());
+    let (digits, unit) = part.split_at(digits_end);
+
+    if digits.len() == 0 || digits.parse::<u32>().is_err() {
+        return Err(format!("`{}` does not start with a number", part));
+    }
+    if unit.len() == 0 {
+        return Ok(digits.to_string());
+    }
+
+    let units = ["seconds", "minutes", "hours", "days", "weeks", "years"];
+    match units.iter().find(|full_unit| full_unit.starts_with(unit)) {
+        Some(full_unit) => Ok(format!("{}{}", digits, &full_unit[0..1])),
+        None => Err(format!("unknown unit `{}`", unit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ StorageSchemas, parse_retentions };
+
+    const CONF : &'static str = "
+[carbon]
+pattern = ^carbon\\.
+retentions = 60:90d
+
+[collectd]
+pattern = ^collectd\\.
+retentions = 10s:6h,1min:7d,10min:5y
+
+[default_1min_for_1day]
+pattern = .*
+retentions = 60s:1d
+";
+
+    #[test]
+    fn first_matching_rule_wins(){
+        let schemas = StorageSchemas::parse(CONF).unwrap();
+        assert_eq!(schemas.rule_for("carbon.agents.host.cpuUsage").unwrap().name, "carbon");
+        assert_eq!(schemas.rule_for("collectd.host.load").unwrap().name, "collectd");
+        assert_eq!(schemas.rule_for("stats.timers.foo").unwrap().name, "default_1min_for_1day");
+    }
+
+    #[test]
+    fn schema_uses_rule_retentions(){
+        let schemas = StorageSchemas::parse(CONF).unwrap();
+        let schema = schemas.schema_for("collectd.host.load");
+        assert_eq!(schema.retention_policies.len(), 3);
+        assert_eq!(schema.retention_policies[0].precision, 10);
+        assert_eq!(schema.retention_policies[1].precision, 60);
+        assert_eq!(schema.retention_policies[2].retention, 5*60*60*24*365);
+    }
+
+    #[test]
+    fn falls_back_to_default(){
+        let schemas = StorageSchemas::parse("[carbon]\npattern = ^carbon\\.\nretentions = 60:90d\n").unwrap();
+        let schema = schemas.schema_for("not.carbon");
+        assert_eq!(schema.retention_policies.len(), 1);
+        assert_eq!(schema.retention_policies[0].precision, 5);
+    }
+
+    #[test]
+    fn normalizes_unit_names(){
+        assert_eq!(parse_retentions("10s:6h, 1min:7days").unwrap(), vec!["10s:6h", "1m:7d"]);
+    }
+
+    #[test]
+    fn rejects_bad_rules(){
+        assert!(StorageSchemas::parse("[a]\nretentions = 60:90d\n").is_err());
+        assert!(StorageSchemas::parse("[a]\npattern = (\nretentions = 60:90d\n").is_err());
+        assert!(StorageSchemas::parse("[a]\npattern = .*\nretentions = 60:90q\n").is_err());
+        assert!(StorageSchemas::parse("[a]\npattern = .*\nretentions = 60\n").is_err());
+    }
+}
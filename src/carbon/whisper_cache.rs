@@ -0,0 +1,194 @@
+// Keeps recently used whisper files open so the writer doesn't pay for an
+// `open` and a header read on every point. Files that don't exist yet are
+// created with whatever storage-schemas.conf and storage-aggregation.conf
+// say for that metric.
+
+use whisper::WhisperFile;
+use lru_cache::LruCache;
+use time;
+
+use std::fs::{ File, OpenOptions, create_dir_all };
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, RwLock };
+
+use super::storage_schemas::StorageSchemas;
+use super::storage_aggregation::StorageAggregation;
+use super::instrumentation::CREATES;
+use whisper_io::{ self, ArchiveInfo, Metadata };
+use graphite::MetricIndex;
+
+pub struct OpenWhisperFile {
+    pub file: File,
+    pub metadata: Metadata
+}
+
+pub type WhisperMutex = Arc<Mutex<OpenWhisperFile>>;
+
+pub struct WhisperCache {
+    pub base_path: PathBuf,
+    open_files: LruCache< PathBuf, WhisperMutex >,
+    schemas: StorageSchemas,
+    aggregation: StorageAggregation,
+    // Told about every file we create, for the HTTP side's finds
+    index: Option<Arc<RwLock<MetricIndex>>>
+}
+
+impl WhisperCache {
+    pub fn new(base_path: &Path, size: usize, schemas: StorageSchemas, aggregation: StorageAggregation) -> WhisperCache {
+        WhisperCache {
+            base_path: base_path.to_path_buf(),
+            open_files: LruCache::new(size),
+            schemas: schemas,
+            aggregation: aggregation,
+            index: None
+        }
+    }
+
+    pub fn with_index(mut self, index: Arc<RwLock<MetricIndex>>) -> WhisperCache {
+        self.index = Some(index);
+        self
+    }
+
+    // All of a file's pending points in one pass, in the order they arrived
+    // so the last one for a slot wins. Returns how many were written; the
+    // rest were outside the file's retention.
+    pub fn write(&mut self, metric_rel_path: &Path, points: &[(u32, f64)]) -> Result<usize, io::Error> {
+        let now = time::get_time().sec as u32;
+
+        let cache_entry = try!( self.get(metric_rel_path.to_path_buf()) );
+        let mut open_file = cache_entry.lock().unwrap();
+        let open_file = &mut *open_file;
+
+        whisper_io::update_many(&mut open_file.file, &open_file.metadata, now, points)
+    }
+
+    // Like carbon, only files created from here on see the new rules. If
+    // either file doesn't parse we keep both of the old ones.
+    pub fn reload(&mut self) -> Result<(), String> {
+        let schemas = match self.schemas.path {
+            Some(ref path) => try!( StorageSchemas::load(path) ),
+            None => StorageSchemas::new_default()
+        };
+        let aggregation = match self.aggregation.path {
+            Some(ref path) => try!( StorageAggregation::load(path) ),
+            None => StorageAggregation::new_default()
+        };
+        self.schemas = schemas;
+        self.aggregation = aggregation;
+        Ok(())
+    }
+
+    pub fn open_file_count(&self) -> usize {
+        self.open_files.len()
+    }
+
+    // Puts everything we've written on disk
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        for (_, open_file) in self.open_files.iter_mut() {
+            try!( open_file.lock().unwrap().file.sync_all() );
+        }
+        Ok(())
+    }
+
+    // A metric's first point is waiting to be written. Finds should see it
+    // now rather than once its file is created.
+    pub fn index_pending(&self, metric_rel_path: &Path) {
+        self.index_metric(&metric_name(metric_rel_path));
+    }
+
+    // Readers (the HTTP side) only want files the writer already has open,
+    // anything else they can read straight off disk.
+    pub fn open_file(&mut self, metric_rel_path: &Path) -> Option<WhisperMutex> {
+        self.open_files.get_mut(metric_rel_path).map(|entry| entry.clone())
+    }
+
+    // The archives `metric_rel_path` will get once it's created, for reading
+    // points that are still waiting for their file
+    pub fn new_file_archives(&self, metric_rel_path: &Path) -> Vec<ArchiveInfo> {
+        let schema = self.schemas.schema_for(&metric_name(metric_rel_path));
+        schema.retention_policies.iter().map(|policy| {
+            ArchiveInfo { offset: 0, seconds_per_point: policy.precision, points: policy.points() }
+        }).collect()
+    }
+
+    fn get(&mut self, metric_rel_path: PathBuf) -> Result< &WhisperMutex, io::Error> {
+        if self.open_files.contains_key(&metric_rel_path) {
+            debug!("file cache hit. resolved {:?}", metric_rel_path);
+            return Ok( self.open_files.get_mut(&metric_rel_path).unwrap() );
+        }
+
+        let path_on_disk = self.base_path.join(&metric_rel_path);
+
+        if path_on_disk.is_file() {
+            debug!("`{:?}` exists on disk. opening.", path_on_disk);
+        } else {
+            try!( self.create(&metric_rel_path, &path_on_disk) );
+        }
+
+        let mut file = try!( OpenOptions::new().read(true).write(true).open(&path_on_disk) );
+        let metadata = try!( whisper_io::read_metadata(&mut file) );
+
+        let open_file = OpenWhisperFile {
+            file: file,
+            metadata: metadata
+        };
+        self.open_files.insert(metric_rel_path.clone(), Arc::new( Mutex::new(open_file) ) );
+        Ok( self.open_files.get_mut(&metric_rel_path).unwrap() )
+    }
+
+    fn create(&self, metric_rel_path: &Path, path_on_disk: &Path) -> Result<(), io::Error> {
+        // TODO: assumption here is that we do not store in root FS
+        let parent = path_on_disk.parent().unwrap();
+        if !parent.is_dir() {
+            debug!("parent dir for `{:?}` must be created first", parent);
+            try!( create_dir_all(parent) );
+        }
+
+        let metric_name = metric_name(metric_rel_path);
+        let schema = self.schemas.schema_for(&metric_name);
+        let (aggregation_method, x_files_factor) = self.aggregation.aggregation_for(&metric_name);
+        debug!("`{:?}` must now be created with {:?} ({}, xff {})", path_on_disk, schema, aggregation_method, x_files_factor);
+
+        // The whisper crate lays out the archives, we fill in how they roll up
+        drop( try!( WhisperFile::new(path_on_disk, schema) ) );
+        let mut file = try!( OpenOptions::new().write(true).open(path_on_disk) );
+        try!( whisper_io::write_aggregation(&mut file, aggregation_method, x_files_factor) );
+        CREATES.increment();
+
+        self.index_metric(&metric_name);
+        Ok(())
+    }
+
+    // Most names are in there already, those only need the read lock
+    fn index_metric(&self, metric_name: &str) {
+        if let Some(ref index) = self.index {
+            if !index.read().unwrap().contains(metric_name) {
+                index.write().unwrap().insert(metric_name);
+            }
+        }
+    }
+}
+
+// Files only know their relative path, so walk it back to the
+// dotted metric name that the config file patterns are written against.
+pub fn metric_name(metric_rel_path: &Path) -> String {
+    let rel_path = metric_rel_path.to_string_lossy();
+    let without_ext = if rel_path.ends_with(".wsp") {
+        &rel_path[0..rel_path.len()-4]
+    } else {
+        &rel_path[..]
+    };
+    without_ext.replace("/", ".")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::metric_name;
+
+    #[test]
+    fn rel_path_to_metric_name(){
+        assert_eq!(metric_name(Path::new("collectd/host/load.wsp")), "collectd.host.load");
+    }
+}
@@ -1,12 +1,75 @@
-use iron;
-use super::super::whisper::Cache;
-
-// This is some weird voodoo so I can use persistent
-// apparently I have to give a vtable which is used to resolve the
-// type of the stored value so it can be recast "safely"
-// but it adds one more level of indirection. Caveat emptor folks.
-pub struct CacheHolder;
-impl iron::typemap::Key for CacheHolder {
-    // TODO: my brain hurts. what does this mean?
-    type Value = Cache;
-}
\ No newline at end of file
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, RwLock };
+
+use carbon::cache_writer::{ Shard, shard_for };
+use carbon::whisper_cache::WhisperMutex;
+use whisper_io::ArchiveInfo;
+use super::finder::{ self, FsTree, Match };
+use super::metric_index::MetricIndex;
+
+// Everything a handler needs to find whisper files. One instance is
+// shared (read-only) by all of the server's worker threads.
+pub struct CacheHolder {
+    pub base_path: PathBuf,
+    // Set when running inside carbon, so reads go through the writers' files
+    // and see what they have yet to put on disk
+    shards: Vec<Shard>,
+    // Finds walk this instead of the filesystem when it's there. Inside
+    // carbon the writers add metrics to it as their first points are
+    // queued, so it has those that don't have a file yet too.
+    index: Option<Arc<RwLock<MetricIndex>>>
+}
+
+impl CacheHolder {
+    pub fn new(base_path: &Path) -> CacheHolder {
+        CacheHolder {
+            base_path: base_path.to_path_buf(),
+            shards: vec![],
+            index: None
+        }
+    }
+
+    pub fn with_shards(shards: Vec<Shard>) -> CacheHolder {
+        let base_path = shards[0].cache.lock().unwrap().base_path.clone();
+        CacheHolder {
+            base_path: base_path,
+            shards: shards,
+            index: None
+        }
+    }
+
+    pub fn with_index(mut self, index: Arc<RwLock<MetricIndex>>) -> CacheHolder {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn find(&self, pattern: &str) -> Vec<Match> {
+        match self.index {
+            Some(ref index) => finder::find(&*index.read().unwrap(), pattern),
+            None => finder::find(&FsTree { root: &self.base_path }, pattern)
+        }
+    }
+
+    // The writer's handle for `metric_rel_path`, if it has one open. The
+    // cache lock is let go before returning so readers only ever hold up
+    // the writer for one file at a time.
+    pub fn open_file(&self, metric_rel_path: &Path) -> Option<WhisperMutex> {
+        self.shard(metric_rel_path).and_then(|shard| shard.cache.lock().unwrap().open_file(metric_rel_path))
+    }
+
+    pub fn pending_points(&self, metric_rel_path: &Path) -> Vec<(u32, f64)> {
+        self.shard(metric_rel_path).map(|shard| shard.pending.lock().unwrap().get(metric_rel_path)).unwrap_or(vec![])
+    }
+
+    // See `WhisperCache::new_file_archives`
+    pub fn new_file_archives(&self, metric_rel_path: &Path) -> Option<Vec<ArchiveInfo>> {
+        self.shard(metric_rel_path).map(|shard| shard.cache.lock().unwrap().new_file_archives(metric_rel_path))
+    }
+
+    fn shard(&self, metric_rel_path: &Path) -> Option<&Shard> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        Some(&self.shards[shard_for(metric_rel_path, self.shards.len())])
+    }
+}
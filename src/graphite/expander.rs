@@ -1,20 +1,17 @@
-use glob::glob;
-use super::super::whisper::cache::Cache;
+use super::cache_holder::CacheHolder;
+use super::finder::Match;
 use std::path::{ Path, PathBuf };
-use std::fs::{ PathExt, read_dir };
-// use std::collections::BTreeMap;
-// use rustc_serialize::json::{self, ToJson, Json};
+use std::collections::BTreeMap;
+use rustc_serialize::json::{ ToJson, Json };
 
 #[derive(Debug)]
 pub enum QueryResultNode {
+    // cache root, path to the .wsp
     WspNode(PathBuf, PathBuf),
-    // path to dir, has children, 
-    DirNode(PathBuf, PathBuf, bool, )
+    // cache root, path to the dir
+    DirNode(PathBuf, PathBuf)
 }
 
-const EXPANDABLE : i8 = 1;
-const NOT_EXPANDABLE : i8 = 2;
-
 fn text_and_file_name<'a>(cache_root: &'a Path, path: &'a Path) -> (&'a str,String) {
     let mut text = path.file_name().unwrap().to_str().unwrap();
     text = &text[0..text.len()-4];
@@ -48,112 +45,107 @@ fn text_and_folder_name<'a>(cache_root: &'a Path, path: &'a Path) -> (&'a str,St
 
 
 impl QueryResultNode {
-    fn to_json(&self) -> String {
+    pub fn is_leaf(&self) -> bool {
         match *self {
-            QueryResultNode::WspNode(ref cache_root, ref path) => {
-                let pair = text_and_file_name(cache_root, path);
-
-                format!(r#"{{"leaf": {leaf}, "context": {{}}, "text": "{text}", "expandable": {expandable}, "id": "{id}", "allowChildren": {allow_children}}}"#,
-                            allow_children=0, expandable=0, leaf=0,
-                            text=pair.0, id=pair.1)
-            },
-            QueryResultNode::DirNode(ref cache_root, ref path, ref has_children) => {
-                let pair = text_and_folder_name(cache_root, path);
-
-                format!(r#"{{"leaf": {leaf}, "context": {{}}, "text": "{text}", "expandable": {expandable}, "id": "{id}", "allowChildren": {allow_children}}}"#,
-                            allow_children=0, expandable= if *has_children { EXPANDABLE } else { NOT_EXPANDABLE } , leaf=0,
-                            text=pair.0, id=pair.1)
-            }
+            QueryResultNode::WspNode(..) => true,
+            QueryResultNode::DirNode(..) => false
+        }
+    }
+
+    // The last part of the metric name, what graphite calls `text`
+    pub fn name(&self) -> &str {
+        match *self {
+            QueryResultNode::WspNode(ref cache_root, ref path) => text_and_file_name(cache_root, path).0,
+            QueryResultNode::DirNode(ref cache_root, ref path) => text_and_folder_name(cache_root, path).0
         }
     }
-}
 
-// A file-system only operation which can detect
-// whisper files
-pub fn expand(query: &String, cache: &Cache) -> Vec<String> {
-    let glob_pattern = dots_to_full_path_glob(query, cache);
-
-    debug!("expanding {}", glob_pattern);
-    
-    let mut retval = vec![];
-    let search = glob(&glob_pattern).unwrap();
-
-    for search_result in search {
-        match search_result {
-            Ok(path_buf) => {
-                debug!("expansion match: {:?}", path_buf);
-                let is_dir = {
-                    let path = path_buf.as_path();
-                    path.is_dir()
-                };
-
-                let has_children = if is_dir {                    
-                    read_dir( &path_buf ).unwrap().any(|f| {
-                        let metadata = f.unwrap().metadata().unwrap();
-                        metadata.is_dir() || metadata.is_file()
-                    })
-                } else {
-                    false
-                };
-
-                if is_dir {
-                    retval.push( QueryResultNode::DirNode( cache.base_path.clone(), path_buf, has_children).to_json() )
-                } else {
-                    retval.push( QueryResultNode::WspNode( cache.base_path.clone(), path_buf).to_json() )
-                }
-            },
-            Err(e) => {
-                info!("error in search: {:?}", e)
-            }
+    // The dotted name graphite knows this node by
+    pub fn metric_name(&self) -> String {
+        match *self {
+            QueryResultNode::WspNode(ref cache_root, ref path) => text_and_file_name(cache_root, path).1,
+            QueryResultNode::DirNode(ref cache_root, ref path) => text_and_folder_name(cache_root, path).1
         }
     }
+}
 
-    debug!("retval len: {}", retval.len());
+// One entry of graphite's `treejson` find format. Leaves can't be opened
+// further, branches always can.
+impl ToJson for QueryResultNode {
+    fn to_json(&self) -> Json {
+        let branch = if self.is_leaf() { 0 } else { 1 };
+
+        let mut object = BTreeMap::new();
+        object.insert("text".to_string(), Json::String(self.name().to_string()));
+        object.insert("id".to_string(), Json::String(self.metric_name()));
+        object.insert("leaf".to_string(), Json::U64(1 - branch));
+        object.insert("expandable".to_string(), Json::U64(branch));
+        object.insert("allowChildren".to_string(), Json::U64(branch));
+        object.insert("context".to_string(), Json::Object(BTreeMap::new()));
+        Json::Object(object)
+    }
+}
 
-    return retval
+// Branches (directories) and leaves (.wsp files) matching the query, from
+// the metric index when there is one and the filesystem otherwise.
+pub fn expand(query: &String, cache: &CacheHolder) -> Vec<QueryResultNode> {
+    let mut nodes : Vec<QueryResultNode> = cache.find(query).into_iter().map(|found| {
+        to_node(cache, found)
+    }).collect();
+    nodes.sort_by(|a, b| a.name().cmp(b.name()));
+    nodes
 }
 
-// TODO: is it really this much work?
-// TODO: what about security concerns for traversing the file system? Can you craft a query such that ".." shows up? (Don't think so)
-fn dots_to_full_path_glob(query: &String, cache: &Cache) -> String {
-    let replaced = query.replace(".","/");
+// Only the whisper files a render target refers to
+pub fn expand_leaves(query: &String, cache: &CacheHolder) -> Vec<QueryResultNode> {
+    cache.find(query).into_iter().filter(|found| found.is_leaf).map(|found| {
+        to_node(cache, found)
+    }).collect()
+}
 
-    let qualified_path = cache.base_path.join(replaced);
-    let path : &Path = qualified_path.as_path();
-    let str_rep = path.to_str().unwrap();
-    let string_rep = str_rep.to_string();
-    // string_rep.push_str(".wsp");
+fn to_node(cache: &CacheHolder, found: Match) -> QueryResultNode {
+    let mut path = cache.base_path.clone();
+    for node in &found.path {
+        path.push(node);
+    }
 
-    return string_rep;
+    if found.is_leaf {
+        path.set_extension("wsp");
+        QueryResultNode::WspNode( cache.base_path.clone(), path )
+    } else {
+        QueryResultNode::DirNode( cache.base_path.clone(), path )
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs::{ self, File };
     use std::path::Path;
-    use super::super::super::whisper::cache::Cache;
-    use super::QueryResultNode;
-
-    #[test]
-    fn has_full_path(){
-        let cache = Cache::new(Path::new("/tmp"));
-        let input = "what.*.ever".to_string();
-        let expected = "/tmp/what/*/ever";
-
-        let full_glob = super::dots_to_full_path_glob(&input, &cache);
-
-        assert_eq!(full_glob, expected)
-    }
+    use test_support::TempDir;
+    use super::super::cache_holder::CacheHolder;
+    use super::{ QueryResultNode, expand, expand_leaves };
+    use rustc_serialize::json::ToJson;
 
-    // Not trying that hard but a simple sanity check
     #[test]
-    fn wont_go_up_directory(){
-        let cache = Cache::new(Path::new("/tmp"));
-        let input = "what.*.ever/../".to_string();
-        let expected = "/tmp/what/*/ever////";
-
-        let full_glob = super::dots_to_full_path_glob(&input, &cache);
-
-        assert_eq!(full_glob, expected)
+    fn finds_on_disk(){
+        let tmp = TempDir::new("graphite-expander-finds_on_disk");
+        let root = tmp.path();
+        fs::create_dir_all(root.join("servers/web1")).unwrap();
+        fs::create_dir_all(root.join("servers/db1")).unwrap();
+        File::create(root.join("servers/web1/cpu.wsp")).unwrap();
+        File::create(root.join("servers/web1/notes.txt")).unwrap();
+        File::create(root.join("servers/db1.wsp")).unwrap();
+
+        let cache = CacheHolder::new(root);
+        let found : Vec<(String, bool)> = expand(&"servers.*".to_string(), &cache).iter().map(|node| (node.metric_name(), node.is_leaf())).collect();
+        assert_eq!(found, vec![("servers.db1".to_string(), false), ("servers.db1".to_string(), true), ("servers.web1".to_string(), false)]);
+
+        let leaves : Vec<String> = expand_leaves(&"servers.{web,db}1.*".to_string(), &cache).iter().map(|node| node.metric_name()).collect();
+        assert_eq!(leaves, vec!["servers.web1.cpu"]);
+
+        // No way out of the storage dir
+        assert_eq!(expand(&"servers.web1...".to_string(), &cache).len(), 0);
+        assert_eq!(expand(&"servers.web1/../..".to_string(), &cache).len(), 0);
     }
 
     #[test]
@@ -161,7 +153,16 @@ mod tests {
         let root = Path::new("/tmp/thing").to_path_buf();
         let deep = Path::new("/tmp/thing/is/cool/bear.wsp").to_path_buf();
         let wsp_node = QueryResultNode::WspNode( root, deep );
-        let expected = "{\"leaf\": 0, \"context\": {}, \"text\": \"bear\", \"expandable\": 0, \"id\": \"is.cool.bear\", \"allowChildren\": 0}";
-        assert_eq!( wsp_node.to_json(), expected.to_string() )
+        let expected = r#"{"allowChildren":0,"context":{},"expandable":0,"id":"is.cool.bear","leaf":1,"text":"bear"}"#;
+        assert_eq!( wsp_node.to_json().to_string(), expected.to_string() )
+    }
+
+    #[test]
+    fn dir_node_json_is_escaped(){
+        let root = Path::new("/tmp/thing").to_path_buf();
+        let deep = Path::new("/tmp/thing/is/\"cool\"").to_path_buf();
+        let dir_node = QueryResultNode::DirNode( root, deep );
+        let expected = r#"{"allowChildren":1,"context":{},"expandable":1,"id":"is.\"cool\"","leaf":0,"text":"\"cool\""}"#;
+        assert_eq!( dir_node.to_json().to_string(), expected.to_string() )
     }
 }
@@ -0,0 +1,399 @@
+// graphite-web's metric pattern matching, done one node at a time.
+//
+// A pattern like `servers.{web,db}*.cpu.[0-3]` first has its braces
+// expanded (over the whole pattern, so alternatives may hold dots), then
+// each variant is split on `.` and every node is matched with Python's
+// fnmatch rules: `*`, `?`, `[abc]`, `[a-z]` and `[!abc]`. The tree is
+// walked from the root and only branches that match the current node are
+// descended into. Nodes without wildcards are looked up directly instead
+// of listing their parent.
+
+use std::fs::read_dir;
+use std::path::{ Path, PathBuf };
+
+// Anything metrics can be looked up in. `path` is a list of node names
+// leading to a branch, the empty list being the root.
+pub trait MetricTree {
+    fn branches(&self, path: &[String]) -> Vec<String>;
+    fn leaves(&self, path: &[String]) -> Vec<String>;
+    fn is_branch(&self, path: &[String]) -> bool;
+    fn is_leaf(&self, path: &[String]) -> bool;
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct Match {
+    pub path: Vec<String>,
+    pub is_leaf: bool
+}
+
+// Every branch and leaf matching `pattern`, sorted by path
+pub fn find<T: MetricTree>(tree: &T, pattern: &str) -> Vec<Match> {
+    let mut matches = vec![];
+    for variant in expand_braces(pattern) {
+        let nodes : Vec<NodePattern> = variant.split('.').map(NodePattern::new).collect();
+        walk(tree, &mut vec![], &nodes, &mut matches);
+    }
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+fn walk<T: MetricTree>(tree: &T, path: &mut Vec<String>, nodes: &[NodePattern], matches: &mut Vec<Match>) {
+    let node = &nodes[0];
+    let last = nodes.len() == 1;
+
+    let branches = if node.is_literal() {
+        let mut candidate = path.clone();
+        candidate.push(node.source.clone());
+        if node.is_safe_literal() && tree.is_branch(&candidate) { vec![node.source.clone()] } else { vec![] }
+    } else {
+        tree.branches(path).into_iter().filter(|name| node.matches(name)).collect()
+    };
+
+    for branch in branches {
+        path.push(branch);
+        if last {
+            matches.push(Match { path: path.clone(), is_leaf: false });
+        } else {
+            walk(tree, path, &nodes[1..], matches);
+        }
+        path.pop();
+    }
+
+    if last {
+        let leaves = if node.is_literal() {
+            let mut candidate = path.clone();
+            candidate.push(node.source.clone());
+            if node.is_safe_literal() && tree.is_leaf(&candidate) { vec![node.source.clone()] } else { vec![] }
+        } else {
+            tree.leaves(path).into_iter().filter(|name| node.matches(name)).collect()
+        };
+
+        for leaf in leaves {
+            let mut leaf_path = path.clone();
+            leaf_path.push(leaf);
+            matches.push(Match { path: leaf_path, is_leaf: true });
+        }
+    }
+}
+
+// `{a,b}` alternatives, innermost first like graphite-web's expand_braces.
+// Braces without a comma are dropped, so `{a}` is just `a`.
+pub fn expand_braces(pattern: &str) -> Vec<String> {
+    let (open, close) = match innermost_group(pattern) {
+        Some(group) => group,
+        None => return vec![pattern.to_string()]
+    };
+
+    let inner = &pattern[open+1..close];
+    let (before, after) = (&pattern[..open], &pattern[close+1..]);
+    if inner.contains(',') {
+        let mut expanded = vec![];
+        for alternative in inner.split(',') {
+            for variant in expand_braces(&format!("{}{}{}", before, alternative, after)) {
+                if !expanded.contains(&variant) {
+                    expanded.push(variant);
+                }
+            }
+        }
+        expanded
+    } else {
+        expand_braces(&format!("{}{}{}", before, inner, after))
+    }
+}
+
+// The last `{` that has a `}` after it, and that `}`
+fn innermost_group(pattern: &str) -> Option<(usize, usize)> {
+    let mut search_end = pattern.len();
+    while let Some(open) = pattern[..search_end].rfind('{') {
+        if let Some(len) = pattern[open..].find('}') {
+            return Some((open, open + len));
+        }
+        search_end = open;
+    }
+    None
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    AnyString,
+    AnyChar,
+    Class(bool, Vec<(char, char)>)
+}
+
+// One dot-separated part of a pattern, matched the way Python's fnmatch does
+#[derive(Debug)]
+struct NodePattern {
+    source: String,
+    tokens: Vec<Token>
+}
+
+impl NodePattern {
+    fn new(source: &str) -> NodePattern {
+        let chars : Vec<char> = source.chars().collect();
+        let mut tokens = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '*' => tokens.push(Token::AnyString),
+                '?' => tokens.push(Token::AnyChar),
+                '[' => match parse_class(&chars, i) {
+                    Some((class, end)) => {
+                        tokens.push(class);
+                        i = end;
+                    },
+                    // An unclosed `[` is just a bracket
+                    None => tokens.push(Token::Char('['))
+                },
+                c => tokens.push(Token::Char(c))
+            }
+            i += 1;
+        }
+
+        NodePattern {
+            source: source.to_string(),
+            tokens: tokens
+        }
+    }
+
+    fn is_literal(&self) -> bool {
+        self.tokens.iter().all(|token| match *token {
+            Token::Char(_) => true,
+            _ => false
+        })
+    }
+
+    // Literal nodes are looked up directly, so they must not walk out of the tree
+    fn is_safe_literal(&self) -> bool {
+        self.source.len() > 0 && self.source != "." && self.source != ".." && !self.source.contains('/')
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let chars : Vec<char> = name.chars().collect();
+        match_tokens(&self.tokens, &chars)
+    }
+}
+
+// `[...]` starting at `start`, giving back the token and the index of its `]`
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+    let mut i = start + 1;
+    let negated = i < chars.len() && chars[i] == '!';
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = vec![];
+    let first = i;
+    while i < chars.len() {
+        // `]` straight after the opening bracket is part of the set
+        if chars[i] == ']' && i > first {
+            return Some((Token::Class(negated, ranges), i));
+        }
+        if i + 2 < chars.len() && chars[i+1] == '-' && chars[i+2] != ']' {
+            ranges.push((chars[i], chars[i+2]));
+            i += 3;
+        } else {
+            ranges.push((chars[i], chars[i]));
+            i += 1;
+        }
+    }
+    None
+}
+
+fn match_tokens(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.first() {
+        None => name.len() == 0,
+        Some(&Token::AnyString) => (0..name.len()+1).any(|skip| match_tokens(&tokens[1..], &name[skip..])),
+        Some(token) => match name.first() {
+            None => false,
+            Some(&c) => {
+                let matched = match *token {
+                    Token::Char(expected) => c == expected,
+                    Token::AnyChar => true,
+                    Token::Class(negated, ref ranges) => ranges.iter().any(|&(low, high)| low <= c && c <= high) != negated,
+                    Token::AnyString => unreachable!()
+                };
+                matched && match_tokens(&tokens[1..], &name[1..])
+            }
+        }
+    }
+}
+
+// The whisper tree on disk: branches are directories, leaves are `.wsp` files
+pub struct FsTree<'a> {
+    pub root: &'a Path
+}
+
+impl<'a> FsTree<'a> {
+    fn dir(&self, path: &[String]) -> PathBuf {
+        let mut dir = self.root.to_path_buf();
+        for node in path {
+            dir.push(node);
+        }
+        dir
+    }
+
+    fn entries(&self, path: &[String]) -> Vec<(String, bool)> {
+        let dir = self.dir(path);
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("could not list {:?}: {:?}", dir, e);
+                return vec![]
+            }
+        };
+
+        entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
+            let is_dir = match entry.file_type() {
+                Ok(file_type) => file_type.is_dir(),
+                Err(_) => return None
+            };
+            entry.file_name().into_string().ok().map(|name| (name, is_dir))
+        }).collect()
+    }
+}
+
+impl<'a> MetricTree for FsTree<'a> {
+    fn branches(&self, path: &[String]) -> Vec<String> {
+        self.entries(path).into_iter().filter(|&(_, is_dir)| is_dir).map(|(name, _)| name).collect()
+    }
+
+    fn leaves(&self, path: &[String]) -> Vec<String> {
+        self.entries(path).into_iter().filter(|&(ref name, is_dir)| !is_dir && name.ends_with(".wsp")).map(|(name, _)| {
+            name[..name.len()-4].to_string()
+        }).collect()
+    }
+
+    fn is_branch(&self, path: &[String]) -> bool {
+        self.dir(path).is_dir()
+    }
+
+    fn is_leaf(&self, path: &[String]) -> bool {
+        let mut file = self.dir(path).into_os_string();
+        file.push(".wsp");
+        Path::new(&file).is_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ find, expand_braces, NodePattern, MetricTree, Match };
+
+    // A tree spelled out as dotted leaf names
+    struct ListTree(Vec<&'static str>);
+
+    impl ListTree {
+        fn children(&self, path: &[String], want_leaves: bool) -> Vec<String> {
+            let mut children = vec![];
+            for metric in &self.0 {
+                let parts : Vec<&str> = metric.split('.').collect();
+                if parts.len() <= path.len() || parts.iter().zip(path).any(|(part, node)| part != node) {
+                    continue;
+                }
+                let is_leaf = parts.len() == path.len() + 1;
+                let child = parts[path.len()].to_string();
+                if is_leaf == want_leaves && !children.contains(&child) {
+                    children.push(child);
+                }
+            }
+            children
+        }
+    }
+
+    impl MetricTree for ListTree {
+        fn branches(&self, path: &[String]) -> Vec<String> { self.children(path, false) }
+        fn leaves(&self, path: &[String]) -> Vec<String> { self.children(path, true) }
+        fn is_branch(&self, path: &[String]) -> bool {
+            let (last, parent) = path.split_last().unwrap();
+            self.branches(parent).contains(last)
+        }
+        fn is_leaf(&self, path: &[String]) -> bool {
+            let (last, parent) = path.split_last().unwrap();
+            self.leaves(parent).contains(last)
+        }
+    }
+
+    fn tree() -> ListTree {
+        ListTree(vec![
+            "servers.web1.cpu.0", "servers.web1.cpu.1", "servers.web1.cpu.7",
+            "servers.web2.cpu.3", "servers.web2.load",
+            "servers.db1.cpu.2", "servers.db1.cpu.4",
+            "servers.cache1.cpu.0",
+            "servers.odd.cpu.0",
+            "stats.a.b.count", "stats.c.count", "stats.timers.x.upper"
+        ])
+    }
+
+    fn names(pattern: &str) -> Vec<String> {
+        find(&tree(), pattern).into_iter().map(|m| {
+            let mut name = m.path.join(".");
+            if !m.is_leaf {
+                name.push('/');
+            }
+            name
+        }).collect()
+    }
+
+    // What graphite-web's find returns for each pattern against the same tree
+    #[test]
+    fn graphite_web_cases(){
+        let cases : Vec<(&str, Vec<&str>)> = vec![
+            ("servers.{web,db}*.cpu.[0-3]", vec!["servers.db1.cpu.2", "servers.web1.cpu.0", "servers.web1.cpu.1", "servers.web2.cpu.3"]),
+            ("servers.*", vec!["servers.cache1/", "servers.db1/", "servers.odd/", "servers.web1/", "servers.web2/"]),
+            ("servers.web?.load", vec!["servers.web2.load"]),
+            ("servers.web[!1].*", vec!["servers.web2.cpu/", "servers.web2.load"]),
+            ("servers.*.cpu.[]0]", vec!["servers.cache1.cpu.0", "servers.odd.cpu.0", "servers.web1.cpu.0"]),
+            ("servers.web1.cpu", vec!["servers.web1.cpu/"]),
+            ("servers.web1.cpu.1", vec!["servers.web1.cpu.1"]),
+            ("servers.{odd}.cpu.*", vec!["servers.odd.cpu.0"]),
+            ("stats.{a.b,c}.count", vec!["stats.a.b.count", "stats.c.count"]),
+            ("stats.{timers.*,c}.*", vec!["stats.c.count", "stats.timers.x.upper"]),
+            ("servers.{web{1,2},db1}.cpu.{0,2}", vec!["servers.db1.cpu.2", "servers.web1.cpu.0"]),
+            ("servers.*.cpu.[a-", vec![]),
+            ("servers..cpu", vec![]),
+            ("nothing.*", vec![])
+        ];
+
+        for (pattern, expected) in cases {
+            assert_eq!(names(pattern), expected, "pattern `{}`", pattern);
+        }
+    }
+
+    #[test]
+    fn duplicates_are_dropped(){
+        assert_eq!(find(&tree(), "servers.{web1,web*}.load"), vec![
+            Match { path: vec!["servers".to_string(), "web2".to_string(), "load".to_string()], is_leaf: true }
+        ]);
+    }
+
+    #[test]
+    fn brace_expansion(){
+        assert_eq!(expand_braces("a.{b,c}.d"), vec!["a.b.d", "a.c.d"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), vec!["a1", "b1", "a2", "b2"]);
+        assert_eq!(expand_braces("a.{b}.{c,d"), vec!["a.b.{c,d"]);
+        assert_eq!(expand_braces("{a{b}c,d}"), vec!["abc", "d"]);
+        assert_eq!(expand_braces("a.{b,c}.{d"), vec!["a.b.{d", "a.c.{d"]);
+        assert_eq!(expand_braces("{x,{y,z}}"), vec!["x", "y", "z"]);
+    }
+
+    #[test]
+    fn fnmatch_rules(){
+        let cases = vec![
+            ("*", "", true), ("a*c", "abbc", true), ("a*c", "abcd", false),
+            ("?", "", false), ("a?c", "abc", true),
+            ("[a-c]x", "bx", true), ("[a-c]x", "dx", false), ("[!a-c]x", "dx", true),
+            ("[-a]", "-", true), ("[a-]", "-", true), ("[[]", "[", true), ("[", "[", true)
+        ];
+        for (pattern, name, expected) in cases {
+            assert_eq!(NodePattern::new(pattern).matches(name), expected, "`{}` against `{}`", pattern, name);
+        }
+    }
+
+    #[test]
+    fn literals_stay_in_the_tree(){
+        assert!(!NodePattern::new("..").is_safe_literal());
+        assert!(!NodePattern::new("a/b").is_safe_literal());
+        assert!(NodePattern::new("web1").is_safe_literal());
+    }
+}
@@ -0,0 +1,397 @@
+// The functions themselves, written to give the same answers (and series
+// names) as graphite-web's functions.py.
+
+use super::Args;
+use super::super::series::{ Series, Consolidation };
+use super::super::time_spec;
+
+// `call_text` is the whole call as written, which is what the combining
+// functions name their result.
+pub fn call(call_text: &str, mut args: Args) -> Result<Vec<Series>, String> {
+    let function = args.function;
+    match function {
+        "sumSeries" | "sum" => combine(call_text, try!( args.all_series(0) ), |values| {
+            values.iter().fold(0.0, |sum, v| sum + v)
+        }),
+        "averageSeries" | "avg" => combine(call_text, try!( args.all_series(0) ), |values| {
+            values.iter().fold(0.0, |sum, v| sum + v) / values.len() as f64
+        }),
+        "maxSeries" => combine(call_text, try!( args.all_series(0) ), |values| {
+            values.iter().fold(::std::f64::NEG_INFINITY, |max, v| max.max(*v))
+        }),
+        "minSeries" => combine(call_text, try!( args.all_series(0) ), |values| {
+            values.iter().fold(::std::f64::INFINITY, |min, v| min.min(*v))
+        }),
+        "scale" => {
+            let factor = try!( args.number(1) );
+            map_values(try!( args.series(0) ), |name| format!("scale({},{})", name, factor), |v| v.map(|v| v * factor))
+        },
+        "offset" => {
+            let factor = try!( args.number(1) );
+            map_values(try!( args.series(0) ), |name| format!("offset({},{})", name, factor), |v| v.map(|v| v + factor))
+        },
+        "derivative" => Ok(try!( args.series(0) ).into_iter().map(|s| {
+            let values = deltas(&s.values, |delta, _, _| Some(delta));
+            rename(&s, "derivative", values)
+        }).collect()),
+        "nonNegativeDerivative" => {
+            let max_value = try!( args.optional_number(1) );
+            Ok(try!( args.series(0) ).into_iter().map(|s| {
+                let values = deltas(&s.values, |delta, prev, value| non_negative(delta, prev, value, max_value));
+                rename(&s, "nonNegativeDerivative", values)
+            }).collect())
+        },
+        "perSecond" => {
+            let max_value = try!( args.optional_number(1) );
+            Ok(try!( args.series(0) ).into_iter().map(|s| {
+                let step = s.step as f64;
+                let values = deltas(&s.values, |delta, prev, value| {
+                    non_negative(delta, prev, value, max_value).map(|delta| delta / step)
+                });
+                rename(&s, "perSecond", values)
+            }).collect())
+        },
+        "integral" => Ok(try!( args.series(0) ).into_iter().map(|s| {
+            let mut total = 0.0;
+            let values = s.values.iter().map(|value| value.map(|v| { total += v; total })).collect();
+            rename(&s, "integral", values)
+        }).collect()),
+        "movingAverage" => {
+            // Either a number of points or a length of time like '5min'
+            let window_for : Box<Fn(u32) -> usize> = match args.string(1) {
+                Ok(interval) => {
+                    let seconds = try!( time_spec::parse_interval(&interval) );
+                    Box::new(move |step| ::std::cmp::max(1, (seconds / step) as usize))
+                },
+                Err(_) => {
+                    let points = try!( args.number(1) );
+                    if points < 1.0 {
+                        return Err(format!("movingAverage: window must be at least 1 point, not {}", points));
+                    }
+                    Box::new(move |_| points as usize)
+                }
+            };
+            let window_text = args.text(1);
+            Ok(try!( args.series(0) ).into_iter().map(|s| {
+                let window = window_for(s.step);
+                let values = moving_average(&s.values, window);
+                let name = format!("movingAverage({},{})", s.name, window_text);
+                s.with_values(name, values)
+            }).collect())
+        },
+        "keepLastValue" => {
+            let limit = try!( args.optional_number(1) ).map(|limit| limit as usize).unwrap_or(::std::usize::MAX);
+            Ok(try!( args.series(0) ).into_iter().map(|s| {
+                let values = keep_last_value(&s.values, limit);
+                rename(&s, "keepLastValue", values)
+            }).collect())
+        },
+        "asPercent" => as_percent(args),
+        "alias" => {
+            let new_name = try!( args.string(1) );
+            Ok(try!( args.series(0) ).into_iter().map(|mut s| {
+                s.name = new_name.clone();
+                s
+            }).collect())
+        },
+        "consolidateBy" => {
+            let method = try!( args.string(1) );
+            let consolidation = try!( Consolidation::from_str(&method).ok_or_else(|| {
+                format!("consolidateBy: unknown function `{}`, use average, sum, min, max or last", method)
+            }) );
+            Ok(try!( args.series(0) ).into_iter().map(|mut s| {
+                s.name = format!("consolidateBy({},\"{}\")", s.name, method);
+                s.consolidation = consolidation;
+                s
+            }).collect())
+        },
+        "aliasByNode" => {
+            let mut nodes = vec![];
+            for i in 1..args.len() {
+                nodes.push( try!( args.number(i) ) as i64 );
+            }
+            let mut aliased = vec![];
+            for mut s in try!( args.series(0) ) {
+                s.name = try!( alias_by_node(&s.name, &nodes) );
+                aliased.push(s);
+            }
+            Ok(aliased)
+        },
+        other => Err(format!("Unknown function `{}`", other))
+    }
+}
+
+fn rename(series: &Series, function: &str, values: Vec<Option<f64>>) -> Series {
+    let name = format!("{}({})", function, series.name);
+    series.with_values(name, values)
+}
+
+fn map_values<N, F>(series: Vec<Series>, name: N, f: F) -> Result<Vec<Series>, String>
+    where N: Fn(&str) -> String, F: Fn(Option<f64>) -> Option<f64> {
+    Ok(series.into_iter().map(|s| {
+        let values = s.values.iter().map(|v| f(*v)).collect();
+        s.with_values(name(&s.name), values)
+    }).collect())
+}
+
+// Brings series with different steps onto the coarsest common step, the
+// way graphite's `normalize` does before lining values up by position.
+fn normalize(series: Vec<Series>) -> (u32, u32, u32, Vec<Series>) {
+    let step = series.iter().fold(1, |step, s| lcm(step, s.step));
+    let start = series.iter().map(|s| s.start).min().unwrap();
+    let mut end = series.iter().map(|s| s.end).max().unwrap();
+    end -= (end - start) % step;
+
+    let normalized = series.into_iter().map(|s| {
+        let values_per_point = (step / s.step) as usize;
+        s.consolidate(values_per_point)
+    }).collect();
+    (start, end, step, normalized)
+}
+
+fn lcm(a: u32, b: u32) -> u32 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        let t = y;
+        y = x % y;
+        x = t;
+    }
+    a / x * b
+}
+
+// One series out of many, `f` only ever sees the values that are present
+fn combine<F>(name: &str, series: Vec<Series>, f: F) -> Result<Vec<Series>, String> where F: Fn(&[f64]) -> f64 {
+    if series.len() == 0 {
+        return Ok(vec![]);
+    }
+
+    let (start, end, step, normalized) = normalize(series);
+    let len = normalized.iter().map(|s| s.values.len()).max().unwrap();
+    let values = (0..len).map(|i| {
+        let present : Vec<f64> = normalized.iter().filter_map(|s| s.values.get(i).and_then(|v| *v)).collect();
+        if present.len() == 0 { None } else { Some(f(&present)) }
+    }).collect();
+
+    Ok(vec![ Series::new(name.to_string(), start, end, step, values) ])
+}
+
+// `f(value - prev, prev, value)` for each neighbouring pair of present values
+fn deltas<F>(values: &[Option<f64>], f: F) -> Vec<Option<f64>> where F: Fn(f64, f64, f64) -> Option<f64> {
+    let mut prev = None;
+    values.iter().map(|value| {
+        let delta = match (prev, *value) {
+            (Some(p), Some(v)) => f(v - p, p, v),
+            _ => None
+        };
+        prev = *value;
+        delta
+    }).collect()
+}
+
+// A counter going backwards has either wrapped at `max_value` or been reset
+fn non_negative(delta: f64, prev: f64, value: f64, max_value: Option<f64>) -> Option<f64> {
+    if delta >= 0.0 {
+        return Some(delta);
+    }
+    match max_value {
+        Some(max) if max >= value => Some((max - prev) + value + 1.0),
+        _ => None
+    }
+}
+
+fn moving_average(values: &[Option<f64>], window: usize) -> Vec<Option<f64>> {
+    (0..values.len()).map(|i| {
+        let from = (i + 1).saturating_sub(window);
+        let present : Vec<f64> = values[from..i+1].iter().filter_map(|v| *v).collect();
+        if present.len() == 0 {
+            None
+        } else {
+            Some(present.iter().fold(0.0, |sum, v| sum + v) / present.len() as f64)
+        }
+    }).collect()
+}
+
+// Gaps of up to `limit` missing points are filled with the last value seen
+fn keep_last_value(values: &[Option<f64>], limit: usize) -> Vec<Option<f64>> {
+    let mut filled = values.to_vec();
+    let mut last = None;
+    let mut gap_start = None;
+
+    for i in 0..values.len() {
+        match values[i] {
+            Some(v) => {
+                if let (Some(start), Some(last)) = (gap_start, last) {
+                    if i - start <= limit {
+                        for slot in &mut filled[start..i] {
+                            *slot = Some(last);
+                        }
+                    }
+                }
+                gap_start = None;
+                last = Some(v);
+            },
+            None => if gap_start.is_none() {
+                gap_start = Some(i);
+            }
+        }
+    }
+
+    if let (Some(start), Some(last)) = (gap_start, last) {
+        if values.len() - start <= limit {
+            for slot in &mut filled[start..] {
+                *slot = Some(last);
+            }
+        }
+    }
+
+    filled
+}
+
+// asPercent(seriesList) is each series' share of the sum at that point,
+// asPercent(seriesList, 100) or asPercent(seriesList, total.series) divide
+// by the given total instead.
+fn as_percent(mut args: Args) -> Result<Vec<Series>, String> {
+    let series = try!( args.series(0) );
+    if series.len() == 0 {
+        return Ok(vec![]);
+    }
+
+    let (total_name, totals) : (String, Vec<Option<f64>>) = if args.is_series(1) {
+        let mut total = try!( args.series(1) );
+        if total.len() != 1 {
+            return Err(format!("asPercent: total must be exactly one series, `{}` has {}", args.text(1), total.len()));
+        }
+        let total = total.remove(0);
+        (total.name, total.values)
+    } else {
+        match try!( args.optional_number(1) ) {
+            Some(total) => (total.to_string(), vec![Some(total); series.iter().map(|s| s.values.len()).max().unwrap()]),
+            None => {
+                let sum_text = format!("sumSeries({})", args.text(0));
+                let sum = try!( combine(&sum_text, series.clone(), |values| values.iter().fold(0.0, |sum, v| sum + v)) ).remove(0);
+                (sum.name, sum.values)
+            }
+        }
+    };
+
+    Ok(series.into_iter().map(|s| {
+        let values = s.values.iter().enumerate().map(|(i, value)| {
+            match (*value, totals.get(i).and_then(|total| *total)) {
+                (Some(v), Some(total)) if total != 0.0 => Some(v / total * 100.0),
+                _ => None
+            }
+        }).collect();
+        let name = format!("asPercent({},{})", s.name, total_name);
+        s.with_values(name, values)
+    }).collect())
+}
+
+// graphite digs the metric path out of names like `scale(a.b.c,2)` first
+fn alias_by_node(name: &str, nodes: &[i64]) -> Result<String, String> {
+    let inner = match name.rfind('(') {
+        Some(pos) => &name[pos+1..],
+        None => name
+    };
+    let path_end = inner.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '*' || c == '.')).unwrap_or(inner.len());
+    let parts : Vec<&str> = inner[..path_end].split('.').collect();
+
+    let mut picked = vec![];
+    for &node in nodes {
+        let index = if node < 0 { parts.len() as i64 + node } else { node };
+        if index < 0 || index >= parts.len() as i64 {
+            return Err(format!("aliasByNode: `{}` has no node {}", name, node));
+        }
+        picked.push(parts[index as usize]);
+    }
+    Ok(picked.join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ parse, evaluate };
+    use super::super::super::series::{ Series, Consolidation };
+
+    fn fetch(path: &str) -> Vec<Series> {
+        let series = |name: &str, values: Vec<Option<f64>>| {
+            let len = values.len() as u32;
+            Series::new(name.to_string(), 100, 100 + 10 * len, 10, values)
+        };
+        match path {
+            "web.*.requests" => vec![
+                series("web.a.requests", vec![Some(1.0), Some(2.0), None, Some(4.0)]),
+                series("web.b.requests", vec![Some(3.0), None, None, Some(4.0)])
+            ],
+            "counter" => vec![ series("counter", vec![Some(10.0), Some(30.0), None, Some(50.0), Some(5.0)]) ],
+            _ => vec![]
+        }
+    }
+
+    fn render(target: &str) -> Vec<(String, Vec<Option<f64>>)> {
+        let expr = parse(target).unwrap();
+        evaluate(&expr, &fetch).unwrap().into_iter().map(|s| (s.name, s.values)).collect()
+    }
+
+    #[test]
+    fn combining(){
+        assert_eq!(render("sumSeries(web.*.requests)"),
+                   vec![("sumSeries(web.*.requests)".to_string(), vec![Some(4.0), Some(2.0), None, Some(8.0)])]);
+        assert_eq!(render("averageSeries(web.*.requests)")[0].1, vec![Some(2.0), Some(2.0), None, Some(4.0)]);
+        assert_eq!(render("maxSeries(web.*.requests)")[0].1, vec![Some(3.0), Some(2.0), None, Some(4.0)]);
+        assert_eq!(render("minSeries(web.*.requests)")[0].1, vec![Some(1.0), Some(2.0), None, Some(4.0)]);
+        assert_eq!(render("sumSeries(nothing.here)"), vec![]);
+    }
+
+    #[test]
+    fn transforms(){
+        assert_eq!(render("scale(counter,0.5)")[0], ("scale(counter,0.5)".to_string(), vec![Some(5.0), Some(15.0), None, Some(25.0), Some(2.5)]));
+        assert_eq!(render("offset(counter,-10)")[0].1, vec![Some(0.0), Some(20.0), None, Some(40.0), Some(-5.0)]);
+        assert_eq!(render("derivative(counter)")[0].1, vec![None, Some(20.0), None, None, Some(-45.0)]);
+        assert_eq!(render("nonNegativeDerivative(counter)")[0].1, vec![None, Some(20.0), None, None, None]);
+        assert_eq!(render("nonNegativeDerivative(counter,100)")[0].1, vec![None, Some(20.0), None, None, Some(56.0)]);
+        assert_eq!(render("perSecond(counter)")[0], ("perSecond(counter)".to_string(), vec![None, Some(2.0), None, None, None]));
+        assert_eq!(render("integral(counter)")[0].1, vec![Some(10.0), Some(40.0), None, Some(90.0), Some(95.0)]);
+        assert_eq!(render("movingAverage(counter,2)")[0], ("movingAverage(counter,2)".to_string(), vec![Some(10.0), Some(20.0), Some(30.0), Some(50.0), Some(27.5)]));
+        assert_eq!(render("movingAverage(counter,'20s')")[0].1, render("movingAverage(counter,2)")[0].1);
+        assert_eq!(render("keepLastValue(counter)")[0].1, vec![Some(10.0), Some(30.0), Some(30.0), Some(50.0), Some(5.0)]);
+    }
+
+    #[test]
+    fn percentages(){
+        let percent = render("asPercent(web.*.requests)");
+        assert_eq!(percent[0].0, "asPercent(web.a.requests,sumSeries(web.*.requests))");
+        assert_eq!(percent[0].1, vec![Some(25.0), Some(100.0), None, Some(50.0)]);
+        assert_eq!(render("asPercent(counter,200)")[0].1, vec![Some(5.0), Some(15.0), None, Some(25.0), Some(2.5)]);
+        assert_eq!(render("asPercent(web.*.requests,sumSeries(web.*.requests))")[1].1, vec![Some(75.0), None, None, Some(50.0)]);
+    }
+
+    #[test]
+    fn aliasing(){
+        let names : Vec<String> = render("aliasByNode(scale(web.*.requests,2),1)").into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(render("aliasByNode(web.*.requests,0,-1)")[0].0, "web.requests");
+        assert_eq!(render("alias(sumSeries(web.*.requests),'total')")[0].0, "total");
+    }
+
+    #[test]
+    fn consolidate_by(){
+        let expr = parse("consolidateBy(scale(counter,2),'max')").unwrap();
+        let series = evaluate(&expr, &fetch).unwrap().remove(0);
+        assert_eq!(series.name, "consolidateBy(scale(counter,2),\"max\")");
+        assert_eq!(series.consolidation, Consolidation::Max);
+        assert_eq!(series.fit_to(3).values, vec![Some(60.0), Some(100.0), Some(10.0)]);
+
+        let expr = parse("scale(consolidateBy(counter,'sum'),2)").unwrap();
+        assert_eq!(evaluate(&expr, &fetch).unwrap()[0].consolidation, Consolidation::Sum);
+    }
+
+    #[test]
+    fn bad_calls(){
+        assert!(evaluate(&parse("noSuchFunction(counter)").unwrap(), &fetch).is_err());
+        assert!(evaluate(&parse("scale(counter)").unwrap(), &fetch).is_err());
+        assert!(evaluate(&parse("scale(1,2)").unwrap(), &fetch).is_err());
+        assert!(evaluate(&parse("aliasByNode(counter,3)").unwrap(), &fetch).is_err());
+        assert!(evaluate(&parse("movingAverage(counter,'5m')").unwrap(), &fetch).is_err());
+        assert!(evaluate(&parse("movingAverage(counter,0)").unwrap(), &fetch).is_err());
+        assert!(evaluate(&parse("consolidateBy(counter,'median')").unwrap(), &fetch).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+// The graphite render functions. A target is parsed into an `Expr`, paths
+// are resolved to series by the caller (see `handlers::render`), and calls
+// are looked up in `library`.
+
+mod parser;
+mod library;
+
+pub use self::parser::{ parse, Expr };
+
+use super::series::Series;
+
+pub enum Arg {
+    SeriesList(Vec<Series>),
+    Number(f64),
+    Str(String),
+    Bool(bool)
+}
+
+// The evaluated arguments of one call, next to the expressions they came
+// from since graphite names most results after their arguments.
+pub struct Args<'a> {
+    pub function: &'a str,
+    values: Vec<Arg>,
+    exprs: &'a [Expr]
+}
+
+impl<'a> Args<'a> {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn text(&self, i: usize) -> String {
+        self.exprs[i].to_string()
+    }
+
+    pub fn series(&mut self, i: usize) -> Result<Vec<Series>, String> {
+        match self.values.get_mut(i) {
+            Some(&mut Arg::SeriesList(ref mut series)) => Ok(::std::mem::replace(series, vec![])),
+            Some(_) => Err(format!("{}: argument {} must be a series list", self.function, i + 1)),
+            None => Err(format!("{}: missing series list argument {}", self.function, i + 1))
+        }
+    }
+
+    // Every argument from `i` on, for the `sumSeries(a.*, b.*)` style
+    pub fn all_series(&mut self, i: usize) -> Result<Vec<Series>, String> {
+        let mut all = vec![];
+        for n in i..self.values.len() {
+            all.extend( try!( self.series(n) ) );
+        }
+        Ok(all)
+    }
+
+    pub fn number(&self, i: usize) -> Result<f64, String> {
+        match try!( self.optional_number(i) ) {
+            Some(number) => Ok(number),
+            None => Err(format!("{}: missing number argument {}", self.function, i + 1))
+        }
+    }
+
+    pub fn optional_number(&self, i: usize) -> Result<Option<f64>, String> {
+        match self.values.get(i) {
+            Some(&Arg::Number(number)) => Ok(Some(number)),
+            Some(_) => Err(format!("{}: argument {} must be a number", self.function, i + 1)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn string(&self, i: usize) -> Result<String, String> {
+        match self.values.get(i) {
+            Some(&Arg::Str(ref string)) => Ok(string.clone()),
+            Some(_) => Err(format!("{}: argument {} must be a string", self.function, i + 1)),
+            None => Err(format!("{}: missing string argument {}", self.function, i + 1))
+        }
+    }
+
+    pub fn is_series(&self, i: usize) -> bool {
+        match self.values.get(i) {
+            Some(&Arg::SeriesList(_)) => true,
+            _ => false
+        }
+    }
+}
+
+// `fetch` turns a metric path (globs and all) into the matching series
+pub fn evaluate<F>(expr: &Expr, fetch: &F) -> Result<Vec<Series>, String> where F: Fn(&str) -> Vec<Series> {
+    match *expr {
+        Expr::Path(ref path) => Ok(fetch(path)),
+        Expr::Call(ref name, ref exprs) => {
+            let mut values = vec![];
+            for arg in exprs {
+                values.push( try!( evaluate_arg(arg, fetch) ) );
+            }
+            let args = Args {
+                function: name,
+                values: values,
+                exprs: exprs
+            };
+            library::call(&expr.to_string(), args)
+        },
+        _ => Err(format!("`{}` is not a series list", expr))
+    }
+}
+
+fn evaluate_arg<F>(expr: &Expr, fetch: &F) -> Result<Arg, String> where F: Fn(&str) -> Vec<Series> {
+    Ok(match *expr {
+        Expr::Number(number) => Arg::Number(number),
+        Expr::Str(ref string) => Arg::Str(string.clone()),
+        Expr::Bool(boolean) => Arg::Bool(boolean),
+        _ => Arg::SeriesList( try!( evaluate(expr, fetch) ) )
+    })
+}
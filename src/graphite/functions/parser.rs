@@ -0,0 +1,187 @@
+// Turns a render target such as `scale(derivative(foo.{a,b}.*),0.1)` into
+// an `Expr` tree. Anything that isn't a call, a number, a quoted string or
+// true/false is taken to be a metric path (globs and all).
+
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Path(String),
+    Call(String, Vec<Expr>),
+    Number(f64),
+    Str(String),
+    Bool(bool)
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Path(ref path) => write!(f, "{}", path),
+            Expr::Number(number) => write!(f, "{}", number),
+            Expr::Str(ref string) => write!(f, "\"{}\"", string),
+            Expr::Bool(boolean) => write!(f, "{}", boolean),
+            Expr::Call(ref name, ref args) => {
+                let args : Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(","))
+            }
+        }
+    }
+}
+
+pub fn parse(target: &str) -> Result<Expr, String> {
+    let mut parser = Parser { chars: target.chars().collect(), pos: 0 };
+    let expr = try!( parser.expr() );
+    parser.skip_whitespace();
+    if parser.pos != parser.chars.len() {
+        return Err(format!("Unexpected `{}` at position {} of `{}`", parser.chars[parser.pos], parser.pos, target));
+    }
+    Ok(expr)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(quote) if quote == '"' || quote == '\'' => self.string(quote),
+            Some(_) => self.token(),
+            None => Err("Unexpected end of target".to_string())
+        }
+    }
+
+    fn string(&mut self, quote: char) -> Result<Expr, String> {
+        self.pos += 1;
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c == quote {
+                let string : String = self.chars[start..self.pos].iter().cloned().collect();
+                self.pos += 1;
+                return Ok(Expr::Str(string));
+            }
+            self.pos += 1;
+        }
+        Err("Unterminated string in target".to_string())
+    }
+
+    // A bare word runs up to the next `(`, `,` or `)` that isn't inside `{}`
+    fn token(&mut self) -> Result<Expr, String> {
+        let start = self.pos;
+        let mut brace_depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                '{' => brace_depth += 1,
+                '}' if brace_depth > 0 => brace_depth -= 1,
+                '(' | ',' | ')' if brace_depth == 0 => break,
+                _ => ()
+            }
+            self.pos += 1;
+        }
+
+        let word : String = self.chars[start..self.pos].iter().cloned().collect();
+        let word = word.trim().to_string();
+        if word.len() == 0 {
+            return Err(format!("Expected an argument at position {}", start));
+        }
+
+        if self.peek() == Some('(') {
+            self.pos += 1;
+            let args = try!( self.args() );
+            return Ok(Expr::Call(word, args));
+        }
+
+        if word == "true" || word == "false" {
+            return Ok(Expr::Bool(word == "true"));
+        }
+
+        // Rust happily parses `inf` and `NaN`, which are fine metric names
+        let looks_numeric = word.starts_with(|c: char| c.is_digit(10) || c == '-' || c == '+' || c == '.');
+        if looks_numeric {
+            if let Ok(number) = word.parse::<f64>() {
+                return Ok(Expr::Number(number));
+            }
+        }
+
+        Ok(Expr::Path(word))
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(')') {
+            self.pos += 1;
+            return Ok(args);
+        }
+
+        loop {
+            args.push( try!( self.expr() ) );
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(')') => {
+                    self.pos += 1;
+                    return Ok(args);
+                },
+                _ => return Err("Expected `,` or `)` in target".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ parse, Expr };
+
+    fn path(p: &str) -> Expr {
+        Expr::Path(p.to_string())
+    }
+
+    #[test]
+    fn nested_calls(){
+        let expected = Expr::Call("scale".to_string(), vec![
+            Expr::Call("derivative".to_string(), vec![path("foo.bar")]),
+            Expr::Number(0.1)
+        ]);
+        assert_eq!(parse("scale(derivative(foo.bar),0.1)").unwrap(), expected);
+    }
+
+    #[test]
+    fn braces_strings_and_whitespace(){
+        let expected = Expr::Call("alias".to_string(), vec![
+            Expr::Call("sumSeries".to_string(), vec![path("stats.{web,db}*.requests")]),
+            Expr::Str("all requests".to_string())
+        ]);
+        let parsed = parse("alias( sumSeries(stats.{web,db}*.requests), 'all requests' )").unwrap();
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.to_string(), "alias(sumSeries(stats.{web,db}*.requests),\"all requests\")");
+    }
+
+    #[test]
+    fn plain_paths_and_numbers(){
+        assert_eq!(parse("collectd.*.load").unwrap(), path("collectd.*.load"));
+        assert_eq!(parse("inf.nan").unwrap(), path("inf.nan"));
+        assert_eq!(parse("aliasByNode(a.b,-1)").unwrap(),
+                   Expr::Call("aliasByNode".to_string(), vec![path("a.b"), Expr::Number(-1.0)]));
+    }
+
+    #[test]
+    fn rejects_garbage(){
+        assert!(parse("sumSeries(a.b").is_err());
+        assert!(parse("sumSeries(a.b))").is_err());
+        assert!(parse("scale(a.b,)").is_err());
+        assert!(parse("alias(a.b,'oops)").is_err());
+    }
+}
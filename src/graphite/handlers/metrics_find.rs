@@ -1,60 +1,126 @@
-use super::super::super::whisper::Cache;
-
-use super::super::expander::expand;
+use super::super::expander::{ expand, QueryResultNode };
 use super::super::cache_holder::CacheHolder;
 use super::super::error::StringError;
+use super::super::http::{ Request, Response, HttpError, HttpResult, PICKLE_CONTENT_TYPE };
+use pickle::{ self, Value };
+
+use rustc_serialize::json::{ Json, ToJson };
+use std::collections::BTreeMap;
 
-use iron::prelude::*;
-use iron;
-use urlencoded::UrlEncodedQuery;
-
-use persistent::State;
-use std::sync::{ Arc, RwLock };
-use std::ops::DerefMut;
-
-pub fn metrics_find(req: &mut Request) -> IronResult<Response> {
-    let locked_cache : Arc< RwLock<Cache> > = req.get::<State<CacheHolder>>().unwrap();
-    let mut cache_writer = locked_cache.write().unwrap();
-    let mut cache = cache_writer.deref_mut();
-
-    // Extract the decoded data as hashmap, using the UrlEncodedQuery plugin.
-    match req.get_ref::<UrlEncodedQuery>() {
-        Ok(ref hashmap) => {
-            match hashmap.get("query") {
-                Some(query) => {
-                    if query.len() == 1 {
-                        let ref first_query = query[0];
-                        let http_body = do_find_metrics(first_query, &mut cache);
-                        let mut http_res = Response::with((iron::status::Ok, http_body));
-
-                        let jsony_ctype = iron::headers::ContentType(
-                            iron::mime::Mime(
-                                iron::mime::TopLevel::Application,
-                                iron::mime::SubLevel::Json,
-                                vec![(iron::mime::Attr::Charset, iron::mime::Value::Utf8)]
-                            )
-                        );
-                        http_res.headers.set::<iron::headers::ContentType>(jsony_ctype);
-                        Ok(http_res)
-                    } else {
-                        error!("must provide only 1 query string");
-                        Err(IronError::new(StringError("Must provide only one query".to_string()), iron::status::BadRequest))
+pub fn metrics_find(req: &Request, cache: &CacheHolder) -> HttpResult {
+    match req.params.get("query") {
+        Some(query) => {
+            if query.len() == 1 {
+                let ref first_query = query[0];
+                let format = match req.param("format") {
+                    None | Some("treejson") => FindFormat::TreeJson,
+                    Some("completer") => FindFormat::Completer,
+                    Some("pickle") => FindFormat::Pickle,
+                    Some(other) => {
+                        error!("unsupported find format `{}`", other);
+                        return Err(HttpError::new(StringError(format!("Unsupported format `{}`", other)), 400));
                     }
-                },
-                None => {
-                    error!("no query was provided");
-                    Err(IronError::new(StringError("Must provide query".to_string()), iron::status::BadRequest))
-                }
+                };
+                Ok(format.respond(&expand(first_query, cache)))
+            } else {
+                error!("must provide only 1 query string");
+                Err(HttpError::new(StringError("Must provide only one query".to_string()), 400))
             }
         },
-        Err(_) => {
-            error!("unknown error");
-            Err(IronError::new(StringError("Error whoaaa".to_string()), iron::status::BadRequest))
+        None => {
+            error!("no query was provided");
+            Err(HttpError::new(StringError("Must provide query".to_string()), 400))
+        }
+    }
+}
+
+// What graphite-web's find_view can answer with
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum FindFormat {
+    TreeJson,
+    Completer,
+    Pickle
+}
+
+impl FindFormat {
+    fn respond(&self, nodes: &[QueryResultNode]) -> Response {
+        match *self {
+            FindFormat::TreeJson => Response::json(Json::Array(nodes.iter().map(|node| node.to_json()).collect()).to_string()),
+            FindFormat::Completer => Response::json(completer(nodes).to_string()),
+            FindFormat::Pickle => Response::with(200, PICKLE_CONTENT_TYPE, pickle::pickle(&find_info(nodes)))
         }
     }
 }
 
-fn do_find_metrics(query: &String, cache: &mut Cache) -> String {
-    let hits = expand(query, cache);
-    format!("[{}]", hits.join(","))
-}
\ No newline at end of file
+// {"metrics": [{"path": "a.b.", "name": "b", "is_leaf": "0"}, ...]}, branch paths end in a dot
+fn completer(nodes: &[QueryResultNode]) -> Json {
+    let metrics = nodes.iter().map(|node| {
+        let mut path = node.metric_name();
+        if !node.is_leaf() {
+            path.push('.');
+        }
+
+        let mut metric = BTreeMap::new();
+        metric.insert("path".to_string(), Json::String(path));
+        metric.insert("name".to_string(), Json::String(node.name().to_string()));
+        metric.insert("is_leaf".to_string(), Json::String(if node.is_leaf() { "1" } else { "0" }.to_string()));
+        Json::Object(metric)
+    }).collect();
+
+    let mut object = BTreeMap::new();
+    object.insert("metrics".to_string(), Json::Array(metrics));
+    Json::Object(object)
+}
+
+// [{'path': 'a.b', 'is_leaf': True}, ...], what a federating graphite-web asks remotes for
+fn find_info(nodes: &[QueryResultNode]) -> Value {
+    Value::List(nodes.iter().map(|node| {
+        Value::Dict(vec![
+            (Value::String("path".to_string()), Value::String(node.metric_name())),
+            (Value::String("is_leaf".to_string()), Value::Bool(node.is_leaf()))
+        ])
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::FindFormat;
+    use super::super::super::expander::QueryResultNode;
+    use pickle::{ unpickle, Value };
+
+    fn nodes() -> Vec<QueryResultNode> {
+        let root = Path::new("/tmp/thing").to_path_buf();
+        vec![
+            QueryResultNode::DirNode( root.clone(), root.join("is/cool") ),
+            QueryResultNode::WspNode( root.clone(), root.join("is/bear.wsp") )
+        ]
+    }
+
+    #[test]
+    fn treejson(){
+        let body = String::from_utf8(FindFormat::TreeJson.respond(&nodes()).body).unwrap();
+        assert_eq!(body, concat!(r#"[{"allowChildren":1,"context":{},"expandable":1,"id":"is.cool","leaf":0,"text":"cool"},"#,
+                                 r#"{"allowChildren":0,"context":{},"expandable":0,"id":"is.bear","leaf":1,"text":"bear"}]"#));
+    }
+
+    #[test]
+    fn completer(){
+        let body = String::from_utf8(FindFormat::Completer.respond(&nodes()).body).unwrap();
+        assert_eq!(body, concat!(r#"{"metrics":[{"is_leaf":"0","name":"cool","path":"is.cool."},"#,
+                                 r#"{"is_leaf":"1","name":"bear","path":"is.bear"}]}"#));
+    }
+
+    #[test]
+    fn pickle(){
+        let response = FindFormat::Pickle.respond(&nodes());
+        assert_eq!(response.content_type, "application/pickle");
+        let expected = Value::List(vec![
+            Value::Dict(vec![(Value::String("path".to_string()), Value::String("is.cool".to_string())),
+                             (Value::String("is_leaf".to_string()), Value::Bool(false))]),
+            Value::Dict(vec![(Value::String("path".to_string()), Value::String("is.bear".to_string())),
+                             (Value::String("is_leaf".to_string()), Value::Bool(true))])
+        ]);
+        assert_eq!(unpickle(&response.body).unwrap(), expected);
+    }
+}
@@ -1,8 +1,19 @@
-use iron::prelude::*;
-use iron;
-use urlencoded::UrlEncodedQuery;
+use super::super::expander::expand_leaves;
+use super::super::cache_holder::CacheHolder;
+use super::super::error::StringError;
+use super::super::series::Series;
+use super::super::functions;
+use super::super::time_spec;
+use super::super::render_format::Format;
+use super::super::http::{ Request, HttpError, HttpResult };
+use whisper_io;
 
-use std::io::Read;
+use time;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
 
 // [{
 //   "target": "entries",
@@ -14,20 +25,230 @@ use std::io::Read;
 //     [6.0, 1311836012]
 //   ]
 // }]
-pub fn render(req: &mut Request) -> IronResult<Response> {
-	let mut buf : Vec<u8> = Vec::new();
-	let bytes_read = req.body.read_to_end(&mut buf);
-	println!("body ({bytes_read:?}: {:#?}", buf, bytes_read=bytes_read);
-
-    match req.get_ref::<UrlEncodedQuery>() {
-        Ok(ref hashmap) => println!("Parsed GET request query string:\n {:?}", hashmap),
-        Err(ref e) => println!("err {:?}", e)
+pub fn render(req: &Request, cache: &CacheHolder) -> HttpResult {
+    let now = time::get_time().sec as u32;
+    let render_params = match RenderParams::from_params(&req.params, now) {
+        Ok(render_params) => render_params,
+        Err(reason) => {
+            error!("bad render request: {}", reason);
+            return Err(HttpError::new(StringError(reason), 400));
+        }
     };
 
-	Ok( Response::with( (iron::status::Ok, "hey".to_string() ) ) )
+    let series = match do_render(&render_params, cache, now) {
+        Ok(series) => series,
+        Err(reason) => {
+            error!("could not evaluate targets: {}", reason);
+            return Err(HttpError::new(StringError(reason), 400));
+        }
+    };
+    Ok(render_params.format.respond(&series))
 }
 
 // target=hey.select%20metric&from=-6h&until=now&format=json&maxDataPoints=1425
-// fn do_render() {
+#[derive(Debug, PartialEq)]
+pub struct RenderParams {
+    pub targets: Vec<String>,
+    pub from: u32,
+    pub until: u32,
+    pub max_data_points: Option<usize>,
+    pub format: Format
+}
+
+impl RenderParams {
+    pub fn from_params(params: &HashMap<String, Vec<String>>, now: u32) -> Result<RenderParams, String> {
+        let targets = match params.get("target") {
+            Some(targets) if targets.len() > 0 => targets.clone(),
+            _ => return Err("Must provide at least one target".to_string())
+        };
+
+        let format = match first(params, "format") {
+            Some(name) => try!( Format::from_str(name).ok_or_else(|| format!("Unsupported format `{}`", name)) ),
+            None => Format::Json
+        };
+
+        let from = try!( time_spec::parse(first(params, "from").unwrap_or("-1d"), now) );
+        let until = try!( time_spec::parse(first(params, "until").unwrap_or("now"), now) );
+        if from >= until {
+            return Err(format!("`from` ({}) must be before `until` ({})", from, until));
+        }
+
+        let max_data_points = match first(params, "maxDataPoints") {
+            Some(max) => match max.parse::<usize>() {
+                Ok(max) if max > 0 => Some(max),
+                _ => return Err(format!("maxDataPoints `{}` is not a positive integer", max))
+            },
+            None => None
+        };
+
+        Ok(RenderParams {
+            targets: targets,
+            from: from,
+            until: until,
+            max_data_points: max_data_points,
+            format: format
+        })
+    }
+}
+
+fn first<'a>(params: &'a HashMap<String, Vec<String>>, key: &str) -> Option<&'a str> {
+    params.get(key).and_then(|values| values.first()).map(|value| &value[..])
+}
+
+// Each target is a function expression (a bare path being the simplest),
+// its paths are read from whisper and then handed to `functions`.
+pub fn do_render(params: &RenderParams, cache: &CacheHolder, now: u32) -> Result<Vec<Series>, String> {
+    let mut series = vec![];
+
+    let fetch = |path: &str| {
+        let mut fetched = vec![];
+        for node in expand_leaves(&path.to_string(), cache) {
+            let metric_name = node.metric_name();
+            match read_series(cache, &metric_name, params.from, params.until, now) {
+                Ok(Some(s)) => fetched.push(s),
+                Ok(None) => debug!("`{}` has no data between {} and {}", metric_name, params.from, params.until),
+                Err(err) => error!("could not read `{}`: {:?}", metric_name, err)
+            }
+        }
+        fetched
+    };
+
+    for target in &params.targets {
+        let expr = try!( functions::parse(target) );
+        series.extend( try!( functions::evaluate(&expr, &fetch) ) );
+    }
+
+    Ok(match params.max_data_points {
+        Some(max_data_points) => series.into_iter().map(|s| s.fit_to(max_data_points)).collect(),
+        None => series
+    })
+}
 
-// }
\ No newline at end of file
+fn read_series(cache: &CacheHolder, metric_name: &str, from: u32, until: u32, now: u32) -> Result<Option<Series>, ::std::io::Error> {
+    let mut rel_path = metric_name.replace(".", "/");
+    rel_path.push_str(".wsp");
+
+    // Pending points first: the writer only drops them once they're on
+    // disk, so this order never misses one in between
+    let pending = cache.pending_points(Path::new(&rel_path));
+
+    let fetched = match cache.open_file(Path::new(&rel_path)) {
+        Some(open_file) => {
+            let mut open_file = open_file.lock().unwrap();
+            let open_file = &mut *open_file;
+            try!( whisper_io::fetch(&mut open_file.file, &open_file.metadata, from, until, now) )
+        },
+        None => match File::open(cache.base_path.join(&rel_path)) {
+            Ok(mut file) => {
+                let metadata = try!( whisper_io::read_metadata(&mut file) );
+                try!( whisper_io::fetch(&mut file, &metadata, from, until, now) )
+            },
+            // No file yet, but the writer has points for one. Lay them out
+            // the way they'll read back once it's created.
+            Err(ref err) if err.kind() == ErrorKind::NotFound && !pending.is_empty() => {
+                let archives = cache.new_file_archives(Path::new(&rel_path)).unwrap();
+                let max_retention = archives.iter().map(|archive| archive.retention()).max().unwrap_or(0);
+                whisper_io::fetch_window(&archives, max_retention, from, until, now).map(|(_, (start, end, step))| {
+                    ((start, end, step), vec![None; ((end - start) / step) as usize])
+                })
+            },
+            Err(err) => return Err(err)
+        }
+    };
+
+    Ok(fetched.map(|((start, end, step), mut values)| {
+        merge_pending(&mut values, start, step, &pending);
+        Series::new(metric_name.to_string(), start, end, step, values)
+    }))
+}
+
+// Lay not-yet-written points over what came off disk, the same way the
+// writer will: into the slot their timestamp falls in, last one wins.
+fn merge_pending(values: &mut [Option<f64>], start: u32, step: u32, pending: &[(u32, f64)]) {
+    for &(timestamp, value) in pending {
+        if timestamp < start {
+            continue
+        }
+        let slot = ((timestamp - start) / step) as usize;
+        if slot < values.len() {
+            values[slot] = Some(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::{ Path, PathBuf };
+    use std::sync::{ Arc, RwLock };
+    use carbon::{ StorageSchemas, StorageAggregation, WhisperCache };
+    use carbon::cache_writer::Shard;
+    use test_support::TempDir;
+    use super::{ RenderParams, do_render, merge_pending };
+    use super::super::super::cache_holder::CacheHolder;
+    use super::super::super::metric_index::MetricIndex;
+    use super::super::super::render_format::Format;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        let mut params : HashMap<String, Vec<String>> = HashMap::new();
+        for &(key, value) in pairs {
+            params.entry(key.to_string()).or_insert(vec![]).push(value.to_string());
+        }
+        params
+    }
+
+    #[test]
+    fn grafana_request(){
+        let now = 1437548400;
+        let raw = params(&[("target", "a.b"), ("target", "c.*"), ("from", "-6h"), ("until", "now"), ("format", "json"), ("maxDataPoints", "1425")]);
+        let expected = RenderParams {
+            targets: vec!["a.b".to_string(), "c.*".to_string()],
+            from: now - 6*60*60,
+            until: now,
+            max_data_points: Some(1425),
+            format: Format::Json
+        };
+        assert_eq!(RenderParams::from_params(&raw, now).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_bad_requests(){
+        let now = 1437548400;
+        assert!(RenderParams::from_params(&params(&[]), now).is_err());
+        assert!(RenderParams::from_params(&params(&[("target", "a"), ("format", "png")]), now).is_err());
+        assert!(RenderParams::from_params(&params(&[("target", "a"), ("maxDataPoints", "lots")]), now).is_err());
+        assert_eq!(RenderParams::from_params(&params(&[("target", "a"), ("format", "csv")]), now).unwrap().format, Format::Csv);
+        assert!(RenderParams::from_params(&params(&[("target", "a"), ("from", "now"), ("until", "-1h")]), now).is_err());
+    }
+
+    #[test]
+    fn pending_points_fill_the_right_edge(){
+        let mut values = vec![Some(1.0), None, None];
+        let pending = [(1437548399, 9.0), (1437548460, 2.0), (1437548530, 3.0), (1437548539, 4.0), (1437548580, 5.0)];
+        merge_pending(&mut values, 1437548400, 60, &pending);
+        assert_eq!(values, vec![Some(1.0), Some(2.0), Some(4.0)]);
+    }
+
+    #[test]
+    fn renders_metrics_without_a_file_yet(){
+        let dir = TempDir::new("graphite-render-renders_metrics_without_a_file_yet");
+
+        let schemas = StorageSchemas::parse("[all]\npattern = .*\nretentions = 60s:1d\n").unwrap();
+        let index = Arc::new( RwLock::new( MetricIndex::new() ) );
+        let shard = Shard::new(WhisperCache::new(dir.path(), 10, schemas, StorageAggregation::new_default()).with_index(index.clone()));
+        let now = 1437548400;
+        // What the writer does with each point it takes off the channel
+        for &(timestamp, value) in &[(now - 120, 1.0), (now - 60, 2.0)] {
+            if shard.pending.lock().unwrap().push(PathBuf::from("new/metric.wsp"), timestamp, value) {
+                shard.cache.lock().unwrap().index_pending(Path::new("new/metric.wsp"));
+            }
+        }
+        let cache = CacheHolder::with_shards(vec![shard]).with_index(index);
+
+        let render_params = RenderParams::from_params(&params(&[("target", "new.*"), ("from", "-5min")]), now).unwrap();
+        let series = do_render(&render_params, &cache, now).unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!((&series[0].name[..], series[0].start, series[0].step), ("new.metric", now - 240, 60));
+        assert_eq!(series[0].values, vec![None, None, Some(1.0), Some(2.0), None]);
+    }
+}
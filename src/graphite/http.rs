@@ -0,0 +1,120 @@
+// The little bit of HTTP the handlers need to see. Keeps tiny_http
+// confined to `server.rs` so handlers stay easy to test.
+
+use std::collections::HashMap;
+use url::form_urlencoded;
+
+use super::error::StringError;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Method {
+    Get,
+    Post,
+    Other
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: Vec<String>,
+    pub params: HashMap<String, Vec<String>>
+}
+
+impl Request {
+    // `url` is the request target (`/metrics/find/?query=*`), `body` is only
+    // looked at for form posts, which is how Grafana sends /render.
+    pub fn new(method: Method, url: &str, body: &[u8]) -> Request {
+        let (path, query) = match url.find('?') {
+            Some(pos) => (&url[..pos], &url[pos+1..]),
+            None => (url, "")
+        };
+
+        let mut params : HashMap<String, Vec<String>> = HashMap::new();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()).chain(form_urlencoded::parse(body)) {
+            params.entry(key.into_owned()).or_insert(vec![]).push(value.into_owned());
+        }
+
+        Request {
+            method: method,
+            path: path.split('/').skip(1).map(|segment| segment.to_string()).collect(),
+            params: params
+        }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params.get(key).and_then(|values| values.first()).map(|value| &value[..])
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn with(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status: status,
+            content_type: content_type.to_string(),
+            body: body
+        }
+    }
+
+    pub fn json(body: String) -> Response {
+        Response::with(200, JSON_CONTENT_TYPE, body.into_bytes())
+    }
+}
+
+pub const JSON_CONTENT_TYPE : &'static str = "application/json; charset=utf-8";
+pub const TEXT_CONTENT_TYPE : &'static str = "text/plain; charset=utf-8";
+pub const CSV_CONTENT_TYPE : &'static str = "text/csv; charset=utf-8";
+pub const PICKLE_CONTENT_TYPE : &'static str = "application/pickle";
+pub const MSGPACK_CONTENT_TYPE : &'static str = "application/x-msgpack";
+
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub error: StringError
+}
+
+impl HttpError {
+    pub fn new(error: StringError, status: u16) -> HttpError {
+        HttpError {
+            status: status,
+            error: error
+        }
+    }
+
+    pub fn not_found() -> HttpError {
+        HttpError::new(StringError("Not found".to_string()), 404)
+    }
+
+    pub fn to_response(&self) -> Response {
+        Response::with(self.status, TEXT_CONTENT_TYPE, (self.error.0.clone() + "\n").into_bytes())
+    }
+}
+
+pub type HttpResult = Result<Response, HttpError>;
+
+#[cfg(test)]
+mod tests {
+    use super::{ Request, Method };
+
+    #[test]
+    fn query_and_form_params(){
+        let req = Request::new(Method::Post, "/render?target=a.b&from=-6h", b"target=c.%2A&until=now");
+        assert_eq!(req.path, vec!["render"]);
+        assert_eq!(req.params.get("target").unwrap(), &vec!["a.b".to_string(), "c.*".to_string()]);
+        assert_eq!(req.param("from"), Some("-6h"));
+        assert_eq!(req.param("until"), Some("now"));
+        assert_eq!(req.param("format"), None);
+    }
+
+    #[test]
+    fn trailing_slash_path(){
+        let req = Request::new(Method::Get, "/metrics/find/?query=*", b"");
+        assert_eq!(req.path, vec!["metrics", "find", ""]);
+    }
+}
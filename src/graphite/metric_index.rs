@@ -0,0 +1,211 @@
+// Every metric name we know of, as a trie of dot-separated nodes, so finds
+// don't have to touch the filesystem. Built by scanning the storage dir and
+// kept current by carbon's `WhisperCache` as it creates files.
+
+use std::collections::BTreeMap;
+use std::fs::{ read_dir, ReadDir };
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use super::finder::MetricTree;
+
+#[derive(Debug, Default)]
+struct Node {
+    children: BTreeMap<String, Node>,
+    // A name can be a leaf and a branch at once (`a.b` next to `a.b.c`)
+    is_leaf: bool
+}
+
+#[derive(Debug, Default)]
+pub struct MetricIndex {
+    root: Node,
+    leaves: usize
+}
+
+impl MetricIndex {
+    pub fn new() -> MetricIndex {
+        MetricIndex::default()
+    }
+
+    // Every .wsp file under `base_path`. Only `base_path` itself has to be
+    // readable, anything below it that isn't is logged and left out.
+    pub fn scan(base_path: &Path) -> io::Result<MetricIndex> {
+        let mut index = MetricIndex::new();
+        let mut pending : Vec<(PathBuf, Vec<String>)> = vec![];
+        index.scan_dir(try!( read_dir(base_path) ), base_path, &[], &mut pending);
+
+        while let Some((dir, path)) = pending.pop() {
+            match read_dir(&dir) {
+                Ok(entries) => index.scan_dir(entries, &dir, &path, &mut pending),
+                Err(reason) => warn!("skipping {:?}: {}", dir, reason)
+            }
+        }
+
+        info!("indexed {} metrics under {:?}", index.leaves, base_path);
+        Ok(index)
+    }
+
+    fn scan_dir(&mut self, entries: ReadDir, dir: &Path, path: &[String], pending: &mut Vec<(PathBuf, Vec<String>)>) {
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(reason) => {
+                    warn!("skipping an entry in {:?}: {}", dir, reason);
+                    continue
+                }
+            };
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    info!("skipping non utf-8 name {:?} in {:?}", name, dir);
+                    continue
+                }
+            };
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(reason) => {
+                    warn!("skipping {:?}: {}", entry.path(), reason);
+                    continue
+                }
+            };
+
+            let mut child = path.to_vec();
+            if file_type.is_dir() {
+                child.push(name);
+                pending.push((entry.path(), child));
+            } else if name.ends_with(".wsp") {
+                child.push(name[..name.len()-4].to_string());
+                self.insert_path(&child);
+            }
+        }
+    }
+
+    pub fn insert(&mut self, metric_name: &str) {
+        let path : Vec<String> = metric_name.split('.').map(|node| node.to_string()).collect();
+        self.insert_path(&path);
+    }
+
+    fn insert_path(&mut self, path: &[String]) {
+        let mut node = &mut self.root;
+        for name in path {
+            node = node.children.entry(name.clone()).or_insert_with(Node::default);
+        }
+        if !node.is_leaf {
+            node.is_leaf = true;
+            self.leaves += 1;
+        }
+    }
+
+    pub fn contains(&self, metric_name: &str) -> bool {
+        let path : Vec<String> = metric_name.split('.').map(|node| node.to_string()).collect();
+        self.node(&path).map(|node| node.is_leaf).unwrap_or(false)
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves
+    }
+
+    fn node(&self, path: &[String]) -> Option<&Node> {
+        let mut node = &self.root;
+        for name in path {
+            node = match node.children.get(name) {
+                Some(child) => child,
+                None => return None
+            };
+        }
+        Some(node)
+    }
+
+    fn children<F>(&self, path: &[String], keep: F) -> Vec<String> where F: Fn(&Node) -> bool {
+        match self.node(path) {
+            Some(node) => node.children.iter().filter(|&(_, child)| keep(child)).map(|(name, _)| name.clone()).collect(),
+            None => vec![]
+        }
+    }
+}
+
+impl MetricTree for MetricIndex {
+    fn branches(&self, path: &[String]) -> Vec<String> {
+        self.children(path, |child| child.children.len() > 0)
+    }
+
+    fn leaves(&self, path: &[String]) -> Vec<String> {
+        self.children(path, |child| child.is_leaf)
+    }
+
+    fn is_branch(&self, path: &[String]) -> bool {
+        self.node(path).map(|node| node.children.len() > 0).unwrap_or(false)
+    }
+
+    fn is_leaf(&self, path: &[String]) -> bool {
+        self.node(path).map(|node| node.is_leaf).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::fs::{ self, File };
+    use std::os::unix::fs::PermissionsExt;
+    use test_support::TempDir;
+    use super::MetricIndex;
+    use super::super::finder::find;
+
+    fn found(index: &MetricIndex, pattern: &str) -> Vec<(String, bool)> {
+        find(index, pattern).into_iter().map(|m| (m.path.join("."), m.is_leaf)).collect()
+    }
+
+    #[test]
+    fn insert_and_find(){
+        let mut index = MetricIndex::new();
+        index.insert("servers.web1.cpu");
+        index.insert("servers.web1.cpu.user");
+        index.insert("servers.db1.load");
+        index.insert("servers.db1.load");
+        assert_eq!(index.len(), 3);
+        assert!(index.contains("servers.web1.cpu"));
+        assert!(!index.contains("servers.web1"));
+
+        assert_eq!(found(&index, "servers.*"), vec![("servers.db1".to_string(), false), ("servers.web1".to_string(), false)]);
+        assert_eq!(found(&index, "servers.web1.cpu"), vec![("servers.web1.cpu".to_string(), false), ("servers.web1.cpu".to_string(), true)]);
+        assert_eq!(found(&index, "servers.{web,db}1.*.user"), vec![("servers.web1.cpu.user".to_string(), true)]);
+        assert_eq!(found(&index, "nope.*"), vec![]);
+    }
+
+    #[test]
+    fn scans_storage_dir(){
+        let tmp = TempDir::new("graphite-metric-index-scans_storage_dir");
+        let root = tmp.path();
+        fs::create_dir_all(root.join("collectd/host1")).unwrap();
+        File::create(root.join("collectd/host1/load.wsp")).unwrap();
+        File::create(root.join("collectd/host1/README")).unwrap();
+        File::create(root.join("carbon.wsp")).unwrap();
+
+        let index = MetricIndex::scan(root).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(found(&index, "*"), vec![("carbon".to_string(), true), ("collectd".to_string(), false)]);
+        assert_eq!(found(&index, "collectd.*.*"), vec![("collectd.host1.load".to_string(), true)]);
+    }
+
+    #[test]
+    fn skips_unreadable_dirs(){
+        let tmp = TempDir::new("graphite-metric-index-skips_unreadable_dirs");
+        let root = tmp.path().to_path_buf();
+        fs::create_dir_all(root.join("locked")).unwrap();
+        File::create(root.join("locked/load.wsp")).unwrap();
+        File::create(root.join("carbon.wsp")).unwrap();
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+        let index = MetricIndex::scan(&root).unwrap();
+        // Readable again before anything can fail, or it couldn't be removed
+        fs::set_permissions(root.join("locked"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        assert_eq!(found(&index, "carbon"), vec![("carbon".to_string(), true)]);
+        // root reads it anyway
+        if unsafe { libc::geteuid() } != 0 {
+            assert_eq!(found(&index, "locked.*"), vec![]);
+        }
+
+        drop(tmp);
+        assert!(MetricIndex::scan(&root).is_err());
+    }
+}
@@ -1,3 +1,10 @@
 mod path_fixer;
 
 pub use self::path_fixer::PathFixer;
+
+use super::http::{ Request, HttpError };
+
+// Runs on every request before it is routed
+pub trait BeforeMiddleware: Send + Sync {
+    fn before(&self, req: &mut Request) -> Result<(), HttpError>;
+}
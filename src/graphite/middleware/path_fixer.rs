@@ -1,19 +1,30 @@
-use iron::prelude::*;
-use iron;
+use super::BeforeMiddleware;
+use super::super::http::{ Request, HttpError };
 
+// graphite-web answers `/metrics/find/` and `/metrics/find` alike
 pub struct PathFixer;
 
-impl iron::middleware::BeforeMiddleware for PathFixer {
-	fn before(&self, req: &mut Request) -> IronResult<()> {
-		let ref mut path = req.url.path;
-		if path.last().unwrap().len() == 0 {
-			path.pop();
-		}
+impl BeforeMiddleware for PathFixer {
+    fn before(&self, req: &mut Request) -> Result<(), HttpError> {
+        let ref mut path = req.path;
+        if path.len() > 1 && path.last().unwrap().len() == 0 {
+            path.pop();
+        }
 
-		Ok(())
-	}
+        Ok(())
+    }
+}
 
-	// fn catch(&self, _: &mut Request, err: IronError) -> IronResult<()> {
-	// 	Err(err)
-	// }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::PathFixer;
+    use super::super::BeforeMiddleware;
+    use super::super::super::http::{ Request, Method };
+
+    #[test]
+    fn drops_trailing_slash(){
+        let mut req = Request::new(Method::Get, "/metrics/find/?query=*", b"");
+        PathFixer.before(&mut req).unwrap();
+        assert_eq!(req.path, vec!["metrics", "find"]);
+    }
+}
@@ -1,19 +1,21 @@
-extern crate iron;
-extern crate router;
-extern crate urlencoded;
-extern crate glob;
-extern crate persistent;
-// extern crate bodyparser;
-
 mod config;
 pub mod server;
 mod error;
 pub mod expander;
+mod finder;
+pub mod metric_index;
+pub mod series;
+mod functions;
+pub mod time_spec;
+mod render_format;
+mod http;
 mod middleware;
 mod handlers;
 mod cache_holder;
 
 pub use self::config::Config;
+pub use self::cache_holder::CacheHolder;
+pub use self::metric_index::MetricIndex;
 
 // Query root namespace
 // curl 'http://10.69.8.54/graphite-web/metrics/find/?query=*' -H 'Pragma: no-cache' -H 'Origin: http://10.69.8.55' -H 'Accept-Encoding: gzip, deflate, sdch' -H 'Accept-Language: en-US,en;q=0.8' -H 'User-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10_10_4) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/43.0.2357.134 Safari/537.36' -H 'Accept: application/json, text/plain, */*' -H 'Referer: http://10.69.8.55/grafana/' -H 'Connection: keep-alive' -H 'Cache-Control: no-cache' --compressed
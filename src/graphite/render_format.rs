@@ -0,0 +1,203 @@
+// The `format=` choices of /render, all written from the same `Series`.
+// Output follows graphite-web byte for byte where it matters to scripts.
+
+use rustc_serialize::json::{ Json, ToJson };
+use byteorder::{ WriteBytesExt, BigEndian };
+use time;
+
+use super::series::Series;
+use super::http::{ Response, TEXT_CONTENT_TYPE, CSV_CONTENT_TYPE, PICKLE_CONTENT_TYPE, MSGPACK_CONTENT_TYPE };
+use pickle::{ self, Value };
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    Json,
+    Raw,
+    Csv,
+    Pickle,
+    Msgpack
+}
+
+impl Format {
+    pub fn from_str(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "raw" => Some(Format::Raw),
+            "csv" => Some(Format::Csv),
+            "pickle" => Some(Format::Pickle),
+            "msgpack" => Some(Format::Msgpack),
+            _ => None
+        }
+    }
+
+    pub fn respond(&self, series: &[Series]) -> Response {
+        match *self {
+            Format::Json => Response::json(Json::Array(series.iter().map(|s| s.to_json()).collect()).to_string()),
+            Format::Raw => Response::with(200, TEXT_CONTENT_TYPE, raw(series).into_bytes()),
+            Format::Csv => Response::with(200, CSV_CONTENT_TYPE, csv(series).into_bytes()),
+            Format::Pickle => Response::with(200, PICKLE_CONTENT_TYPE, pickle::pickle(&series_info(series))),
+            Format::Msgpack => Response::with(200, MSGPACK_CONTENT_TYPE, msgpack(&series_info(series)))
+        }
+    }
+}
+
+// name,start,end,step|v1,v2,None,...
+fn raw(series: &[Series]) -> String {
+    let mut out = String::new();
+    for s in series {
+        let values : Vec<String> = s.values.iter().map(|value| match *value {
+            Some(v) => format!("{:?}", v),
+            None => "None".to_string()
+        }).collect();
+        out.push_str(&format!("{},{},{},{}|{}\n", s.name, s.start, s.end, s.step, values.join(",")));
+    }
+    out
+}
+
+// name,YYYY-MM-DD HH:MM:SS,value with an empty value for gaps, in UTC
+fn csv(series: &[Series]) -> String {
+    let mut out = String::new();
+    for s in series {
+        for (value, timestamp) in s.datapoints() {
+            let tm = time::at_utc(time::Timespec::new(timestamp as i64, 0));
+            let value = value.map(|v| format!("{:?}", v)).unwrap_or(String::new());
+            out.push_str(&format!("{},{},{}\r\n", s.name, tm.strftime("%Y-%m-%d %H:%M:%S").unwrap(), value));
+        }
+    }
+    out
+}
+
+// graphite-web's `[series.getInfo() for series in data]`, shared by pickle and msgpack
+fn series_info(series: &[Series]) -> Value {
+    let string = |s: &str| Value::String(s.to_string());
+    Value::List(series.iter().map(|s| {
+        let values = s.values.iter().map(|value| value.map(Value::Float).unwrap_or(Value::None)).collect();
+        Value::Dict(vec![
+            (string("name"), string(&s.name)),
+            (string("pathExpression"), string(&s.name)),
+            (string("start"), Value::Int(s.start as i64)),
+            (string("end"), Value::Int(s.end as i64)),
+            (string("step"), Value::Int(s.step as i64)),
+            (string("values"), Value::List(values))
+        ])
+    }).collect())
+}
+
+fn msgpack(value: &Value) -> Vec<u8> {
+    let mut out = vec![];
+    write_msgpack(&mut out, value);
+    out
+}
+
+fn write_msgpack(out: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::None => out.push(0xc0),
+        Value::Bool(b) => out.push(if b { 0xc3 } else { 0xc2 }),
+        Value::Int(i) if i >= 0 && i < 0x80 => out.push(i as u8),
+        Value::Int(i) if i >= 0 && i <= 0xffffffff => {
+            out.push(0xce);
+            out.write_u32::<BigEndian>(i as u32).unwrap();
+        },
+        Value::Int(i) => {
+            out.push(0xd3);
+            out.write_i64::<BigEndian>(i).unwrap();
+        },
+        Value::Float(f) => {
+            out.push(0xcb);
+            out.write_f64::<BigEndian>(f).unwrap();
+        },
+        Value::String(ref string) => {
+            let len = string.len();
+            if len < 32 {
+                out.push(0xa0 | len as u8);
+            } else if len <= 0xff {
+                out.push(0xd9);
+                out.push(len as u8);
+            } else if len <= 0xffff {
+                out.push(0xda);
+                out.write_u16::<BigEndian>(len as u16).unwrap();
+            } else {
+                out.push(0xdb);
+                out.write_u32::<BigEndian>(len as u32).unwrap();
+            }
+            out.extend(string.as_bytes());
+        },
+        Value::Tuple(ref items) | Value::List(ref items) => {
+            write_msgpack_len(out, items.len(), 0x90, 0xdc);
+            for item in items {
+                write_msgpack(out, item);
+            }
+        },
+        Value::Dict(ref pairs) => {
+            write_msgpack_len(out, pairs.len(), 0x80, 0xde);
+            for &(ref key, ref value) in pairs {
+                write_msgpack(out, key);
+                write_msgpack(out, value);
+            }
+        }
+    }
+}
+
+// fix, 16 and 32 bit length headers for arrays and maps
+fn write_msgpack_len(out: &mut Vec<u8>, len: usize, fix: u8, marker16: u8) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if len <= 0xffff {
+        out.push(marker16);
+        out.write_u16::<BigEndian>(len as u16).unwrap();
+    } else {
+        out.push(marker16 + 1);
+        out.write_u32::<BigEndian>(len as u32).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+    use super::super::series::Series;
+    use pickle::{ unpickle, Value };
+
+    fn series() -> Vec<Series> {
+        vec![ Series::new("a.b".to_string(), 1437548400, 1437548520, 60, vec![Some(1.5), None]) ]
+    }
+
+    #[test]
+    fn raw_and_csv(){
+        let raw = Format::Raw.respond(&series());
+        assert_eq!(String::from_utf8(raw.body).unwrap(), "a.b,1437548400,1437548520,60|1.5,None\n");
+
+        let csv = Format::Csv.respond(&series());
+        assert_eq!(csv.content_type, "text/csv; charset=utf-8");
+        assert_eq!(String::from_utf8(csv.body).unwrap(), "a.b,2015-07-22 07:00:00,1.5\r\na.b,2015-07-22 07:01:00,\r\n");
+    }
+
+    #[test]
+    fn pickle_series_info(){
+        let response = Format::Pickle.respond(&series());
+        assert_eq!(response.content_type, "application/pickle");
+        match unpickle(&response.body).unwrap() {
+            Value::List(infos) => match infos[0] {
+                Value::Dict(ref pairs) => {
+                    assert!(pairs.contains(&(Value::String("step".to_string()), Value::Int(60))));
+                    assert!(pairs.contains(&(Value::String("values".to_string()), Value::List(vec![Value::Float(1.5), Value::None]))));
+                },
+                ref other => panic!("expected a dict, got {:?}", other)
+            },
+            other => panic!("expected a list, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn msgpack_series_info(){
+        let body = Format::Msgpack.respond(&series()).body;
+        // [ {6 entries, first "name": "a.b"
+        assert_eq!(&body[..11], b"\x91\x86\xa4name\xa3a.b");
+        assert!(body.ends_with(b"\xa6values\x92\xcb\x3f\xf8\x00\x00\x00\x00\x00\x00\xc0"));
+    }
+
+    #[test]
+    fn unknown_formats(){
+        assert_eq!(Format::from_str("raw"), Some(Format::Raw));
+        assert_eq!(Format::from_str("png"), None);
+    }
+}
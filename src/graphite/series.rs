@@ -0,0 +1,182 @@
+use rustc_serialize::json::{ Json, ToJson };
+use std::collections::BTreeMap;
+
+// How several slots become one when a series is drawn with fewer points
+// than it has, picked with `consolidateBy()`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Consolidation {
+    Average,
+    Sum,
+    Min,
+    Max,
+    Last
+}
+
+impl Consolidation {
+    pub fn from_str(name: &str) -> Option<Consolidation> {
+        match name {
+            "average" | "avg" => Some(Consolidation::Average),
+            "sum" => Some(Consolidation::Sum),
+            "min" => Some(Consolidation::Min),
+            "max" => Some(Consolidation::Max),
+            "last" => Some(Consolidation::Last),
+            _ => None
+        }
+    }
+
+    // `present` is never empty, a bucket with nothing in it stays `None`
+    fn apply(&self, present: &[f64]) -> f64 {
+        match *self {
+            Consolidation::Average => present.iter().fold(0.0, |sum, v| sum + v) / present.len() as f64,
+            Consolidation::Sum => present.iter().fold(0.0, |sum, v| sum + v),
+            Consolidation::Min => present.iter().fold(::std::f64::INFINITY, |min, v| min.min(*v)),
+            Consolidation::Max => present.iter().fold(::std::f64::NEG_INFINITY, |max, v| max.max(*v)),
+            Consolidation::Last => present[present.len() - 1]
+        }
+    }
+}
+
+// One line on a graph: evenly spaced slots from `start` (inclusive)
+// to `end` (exclusive), `step` seconds apart.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Series {
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+    pub step: u32,
+    pub values: Vec<Option<f64>>,
+    pub consolidation: Consolidation
+}
+
+impl Series {
+    pub fn new(name: String, start: u32, end: u32, step: u32, values: Vec<Option<f64>>) -> Series {
+        Series {
+            name: name,
+            start: start,
+            end: end,
+            step: step,
+            values: values,
+            consolidation: Consolidation::Average
+        }
+    }
+
+    // Same slots and consolidation, new name and values. What most
+    // functions hand back.
+    pub fn with_values(&self, name: String, values: Vec<Option<f64>>) -> Series {
+        Series {
+            name: name,
+            start: self.start,
+            end: self.end,
+            step: self.step,
+            values: values,
+            consolidation: self.consolidation
+        }
+    }
+
+    // (value, timestamp) pairs, the order graphite-web puts them in
+    pub fn datapoints(&self) -> Vec<(Option<f64>, u32)> {
+        self.values.iter().enumerate().map(|(i, value)| {
+            (*value, self.start + self.step * i as u32)
+        }).collect()
+    }
+
+    // Folds every `values_per_point` slots into one with the series'
+    // consolidation function, ignoring gaps
+    pub fn consolidate(self, values_per_point: usize) -> Series {
+        if values_per_point <= 1 {
+            return self;
+        }
+
+        let consolidation = self.consolidation;
+        let values : Vec<Option<f64>> = self.values.chunks(values_per_point).map(|chunk| {
+            let present : Vec<f64> = chunk.iter().filter_map(|v| *v).collect();
+            if present.len() == 0 { None } else { Some(consolidation.apply(&present)) }
+        }).collect();
+
+        let step = self.step * values_per_point as u32;
+        let end = self.start + step * values.len() as u32;
+        Series {
+            name: self.name,
+            start: self.start,
+            end: end,
+            step: step,
+            values: values,
+            consolidation: consolidation
+        }
+    }
+
+    // Consolidates down to at most `max_data_points` (Grafana's
+    // `maxDataPoints`). Like graphite, the buckets start on a multiple of
+    // the new step so they don't shift around as the graph refreshes; the
+    // partial bucket in front is dropped.
+    pub fn fit_to(mut self, max_data_points: usize) -> Series {
+        let len = self.values.len();
+        if len <= max_data_points {
+            return self;
+        }
+
+        let values_per_point = (len + max_data_points - 1) / max_data_points;
+        let seconds_per_point = self.step * values_per_point as u32;
+        let misalignment = self.start % seconds_per_point;
+        if misalignment != 0 {
+            let to_drop = ::std::cmp::min(len, ((seconds_per_point - misalignment) / self.step) as usize);
+            self.values.drain(0..to_drop);
+            self.start += to_drop as u32 * self.step;
+        }
+
+        self.consolidate(values_per_point)
+    }
+}
+
+impl ToJson for Series {
+    fn to_json(&self) -> Json {
+        let datapoints = self.datapoints().into_iter().map(|(value, timestamp)| {
+            let json_value = match value {
+                Some(v) if v.is_finite() => Json::F64(v),
+                _ => Json::Null
+            };
+            Json::Array(vec![json_value, Json::U64(timestamp as u64)])
+        }).collect();
+
+        let mut object = BTreeMap::new();
+        object.insert("target".to_string(), Json::String(self.name.clone()));
+        object.insert("datapoints".to_string(), Json::Array(datapoints));
+        Json::Object(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::ToJson;
+    use super::{ Series, Consolidation };
+
+    #[test]
+    fn json_shape(){
+        let series = Series::new("entries".to_string(), 1311836008, 1311836011, 1, vec![Some(1.0), None, Some(3.5)]);
+        let expected = r#"{"datapoints":[[1.0,1311836008],[null,1311836009],[3.5,1311836010]],"target":"entries"}"#;
+        assert_eq!(series.to_json().to_string(), expected);
+    }
+
+    #[test]
+    fn consolidate_averages(){
+        let series = Series::new("a".to_string(), 0, 50, 10, vec![Some(1.0), Some(3.0), None, None, Some(5.0)]);
+        let consolidated = series.consolidate(2);
+        assert_eq!(consolidated.step, 20);
+        assert_eq!(consolidated.end, 60);
+        assert_eq!(consolidated.values, vec![Some(2.0), None, Some(5.0)]);
+    }
+
+    #[test]
+    fn fit_to_max_data_points(){
+        let values = (0..10).map(|v| Some(v as f64)).collect();
+        let mut series = Series::new("a".to_string(), 10, 110, 10, values);
+        series.consolidation = Consolidation::Sum;
+
+        // 10 slots into 4 points means 3 per point, aligned to 30s
+        let fitted = series.clone().fit_to(4);
+        assert_eq!((fitted.start, fitted.step), (30, 30));
+        assert_eq!(fitted.values, vec![Some(2.0 + 3.0 + 4.0), Some(5.0 + 6.0 + 7.0), Some(8.0 + 9.0)]);
+
+        assert_eq!(series.clone().fit_to(10), series);
+    }
+}
@@ -1,23 +1,88 @@
 use super::config::Config;
-use super::middleware::PathFixer;
+use super::middleware::{ BeforeMiddleware, PathFixer };
+use super::http::{ self, Request, HttpError, HttpResult };
 
-use iron::prelude::*;
-use persistent::State;
+use tiny_http;
 
-use router::Router;
+use std::sync::Arc;
+use std::thread::{ self, JoinHandle };
 
-use super::super::whisper::Cache;
 use super::handlers;
 use super::cache_holder::CacheHolder;
 
-pub fn run(config: Config, cache: Cache) {
-    let mut router = Router::new();
-    router.get("/metrics/find", handlers::metrics_find);
-    router.post("/render", handlers::render);
+pub fn run(config: Config, cache: CacheHolder) {
+    for worker in spawn(config, cache) {
+        worker.join().unwrap();
+    }
+}
+
+// Binds and hands back the worker threads so callers (like carbon) can
+// run the HTTP server alongside other things.
+pub fn spawn(config: Config, cache: CacheHolder) -> Vec<JoinHandle<()>> {
+    info!("graphite server binding to `{}`", config.bind_spec);
+    let server = Arc::new( tiny_http::Server::http(config.bind_spec).unwrap() );
+    let cache = Arc::new(cache);
+
+    (0..config.worker_threads).map(|_| {
+        let server = server.clone();
+        let cache = cache.clone();
+        thread::spawn(move || {
+            loop {
+                match server.recv() {
+                    Ok(request) => handle(request, &cache),
+                    Err(err) => {
+                        error!("could not receive http request: {:?}", err);
+                        return;
+                    }
+                }
+            }
+        })
+    }).collect()
+}
+
+fn handle(mut raw_request: tiny_http::Request, cache: &CacheHolder) {
+    let method = match *raw_request.method() {
+        tiny_http::Method::Get => http::Method::Get,
+        tiny_http::Method::Post => http::Method::Post,
+        _ => http::Method::Other
+    };
+
+    let mut body = vec![];
+    if method == http::Method::Post {
+        if let Err(err) = raw_request.as_reader().read_to_end(&mut body) {
+            info!("could not read request body: {:?}", err);
+        }
+    }
+
+    let mut req = Request::new(method, raw_request.url(), &body);
+    debug!("{:?} {:?}", req.method, req.path);
+
+    let response = match route(&mut req, cache) {
+        Ok(response) => response,
+        Err(err) => {
+            info!("{} for {:?}: {}", err.status, req.path, err.error.0);
+            err.to_response()
+        }
+    };
+
+    let content_type = tiny_http::Header::from_bytes(&b"Content-Type"[..], response.content_type.as_bytes()).unwrap();
+    let raw_response = tiny_http::Response::from_data(response.body)
+                                           .with_status_code(response.status)
+                                           .with_header(content_type);
+
+    if let Err(err) = raw_request.respond(raw_response) {
+        info!("could not write http response: {:?}", err);
+    }
+}
 
-    let mut chain = Chain::new(router);
-    chain.link_before(PathFixer);
-    chain.link( State::<CacheHolder>::both(cache) );
+fn route(req: &mut Request, cache: &CacheHolder) -> HttpResult {
+    try!( PathFixer.before(req) );
 
-    Iron::new(chain).http(config.bind_spec).unwrap(); 
+    let path = req.path.join("/");
+    match (req.method, &path[..]) {
+        (http::Method::Get, "metrics/find") => handlers::metrics_find(req, cache),
+        (http::Method::Get, "render") |
+        (http::Method::Post, "render") => handlers::render(req, cache),
+        _ => Err(HttpError::not_found())
+    }
 }
@@ -0,0 +1,216 @@
+// graphite's `from`/`until` formats, turned into epoch seconds. Like
+// graphite-web's attime.py a spec is an optional reference point followed by
+// an optional offset: `-6h`, `now`, `yesterday+12h`, `00:00_20150722`,
+// `20150722`, `1437548400`. Everything is UTC.
+
+const MINUTE : i64 = 60;
+const HOUR : i64 = 60 * MINUTE;
+const DAY : i64 = 24 * HOUR;
+
+pub fn parse(spec: &str, now: u32) -> Result<u32, String> {
+    let spec = spec.trim();
+    let split = spec.find(|c: char| c == '+' || c == '-').unwrap_or(spec.len());
+    let (reference, offset) = spec.split_at(split);
+
+    let base = try!( parse_reference(reference, now as i64).map_err(|reason| format!("Bad time `{}`: {}", spec, reason)) );
+    let offset = if offset.len() > 0 {
+        try!( parse_offset(offset).map_err(|reason| format!("Bad time `{}`: {}", spec, reason)) )
+    } else {
+        0
+    };
+
+    match base.checked_add(offset) {
+        Some(time) if time >= 0 && time <= ::std::u32::MAX as i64 => Ok(time as u32),
+        _ => Err(format!("Bad time `{}`: out of range", spec))
+    }
+}
+
+// A length of time such as `5min` or `1d`, as taken by movingAverage
+pub fn parse_interval(spec: &str) -> Result<u32, String> {
+    let spec = spec.trim();
+    let seconds = try!( parse_count_and_unit(spec).map_err(|reason| format!("Bad interval `{}`: {}", spec, reason)) );
+    if seconds <= 0 || seconds > ::std::u32::MAX as i64 {
+        return Err(format!("Bad interval `{}`: out of range", spec));
+    }
+    Ok(seconds as u32)
+}
+
+fn parse_reference(reference: &str, now: i64) -> Result<i64, String> {
+    let midnight = now - now % DAY;
+    match reference {
+        "" | "now" => return Ok(now),
+        "today" | "midnight" => return Ok(midnight),
+        "yesterday" => return Ok(midnight - DAY),
+        "tomorrow" => return Ok(midnight + DAY),
+        "noon" => return Ok(midnight + 12 * HOUR),
+        _ => ()
+    }
+
+    if reference.chars().all(|c| c.is_digit(10)) {
+        // Same rule as graphite: eight digits that make a date are a date
+        if reference.len() == 8 {
+            if let Ok(date) = parse_date(reference) {
+                return Ok(date);
+            }
+        }
+        return reference.parse::<i64>().map_err(|_| "epoch is too large".to_string());
+    }
+
+    let (clock, date) = match reference.find('_') {
+        Some(pos) => (&reference[..pos], Some(&reference[pos+1..])),
+        None => (reference, None)
+    };
+    let day = match date {
+        Some(date) => try!( parse_date(date) ),
+        None => midnight
+    };
+    Ok(day + try!( parse_clock(clock) ))
+}
+
+// HH:MM
+fn parse_clock(clock: &str) -> Result<i64, String> {
+    let parts : Vec<&str> = clock.split(':').collect();
+    if parts.len() != 2 {
+        return Err(format!("expected HH:MM, got `{}`", clock));
+    }
+    let hours = try!( parts[0].parse::<i64>().map_err(|_| format!("bad hour `{}`", parts[0])) );
+    let minutes = try!( parts[1].parse::<i64>().map_err(|_| format!("bad minute `{}`", parts[1])) );
+    if hours > 23 || minutes > 59 {
+        return Err(format!("no such time of day `{}`", clock));
+    }
+    Ok(hours * HOUR + minutes * MINUTE)
+}
+
+// YYYYMMDD at midnight
+fn parse_date(date: &str) -> Result<i64, String> {
+    if date.len() != 8 || !date.chars().all(|c| c.is_digit(10)) {
+        return Err(format!("expected YYYYMMDD, got `{}`", date));
+    }
+    let year = date[0..4].parse::<i64>().unwrap();
+    let month = date[4..6].parse::<i64>().unwrap();
+    let day = date[6..8].parse::<i64>().unwrap();
+    if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+        return Err(format!("no such date `{}`", date));
+    }
+    Ok(days_from_civil(year, month, day) * DAY)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// +N<unit> or -N<unit>
+fn parse_offset(offset: &str) -> Result<i64, String> {
+    let (sign, rest) = offset.split_at(1);
+    let seconds = try!( parse_count_and_unit(rest) );
+    Ok(if sign == "-" { -seconds } else { seconds })
+}
+
+fn parse_count_and_unit(spec: &str) -> Result<i64, String> {
+    let digits_end = spec.find(|c: char| !c.is_digit(10)).unwrap_or(spec.len());
+    let (digits, unit) = spec.split_at(digits_end);
+    let count = try!( digits.parse::<i64>().map_err(|_| format!("expected a number before `{}`", unit)) );
+    let unit_seconds = try!( unit_seconds(unit.trim()) );
+    count.checked_mul(unit_seconds).ok_or_else(|| "out of range".to_string())
+}
+
+// The unit prefixes graphite accepts. A lone `m` could be minutes or
+// months, so like graphite we refuse to guess.
+fn unit_seconds(unit: &str) -> Result<i64, String> {
+    if unit.starts_with("s") {
+        Ok(1)
+    } else if unit.starts_with("min") {
+        Ok(MINUTE)
+    } else if unit.starts_with("h") {
+        Ok(HOUR)
+    } else if unit.starts_with("d") {
+        Ok(DAY)
+    } else if unit.starts_with("w") {
+        Ok(7 * DAY)
+    } else if unit.starts_with("mon") {
+        Ok(30 * DAY)
+    } else if unit.starts_with("y") {
+        Ok(365 * DAY)
+    } else if unit == "m" {
+        Err("`m` is ambiguous, use `min` or `mon`".to_string())
+    } else {
+        Err(format!("unknown unit `{}`", unit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ parse, parse_interval };
+
+    // 2015-07-22 07:00:00 UTC
+    const NOW : u32 = 1437548400;
+    const MIDNIGHT : u32 = 1437523200;
+
+    #[test]
+    fn now_and_epochs(){
+        assert_eq!(parse("now", NOW).unwrap(), NOW);
+        assert_eq!(parse("123", NOW).unwrap(), 123);
+        assert_eq!(parse(" 1437548400 ", NOW).unwrap(), NOW);
+    }
+
+    #[test]
+    fn relative_offsets(){
+        assert_eq!(parse("-5min", 1000).unwrap(), 700);
+        assert_eq!(parse("-6h", NOW).unwrap(), NOW - 6*60*60);
+        assert_eq!(parse("-7d", NOW).unwrap(), NOW - 7*24*60*60);
+        assert_eq!(parse("-1mon", NOW).unwrap(), NOW - 30*24*60*60);
+        assert_eq!(parse("-2weeks", NOW).unwrap(), NOW - 14*24*60*60);
+        assert_eq!(parse("now-30s", NOW).unwrap(), NOW - 30);
+        assert_eq!(parse("+1y", NOW).unwrap(), NOW + 365*24*60*60);
+    }
+
+    #[test]
+    fn absolute_dates(){
+        assert_eq!(parse("00:00_20150722", NOW).unwrap(), MIDNIGHT);
+        assert_eq!(parse("07:00_20150722", NOW).unwrap(), NOW);
+        assert_eq!(parse("20150722", NOW).unwrap(), MIDNIGHT);
+        assert_eq!(parse("00:00_19700101", NOW).unwrap(), 0);
+        assert_eq!(parse("12:30_20000229", NOW).unwrap(), 951827400);
+        assert_eq!(parse("today", NOW).unwrap(), MIDNIGHT);
+        assert_eq!(parse("yesterday", NOW).unwrap(), MIDNIGHT - 24*60*60);
+        assert_eq!(parse("yesterday+12h", NOW).unwrap(), MIDNIGHT - 12*60*60);
+        assert_eq!(parse("13:15", NOW).unwrap(), MIDNIGHT + 13*60*60 + 15*60);
+    }
+
+    #[test]
+    fn clear_errors(){
+        assert!(parse("-5m", NOW).unwrap_err().contains("ambiguous"));
+        assert!(parse("-h", NOW).is_err());
+        assert!(parse("-5fortnights", NOW).is_err());
+        assert!(parse("25:00_20150722", NOW).is_err());
+        assert!(parse("00:00_20150230", NOW).is_err());
+        assert!(parse("last tuesday", NOW).is_err());
+        assert!(parse("-100y", NOW).unwrap_err().contains("out of range"));
+        assert!(parse("-9223372036854775807y", NOW).unwrap_err().contains("out of range"));
+        assert!(parse("9223372036854775807+1s", NOW).unwrap_err().contains("out of range"));
+    }
+
+    #[test]
+    fn intervals(){
+        assert_eq!(parse_interval("5min").unwrap(), 300);
+        assert_eq!(parse_interval("1d").unwrap(), 86400);
+        assert!(parse_interval("5").is_err());
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("9223372036854775807w").unwrap_err().starts_with("Bad interval"));
+    }
+}
@@ -26,7 +26,7 @@ Status of the codes:
  * `carbon`
   * *DOESN'T DO ANYTHING*
  * `graphite`
-  * *DOESN'T EXIST*
+  * *DOES* answer `/metrics/find` and `/render` (JSON) from the whisper files on disk
 
 ## Also, this is brand-new code. In the true rust spirit it does not guarantee the safety of your kittens.
 
@@ -40,15 +40,29 @@ extern crate test;
 
 extern crate time;
 extern crate byteorder;
+extern crate libc;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
 extern crate regex;
+extern crate rustc_serialize;
 
 extern crate whisper;
+extern crate lru_cache;
+
+extern crate tiny_http;
+extern crate url;
+extern crate md5;
+
+#[cfg(feature = "tls")]
+extern crate openssl;
 
 pub mod carbon;
-// TODO: scuttled until I want to fix all the iron related issues
-// pub mod graphite; 
+pub mod pickle;
+pub mod whisper_io;
+pub mod graphite;
+
+#[cfg(test)]
+mod test_support;
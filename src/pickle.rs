@@ -0,0 +1,663 @@
+/*!
+
+A tiny, safe subset of Python's pickle format.
+
+carbon-relay and friends ship batches of `(path, (timestamp, value))` tuples
+as pickles. We only need the opcodes that describe plain data: numbers, strings,
+tuples, lists and dicts. Anything that would instantiate a Python class
+(`GLOBAL`, `REDUCE`, `BUILD`, ...) is rejected, same as carbon's `SafeUnpickler`.
+
+Going the other way, `pickle` writes the same kinds of values as protocol 2
+for graphite-web's `format=pickle` clients.
+
+*/
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{ Cursor, Read };
+use std::mem;
+use std::str;
+
+use byteorder::{ ReadBytesExt, WriteBytesExt, LittleEndian, BigEndian };
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Dict(Vec<(Value, Value)>)
+}
+
+impl Value {
+    // Python's `float()` is happy with ints, floats and numeric strings.
+    // carbon calls it on both halves of every datapoint so we do too.
+    pub fn to_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Int(i) => Some(i as f64),
+            Value::Float(f) => Some(f),
+            Value::Bool(b) => Some(if b { 1.0 } else { 0.0 }),
+            Value::String(ref s) => s.trim().parse::<f64>().ok(),
+            _ => None
+        }
+    }
+}
+
+// Bounds on what one pickle decodes to. Memo GETs copy values, so without
+// them a few hundred bytes could ask for more memory than there is, and a
+// deeply nested list would overflow the stack when it's dropped.
+const MAX_DECODED_BYTES : usize = 64 * 1024 * 1024;
+const MAX_DEPTH : usize = 64;
+
+// Roughly how much memory a value takes, and how deeply it nests
+#[derive(Debug, Clone, Copy)]
+struct Size {
+    bytes: usize,
+    depth: usize
+}
+
+impl Size {
+    fn of_scalar(value: &Value) -> Size {
+        let len = match *value {
+            Value::String(ref s) => s.len(),
+            _ => 0
+        };
+        Size { bytes: mem::size_of::<Value>() + len, depth: 1 }
+    }
+
+    fn add_item(&mut self, item: Size) {
+        self.bytes += item.bytes;
+        self.depth = cmp::max(self.depth, item.depth + 1);
+    }
+}
+
+// Things that live on the unpickler stack. `Mark` never escapes `unpickle`.
+#[derive(Debug, Clone)]
+enum Item {
+    Mark,
+    Value(Value, Size)
+}
+
+pub fn unpickle(data: &[u8]) -> Result<Value, String> {
+    let mut cursor = Cursor::new(data);
+    let mut stack : Vec<Item> = vec![];
+    let mut memo : HashMap<u32, (Value, Size)> = HashMap::new();
+    // Bytes of values made so far, copies included
+    let mut decoded = 0;
+
+    loop {
+        let opcode = try!( read_u8(&mut cursor) );
+
+        match opcode {
+            // PROTO
+            0x80 => { try!( read_u8(&mut cursor) ); },
+            // FRAME
+            0x95 => { try!( read_bytes(&mut cursor, 8) ); },
+            // STOP
+            b'.' => {
+                return match stack.pop() {
+                    Some(Item::Value(value, _)) => Ok(value),
+                    _ => Err("pickle stopped without a value".to_string())
+                }
+            },
+            // MARK
+            b'(' => stack.push(Item::Mark),
+            // POP
+            b'0' => { try!( pop_value(&mut stack) ); },
+            // POP_MARK
+            b'1' => { try!( pop_mark(&mut stack) ); },
+            // DUP
+            b'2' => {
+                let (top, size) = try!( peek_value(&stack) );
+                try!( count(&mut decoded, size.bytes) );
+                stack.push(Item::Value(top, size));
+            },
+
+            // NONE, NEWTRUE, NEWFALSE
+            b'N' => try!( push_scalar(&mut stack, &mut decoded, Value::None) ),
+            0x88 => try!( push_scalar(&mut stack, &mut decoded, Value::Bool(true)) ),
+            0x89 => try!( push_scalar(&mut stack, &mut decoded, Value::Bool(false)) ),
+
+            // INT, LONG (text protocol)
+            b'I' | b'L' => {
+                let line = try!( read_line(&mut cursor) );
+                let value = try!( parse_text_int(&line) );
+                try!( push_scalar(&mut stack, &mut decoded, value) );
+            },
+            // BININT, BININT1, BININT2
+            b'J' => {
+                let i = try!( cursor.read_i32::<LittleEndian>().map_err(truncated) );
+                try!( push_scalar(&mut stack, &mut decoded, Value::Int(i as i64)) );
+            },
+            b'K' => {
+                let i = try!( read_u8(&mut cursor) );
+                try!( push_scalar(&mut stack, &mut decoded, Value::Int(i as i64)) );
+            },
+            b'M' => {
+                let i = try!( cursor.read_u16::<LittleEndian>().map_err(truncated) );
+                try!( push_scalar(&mut stack, &mut decoded, Value::Int(i as i64)) );
+            },
+            // LONG1
+            0x8a => {
+                let len = try!( read_u8(&mut cursor) ) as usize;
+                let bytes = try!( read_bytes(&mut cursor, len) );
+                try!( push_scalar(&mut stack, &mut decoded, try!( decode_long(&bytes) )) );
+            },
+            // FLOAT, BINFLOAT
+            b'F' => {
+                let line = try!( read_line(&mut cursor) );
+                match line.trim().parse::<f64>() {
+                    Ok(f) => try!( push_scalar(&mut stack, &mut decoded, Value::Float(f)) ),
+                    Err(_) => return Err(format!("bad float `{}`", line))
+                }
+            },
+            b'G' => {
+                let f = try!( cursor.read_f64::<BigEndian>().map_err(truncated) );
+                try!( push_scalar(&mut stack, &mut decoded, Value::Float(f)) );
+            },
+
+            // STRING, UNICODE (text protocol)
+            b'S' => {
+                let line = try!( read_line(&mut cursor) );
+                let unquoted = line.trim_matches(|c| c == '\'' || c == '"').to_string();
+                try!( push_scalar(&mut stack, &mut decoded, Value::String(unquoted)) );
+            },
+            b'V' => {
+                let line = try!( read_line(&mut cursor) );
+                try!( push_scalar(&mut stack, &mut decoded, Value::String(line)) );
+            },
+            // SHORT_BINSTRING, SHORT_BINUNICODE, SHORT_BINBYTES
+            b'U' | 0x8c | b'C' => {
+                let len = try!( read_u8(&mut cursor) ) as usize;
+                let bytes = try!( read_bytes(&mut cursor, len) );
+                try!( push_scalar(&mut stack, &mut decoded, Value::String(try!( decode_str(&bytes) ))) );
+            },
+            // BINSTRING, BINUNICODE, BINBYTES
+            b'T' | b'X' | b'B' => {
+                let len = try!( cursor.read_u32::<LittleEndian>().map_err(truncated) ) as usize;
+                let bytes = try!( read_bytes(&mut cursor, len) );
+                try!( push_scalar(&mut stack, &mut decoded, Value::String(try!( decode_str(&bytes) ))) );
+            },
+
+            // EMPTY_TUPLE, TUPLE, TUPLE1, TUPLE2, TUPLE3
+            b')' => try!( push_container(&mut stack, &mut decoded, vec![], Value::Tuple) ),
+            b't' => {
+                let items = try!( pop_mark(&mut stack) );
+                try!( push_container(&mut stack, &mut decoded, items, Value::Tuple) );
+            },
+            0x85 | 0x86 | 0x87 => {
+                let count = (opcode - 0x84) as usize;
+                let mut items = Vec::with_capacity(count);
+                for _ in 0..count {
+                    items.push(try!( pop_value(&mut stack) ));
+                }
+                items.reverse();
+                try!( push_container(&mut stack, &mut decoded, items, Value::Tuple) );
+            },
+
+            // EMPTY_LIST, LIST, APPEND, APPENDS
+            b']' => try!( push_container(&mut stack, &mut decoded, vec![], Value::List) ),
+            b'l' => {
+                let items = try!( pop_mark(&mut stack) );
+                try!( push_container(&mut stack, &mut decoded, items, Value::List) );
+            },
+            b'a' => {
+                let item = try!( pop_value(&mut stack) );
+                try!( extend_list(&mut stack, vec![item]) );
+            },
+            b'e' => {
+                let items = try!( pop_mark(&mut stack) );
+                try!( extend_list(&mut stack, items) );
+            },
+
+            // EMPTY_DICT, DICT, SETITEM, SETITEMS
+            b'}' => try!( push_container(&mut stack, &mut decoded, vec![], |_| Value::Dict(vec![])) ),
+            b'd' => {
+                let items = try!( pop_mark(&mut stack) );
+                let (values, size) = try!( container_size(items) );
+                try!( count(&mut decoded, mem::size_of::<Value>()) );
+                stack.push(Item::Value(Value::Dict(try!( pair_up(values) )), size));
+            },
+            b's' => {
+                let value = try!( pop_value(&mut stack) );
+                let key = try!( pop_value(&mut stack) );
+                try!( extend_dict(&mut stack, vec![key, value]) );
+            },
+            b'u' => {
+                let items = try!( pop_mark(&mut stack) );
+                try!( extend_dict(&mut stack, items) );
+            },
+
+            // PUT, BINPUT, LONG_BINPUT, MEMOIZE
+            b'p' | b'q' | b'r' | 0x94 => {
+                let index = match opcode {
+                    b'p' => {
+                        let line = try!( read_line(&mut cursor) );
+                        try!( parse_memo_index(&line) )
+                    },
+                    b'q' => try!( read_u8(&mut cursor) ) as u32,
+                    b'r' => try!( cursor.read_u32::<LittleEndian>().map_err(truncated) ),
+                    _ => memo.len() as u32
+                };
+                let (value, size) = try!( peek_value(&stack) );
+                try!( count(&mut decoded, size.bytes) );
+                memo.insert(index, (value, size));
+            },
+            // GET, BINGET, LONG_BINGET
+            b'g' | b'h' | b'j' => {
+                let index = match opcode {
+                    b'g' => {
+                        let line = try!( read_line(&mut cursor) );
+                        try!( parse_memo_index(&line) )
+                    },
+                    b'h' => try!( read_u8(&mut cursor) ) as u32,
+                    _ => try!( cursor.read_u32::<LittleEndian>().map_err(truncated) )
+                };
+                let (value, size) = match memo.get(&index) {
+                    Some(&(ref value, size)) => (value, size),
+                    None => return Err(format!("memo index {} was never stored", index))
+                };
+                try!( count(&mut decoded, size.bytes) );
+                stack.push(Item::Value(value.clone(), size));
+            },
+
+            other => {
+                return Err(format!("unsupported pickle opcode 0x{:02x}", other))
+            }
+        }
+    }
+}
+
+pub fn pickle(value: &Value) -> Vec<u8> {
+    let mut out = vec![0x80, 2];
+    write_value(&mut out, value);
+    out.push(b'.');
+    out
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::None => out.push(b'N'),
+        Value::Bool(true) => out.push(0x88),
+        Value::Bool(false) => out.push(0x89),
+        Value::Int(i) if i >= 0 && i <= 0xff => {
+            out.push(b'K');
+            out.push(i as u8);
+        },
+        Value::Int(i) if i >= 0 && i <= 0xffff => {
+            out.push(b'M');
+            out.write_u16::<LittleEndian>(i as u16).unwrap();
+        },
+        Value::Int(i) if i >= ::std::i32::MIN as i64 && i <= ::std::i32::MAX as i64 => {
+            out.push(b'J');
+            out.write_i32::<LittleEndian>(i as i32).unwrap();
+        },
+        Value::Int(i) => {
+            // LONG1: little endian two's complement, 8 bytes is always enough
+            out.push(0x8a);
+            out.push(8);
+            out.write_i64::<LittleEndian>(i).unwrap();
+        },
+        Value::Float(f) => {
+            out.push(b'G');
+            out.write_f64::<BigEndian>(f).unwrap();
+        },
+        Value::String(ref string) => {
+            out.push(b'X');
+            out.write_u32::<LittleEndian>(string.len() as u32).unwrap();
+            out.extend(string.as_bytes());
+        },
+        Value::Tuple(ref items) => {
+            out.push(b'(');
+            for item in items {
+                write_value(out, item);
+            }
+            out.push(b't');
+        },
+        Value::List(ref items) => {
+            out.push(b']');
+            if items.len() > 0 {
+                out.push(b'(');
+                for item in items {
+                    write_value(out, item);
+                }
+                out.push(b'e');
+            }
+        },
+        Value::Dict(ref pairs) => {
+            out.push(b'}');
+            if pairs.len() > 0 {
+                out.push(b'(');
+                for &(ref key, ref value) in pairs {
+                    write_value(out, key);
+                    write_value(out, value);
+                }
+                out.push(b'u');
+            }
+        }
+    }
+}
+
+// Walks a `[(path, (timestamp, value)), ...]` batch and hands back the
+// `(path, timestamp, value)` triples. Malformed entries spoil the batch.
+pub fn datapoints(batch: &Value) -> Result<Vec<(String, u32, f64)>, String> {
+    let entries = match *batch {
+        Value::List(ref entries) | Value::Tuple(ref entries) => entries,
+        _ => return Err("pickle batch is not a list".to_string())
+    };
+
+    let mut points = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let (path, datapoint) = match *entry {
+            Value::Tuple(ref pair) | Value::List(ref pair) if pair.len() == 2 => (&pair[0], &pair[1]),
+            _ => return Err(format!("batch entry is not a (path, datapoint) pair: {:?}", entry))
+        };
+
+        let path = match *path {
+            Value::String(ref s) => s.clone(),
+            _ => return Err(format!("metric path is not a string: {:?}", path))
+        };
+
+        let (timestamp, value) = match *datapoint {
+            Value::Tuple(ref pair) | Value::List(ref pair) if pair.len() == 2 => {
+                match (pair[0].to_f64(), pair[1].to_f64()) {
+                    (Some(ts), Some(val)) => (ts, val),
+                    _ => return Err(format!("datapoint for `{}` is not numeric: {:?}", path, datapoint))
+                }
+            },
+            _ => return Err(format!("datapoint for `{}` is not a pair: {:?}", path, datapoint))
+        };
+
+        if timestamp < 0.0 || timestamp > ::std::u32::MAX as f64 {
+            return Err(format!("timestamp for `{}` is out of range: {}", path, timestamp));
+        }
+
+        points.push((path, timestamp as u32, value));
+    }
+
+    Ok(points)
+}
+
+fn truncated<E>(_: E) -> String {
+    "pickle data ended unexpectedly".to_string()
+}
+
+fn read_u8(cursor: &mut Cursor<&[u8]>) -> Result<u8, String> {
+    cursor.read_u8().map_err(truncated)
+}
+
+fn read_bytes(cursor: &mut Cursor<&[u8]>, len: usize) -> Result<Vec<u8>, String> {
+    // The length comes off the wire, don't allocate more than could be there
+    if len as u64 > cursor.get_ref().len() as u64 - cursor.position() {
+        return Err(truncated(()));
+    }
+    let mut buf = vec![0; len];
+    try!( cursor.read_exact(&mut buf[..]).map_err(truncated) );
+    Ok(buf)
+}
+
+fn read_line(cursor: &mut Cursor<&[u8]>) -> Result<String, String> {
+    let mut bytes = vec![];
+    loop {
+        let byte = try!( read_u8(cursor) );
+        if byte == b'\n' {
+            break;
+        }
+        bytes.push(byte);
+    }
+    decode_str(&bytes)
+}
+
+fn decode_str(bytes: &[u8]) -> Result<String, String> {
+    match str::from_utf8(bytes) {
+        Ok(s) => Ok(s.to_string()),
+        Err(_) => Err("pickled string is not valid utf8".to_string())
+    }
+}
+
+fn parse_text_int(line: &str) -> Result<Value, String> {
+    let trimmed = line.trim().trim_right_matches('L');
+    match trimmed {
+        // protocol 0 spells booleans as ints
+        "00" => Ok(Value::Bool(false)),
+        "01" => Ok(Value::Bool(true)),
+        _ => match trimmed.parse::<i64>() {
+            Ok(i) => Ok(Value::Int(i)),
+            Err(_) => Err(format!("bad int `{}`", line))
+        }
+    }
+}
+
+fn parse_memo_index(line: &str) -> Result<u32, String> {
+    line.trim().parse::<u32>().map_err(|_| format!("bad memo index `{}`", line))
+}
+
+// Little-endian two's complement, as written by LONG1. We only care about
+// values that fit in an i64 (timestamps, mostly).
+fn decode_long(bytes: &[u8]) -> Result<Value, String> {
+    if bytes.len() > 8 {
+        return Err(format!("{} byte long is too large", bytes.len()));
+    }
+    if bytes.len() == 0 {
+        return Ok(Value::Int(0));
+    }
+
+    let mut value : i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (8 * i);
+    }
+    let bits = 8 * bytes.len();
+    if bits < 64 && bytes[bytes.len() - 1] & 0x80 != 0 {
+        value -= 1i64 << bits;
+    }
+    Ok(Value::Int(value))
+}
+
+fn count(decoded: &mut usize, bytes: usize) -> Result<(), String> {
+    *decoded += bytes;
+    if *decoded > MAX_DECODED_BYTES {
+        return Err(format!("pickle decodes to more than {} bytes", MAX_DECODED_BYTES));
+    }
+    Ok(())
+}
+
+fn check_depth(size: Size) -> Result<(), String> {
+    if size.depth > MAX_DEPTH {
+        return Err(format!("pickle nests more than {} deep", MAX_DEPTH));
+    }
+    Ok(())
+}
+
+fn push_scalar(stack: &mut Vec<Item>, decoded: &mut usize, value: Value) -> Result<(), String> {
+    let size = Size::of_scalar(&value);
+    try!( count(decoded, size.bytes) );
+    stack.push(Item::Value(value, size));
+    Ok(())
+}
+
+// Only the container itself is new, its items were counted when they were made
+fn push_container<F>(stack: &mut Vec<Item>, decoded: &mut usize, items: Vec<(Value, Size)>, make: F) -> Result<(), String>
+    where F: FnOnce(Vec<Value>) -> Value
+{
+    let (values, size) = try!( container_size(items) );
+    try!( count(decoded, mem::size_of::<Value>()) );
+    stack.push(Item::Value(make(values), size));
+    Ok(())
+}
+
+fn container_size(items: Vec<(Value, Size)>) -> Result<(Vec<Value>, Size), String> {
+    let mut size = Size { bytes: mem::size_of::<Value>(), depth: 1 };
+    let mut values = Vec::with_capacity(items.len());
+    for (value, item_size) in items {
+        size.add_item(item_size);
+        values.push(value);
+    }
+    try!( check_depth(size) );
+    Ok((values, size))
+}
+
+fn pop_value(stack: &mut Vec<Item>) -> Result<(Value, Size), String> {
+    match stack.pop() {
+        Some(Item::Value(value, size)) => Ok((value, size)),
+        Some(Item::Mark) => Err("found mark where a value was expected".to_string()),
+        None => Err("pickle stack underflow".to_string())
+    }
+}
+
+fn peek_value(stack: &Vec<Item>) -> Result<(Value, Size), String> {
+    match stack.last() {
+        Some(&Item::Value(ref value, size)) => Ok((value.clone(), size)),
+        _ => Err("pickle stack has no value on top".to_string())
+    }
+}
+
+fn pop_mark(stack: &mut Vec<Item>) -> Result<Vec<(Value, Size)>, String> {
+    let mut items = vec![];
+    loop {
+        match stack.pop() {
+            Some(Item::Mark) => break,
+            Some(Item::Value(value, size)) => items.push((value, size)),
+            None => return Err("pickle mark not found".to_string())
+        }
+    }
+    items.reverse();
+    Ok(items)
+}
+
+fn pair_up(items: Vec<Value>) -> Result<Vec<(Value, Value)>, String> {
+    if items.len() % 2 != 0 {
+        return Err("dict has a key without a value".to_string());
+    }
+    let mut pairs = Vec::with_capacity(items.len() / 2);
+    let mut iter = items.into_iter();
+    while let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+fn extend_list(stack: &mut Vec<Item>, items: Vec<(Value, Size)>) -> Result<(), String> {
+    match stack.last_mut() {
+        Some(&mut Item::Value(Value::List(ref mut list), ref mut size)) => {
+            for (item, item_size) in items {
+                size.add_item(item_size);
+                list.push(item);
+            }
+            check_depth(*size)
+        },
+        _ => Err("append target is not a list".to_string())
+    }
+}
+
+// `items` alternate keys and values
+fn extend_dict(stack: &mut Vec<Item>, items: Vec<(Value, Size)>) -> Result<(), String> {
+    match stack.last_mut() {
+        Some(&mut Item::Value(Value::Dict(ref mut dict), ref mut size)) => {
+            let mut values = Vec::with_capacity(items.len());
+            for (item, item_size) in items {
+                size.add_item(item_size);
+                values.push(item);
+            }
+            dict.extend(try!( pair_up(values) ));
+            check_depth(*size)
+        },
+        _ => Err("setitem target is not a dict".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ unpickle, pickle, datapoints, Value };
+
+    // python3 -c "import pickle; print(pickle.dumps([('foo.bar', (1437548400, 1.5)), ('baz', (1437548460, 2))], protocol=0))"
+    const PROTOCOL_0 : &'static [u8] = b"(lp0\n(Vfoo.bar\np1\n(I1437548400\nF1.5\ntp2\ntp3\na(Vbaz\np4\n(I1437548460\nI2\ntp5\ntp6\na.";
+
+    // same batch, protocol=2
+    const PROTOCOL_2 : &'static [u8] = b"\x80\x02]q\x00(X\x07\x00\x00\x00foo.barq\x01Jp?\xafUG?\xf8\x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03X\x03\x00\x00\x00bazq\x04J\xac?\xafUK\x02\x86q\x05\x86q\x06e.";
+
+    fn expected() -> Vec<(String, u32, f64)> {
+        vec![
+            ("foo.bar".to_string(), 1437548400, 1.5),
+            ("baz".to_string(), 1437548460, 2.0)
+        ]
+    }
+
+    #[test]
+    fn protocol_0_batch(){
+        let batch = unpickle(PROTOCOL_0).unwrap();
+        assert_eq!(datapoints(&batch).unwrap(), expected());
+    }
+
+    #[test]
+    fn protocol_2_batch(){
+        let batch = unpickle(PROTOCOL_2).unwrap();
+        assert_eq!(datapoints(&batch).unwrap(), expected());
+    }
+
+    #[test]
+    fn python2_short_binstring(){
+        // cPickle.dumps([('a.b', (10, 0.5))], 2) from carbon-relay on python 2
+        let data = b"\x80\x02]q\x01U\x03a.bq\x02K\nG?\xe0\x00\x00\x00\x00\x00\x00\x86\x86q\x03a.";
+        let batch = unpickle(data).unwrap();
+        assert_eq!(datapoints(&batch).unwrap(), vec![("a.b".to_string(), 10, 0.5)]);
+    }
+
+    #[test]
+    fn rejects_globals(){
+        let data = b"cos\nsystem\n(S'echo hi'\ntR.";
+        assert!(unpickle(data).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_data(){
+        assert!(unpickle(&PROTOCOL_2[..20]).is_err());
+        // BINUNICODE claiming 4GiB
+        assert!(unpickle(b"\x80\x02X\xff\xff\xff\xffab.").is_err());
+    }
+
+    #[test]
+    fn rejects_runaway_copies(){
+        // A list of two copies of the last one, PUT in its place, doubling
+        // every time
+        let mut data = b"\x80\x02]q\x00".to_vec();
+        for _ in 0..40 {
+            data.extend_from_slice(b"](h\x00h\x00eq\x00");
+        }
+        data.push(b'.');
+        assert!(unpickle(&data).is_err());
+
+        // Lists in lists
+        let mut data = b"\x80\x02".to_vec();
+        for _ in 0..100 {
+            data.push(b']');
+        }
+        for _ in 0..99 {
+            data.push(b'a');
+        }
+        data.push(b'.');
+        assert!(unpickle(&data).is_err());
+    }
+
+    #[test]
+    fn pickle_round_trip(){
+        let value = Value::List(vec![
+            Value::Dict(vec![
+                (Value::String("name".to_string()), Value::String("a.b".to_string())),
+                (Value::String("values".to_string()), Value::List(vec![Value::Float(1.5), Value::None, Value::Bool(true)]))
+            ]),
+            Value::Tuple(vec![Value::Int(7), Value::Int(1000), Value::Int(1437548400), Value::Int(-5), Value::Int(1 << 40)]),
+            Value::List(vec![]),
+            Value::Dict(vec![])
+        ]);
+        assert_eq!(unpickle(&pickle(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_non_batches(){
+        let batch = Value::List(vec![Value::Int(1)]);
+        assert!(datapoints(&batch).is_err());
+    }
+}
@@ -0,0 +1,32 @@
+// Helpers shared by the tests
+
+use std::env;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::process;
+
+// A fresh directory under the system temp dir, removed along with
+// everything in it when dropped, so a failing test doesn't leave it behind
+pub struct TempDir {
+    path: PathBuf
+}
+
+impl TempDir {
+    // `name` tells tests apart, the process id parallel runs of the suite
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
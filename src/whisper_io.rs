@@ -0,0 +1,636 @@
+/*!
+
+Header-aware reads and writes on whisper files, done the way `whisper.py`
+does them: seek, read, write. The `whisper` crate creates files for us but
+it does not know about aggregation methods, x-files-factor or rolling points
+up into the lower precision archives, so that logic lives here.
+
+File layout reminder (all big-endian):
+
+ * header: aggregation type (u32), max retention (u32), xFilesFactor (f32), archive count (u32)
+ * archive infos: offset (u32), seconds per point (u32), points (u32)
+ * archives: points of (timestamp u32, value f64)
+
+*/
+
+use std::fmt;
+use std::io::{ self, Read, Write, Seek, SeekFrom };
+
+use byteorder::{ ReadBytesExt, WriteBytesExt, BigEndian };
+
+pub const STATIC_HEADER_SIZE : u64 = 16;
+pub const ARCHIVE_INFO_SIZE : u64 = 12;
+pub const POINT_SIZE : u64 = 12;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AggregationMethod {
+    Average,
+    Sum,
+    Last,
+    Max,
+    Min,
+    AvgZero,
+    AbsMax,
+    AbsMin
+}
+
+impl AggregationMethod {
+    // Numbering matches `aggregationTypeToMethod` in whisper.py
+    pub fn from_u32(val: u32) -> Option<AggregationMethod> {
+        match val {
+            1 => Some(AggregationMethod::Average),
+            2 => Some(AggregationMethod::Sum),
+            3 => Some(AggregationMethod::Last),
+            4 => Some(AggregationMethod::Max),
+            5 => Some(AggregationMethod::Min),
+            6 => Some(AggregationMethod::AvgZero),
+            7 => Some(AggregationMethod::AbsMax),
+            8 => Some(AggregationMethod::AbsMin),
+            _ => None
+        }
+    }
+
+    pub fn to_u32(&self) -> u32 {
+        match *self {
+            AggregationMethod::Average => 1,
+            AggregationMethod::Sum => 2,
+            AggregationMethod::Last => 3,
+            AggregationMethod::Max => 4,
+            AggregationMethod::Min => 5,
+            AggregationMethod::AvgZero => 6,
+            AggregationMethod::AbsMax => 7,
+            AggregationMethod::AbsMin => 8
+        }
+    }
+
+    pub fn from_str(name: &str) -> Option<AggregationMethod> {
+        match name {
+            "average" | "avg" => Some(AggregationMethod::Average),
+            "sum" => Some(AggregationMethod::Sum),
+            "last" => Some(AggregationMethod::Last),
+            "max" => Some(AggregationMethod::Max),
+            "min" => Some(AggregationMethod::Min),
+            "avg_zero" => Some(AggregationMethod::AvgZero),
+            "absmax" => Some(AggregationMethod::AbsMax),
+            "absmin" => Some(AggregationMethod::AbsMin),
+            _ => None
+        }
+    }
+
+    // `known` holds the values that were present, `total` how many slots
+    // there were in the interval. Only avg_zero cares about the difference.
+    pub fn aggregate(&self, known: &[f64], total: usize) -> f64 {
+        match *self {
+            AggregationMethod::Average => known.iter().fold(0.0, |sum, v| sum + v) / known.len() as f64,
+            AggregationMethod::Sum => known.iter().fold(0.0, |sum, v| sum + v),
+            AggregationMethod::Last => known[known.len()-1],
+            AggregationMethod::Max => known.iter().fold(known[0], |max, v| max.max(*v)),
+            AggregationMethod::Min => known.iter().fold(known[0], |min, v| min.min(*v)),
+            AggregationMethod::AvgZero => known.iter().fold(0.0, |sum, v| sum + v) / total as f64,
+            AggregationMethod::AbsMax => known.iter().fold(known[0], |max, v| if v.abs() > max.abs() { *v } else { max }),
+            AggregationMethod::AbsMin => known.iter().fold(known[0], |min, v| if v.abs() < min.abs() { *v } else { min })
+        }
+    }
+}
+
+impl fmt::Display for AggregationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            AggregationMethod::Average => "average",
+            AggregationMethod::Sum => "sum",
+            AggregationMethod::Last => "last",
+            AggregationMethod::Max => "max",
+            AggregationMethod::Min => "min",
+            AggregationMethod::AvgZero => "avg_zero",
+            AggregationMethod::AbsMax => "absmax",
+            AggregationMethod::AbsMin => "absmin"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArchiveInfo {
+    pub offset: u64,
+    pub seconds_per_point: u32,
+    pub points: u32
+}
+
+impl ArchiveInfo {
+    pub fn retention(&self) -> u32 {
+        self.seconds_per_point * self.points
+    }
+
+    pub fn size(&self) -> u64 {
+        self.points as u64 * POINT_SIZE
+    }
+
+    pub fn interval(&self, timestamp: u32) -> u32 {
+        timestamp - (timestamp % self.seconds_per_point)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Metadata {
+    pub aggregation_method: AggregationMethod,
+    pub max_retention: u32,
+    pub x_files_factor: f32,
+    pub archives: Vec<ArchiveInfo>
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "aggregationMethod: {}\nmaxRetention: {}\nxFilesFactor: {}\n",
+                    self.aggregation_method, self.max_retention, self.x_files_factor));
+        for (index, archive) in self.archives.iter().enumerate() {
+            try!(write!(f, "\nArchive {}\n  offset: {}\n  secondsPerPoint: {}\n  points: {}\n  retention: {}\n  size: {}\n",
+                        index, archive.offset, archive.seconds_per_point, archive.points, archive.retention(), archive.size()));
+        }
+        Ok(())
+    }
+}
+
+pub fn read_metadata<F: Read + Seek>(file: &mut F) -> io::Result<Metadata> {
+    try!( file.seek(SeekFrom::Start(0)) );
+
+    let aggregation_type = try!( file.read_u32::<BigEndian>() );
+    let max_retention = try!( file.read_u32::<BigEndian>() );
+    let x_files_factor = try!( file.read_f32::<BigEndian>() );
+    let archive_count = try!( file.read_u32::<BigEndian>() );
+
+    // Files written by older versions of this crate claim type 1 or worse. Treat
+    // anything we don't understand as average, same as whisper.py's default.
+    let aggregation_method = AggregationMethod::from_u32(aggregation_type).unwrap_or(AggregationMethod::Average);
+
+    let mut archives = Vec::with_capacity(archive_count as usize);
+    for _ in 0..archive_count {
+        let offset = try!( file.read_u32::<BigEndian>() );
+        let seconds_per_point = try!( file.read_u32::<BigEndian>() );
+        let points = try!( file.read_u32::<BigEndian>() );
+
+        if seconds_per_point == 0 || points == 0 {
+            return Err(invalid_data("archive with zero seconds per point or points"));
+        }
+
+        archives.push(ArchiveInfo {
+            offset: offset as u64,
+            seconds_per_point: seconds_per_point,
+            points: points
+        });
+    }
+
+    if archives.len() == 0 {
+        return Err(invalid_data("whisper file has no archives"));
+    }
+
+    Ok(Metadata {
+        aggregation_method: aggregation_method,
+        max_retention: max_retention,
+        x_files_factor: x_files_factor,
+        archives: archives
+    })
+}
+
+// Overwrites aggregation type and xFilesFactor in place. Same thing
+// `whisper-set-aggregation-method.py` does.
+pub fn write_aggregation<F: Write + Seek>(file: &mut F, method: AggregationMethod, x_files_factor: f32) -> io::Result<()> {
+    try!( file.seek(SeekFrom::Start(0)) );
+    try!( file.write_u32::<BigEndian>(method.to_u32()) );
+    try!( file.seek(SeekFrom::Start(8)) );
+    try!( file.write_f32::<BigEndian>(x_files_factor) );
+    file.flush()
+}
+
+// Port of whisper.py's `file_update`: find the best archive still covering
+// `timestamp`, write the point there and roll it up into every lower archive.
+pub fn update<F: Read + Write + Seek>(file: &mut F, metadata: &Metadata, now: u32, timestamp: u32, value: f64) -> io::Result<()> {
+    if timestamp > now || now - timestamp > metadata.max_retention {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("timestamp {} not covered by any archive (now: {})", timestamp, now)));
+    }
+    let age = now - timestamp;
+
+    let archive_index = match metadata.archives.iter().position(|archive| archive.retention() >= age) {
+        Some(index) => index,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no archive covers timestamp {}", timestamp)))
+    };
+
+    let archive = &metadata.archives[archive_index];
+    let my_interval = archive.interval(timestamp);
+    try!( write_point(file, archive, my_interval, value) );
+
+    let mut higher = archive;
+    for lower in &metadata.archives[archive_index+1..] {
+        if !try!( propagate(file, metadata, my_interval, higher, lower) ) {
+            break;
+        }
+        higher = lower;
+    }
+
+    Ok(())
+}
+
+// Port of whisper.py's `file_update_many`: each point goes in the finest
+// archive that covers it and lower archives are propagated once per interval
+// touched rather than once per point. Points from the future or from before
+// the file's retention are dropped. Returns how many were written.
+pub fn update_many<F: Read + Write + Seek>(file: &mut F, metadata: &Metadata, now: u32, points: &[(u32, f64)]) -> io::Result<usize> {
+    let mut points : Vec<(u32, f64)> = points.iter().cloned().filter(|&(timestamp, _)| {
+        timestamp <= now && now - timestamp <= metadata.max_retention
+    }).collect();
+    // Oldest first, and the sort is stable, so the last value sent for a slot wins
+    points.sort_by_key(|&(timestamp, _)| timestamp);
+
+    // Each archive takes the newest points that are left and that it covers
+    let mut end = points.len();
+    for archive_index in 0..metadata.archives.len() {
+        if end == 0 {
+            break;
+        }
+        let oldest_covered = now.saturating_sub(metadata.archives[archive_index].retention());
+        let start = points[..end].iter().position(|&(timestamp, _)| timestamp >= oldest_covered).unwrap_or(end);
+        if start < end {
+            try!( archive_update_many(file, metadata, archive_index, &points[start..end]) );
+        }
+        end = start;
+    }
+
+    Ok(points.len())
+}
+
+// `points` are in time order
+fn archive_update_many<F: Read + Write + Seek>(file: &mut F, metadata: &Metadata, archive_index: usize, points: &[(u32, f64)]) -> io::Result<()> {
+    let archive = &metadata.archives[archive_index];
+    let mut base_interval = try!( base_interval(file, archive) );
+
+    let mut intervals : Vec<u32> = Vec::with_capacity(points.len());
+    for &(timestamp, value) in points {
+        let interval = archive.interval(timestamp);
+        // An empty archive gets anchored at its first slot
+        if base_interval == 0 {
+            base_interval = interval;
+        }
+        try!( file.seek(SeekFrom::Start(archive.offset + slot_for(archive, base_interval, interval) * POINT_SIZE)) );
+        try!( file.write_u32::<BigEndian>(interval) );
+        try!( file.write_f64::<BigEndian>(value) );
+        intervals.push(interval);
+    }
+
+    let mut higher = archive;
+    for lower in &metadata.archives[archive_index+1..] {
+        let mut lower_intervals : Vec<u32> = intervals.iter().map(|&interval| lower.interval(interval)).collect();
+        lower_intervals.dedup();
+
+        let mut propagate_further = false;
+        for &interval in &lower_intervals {
+            if try!( propagate(file, metadata, interval, higher, lower) ) {
+                propagate_further = true;
+            }
+        }
+        if !propagate_further {
+            break;
+        }
+        higher = lower;
+    }
+
+    Ok(())
+}
+
+// Rolls the `higher` points covering `timestamp` into a single `lower` point.
+// Returns false when there weren't enough known values to satisfy xFilesFactor,
+// which also means there's no point continuing down the archives.
+pub fn propagate<F: Read + Write + Seek>(file: &mut F, metadata: &Metadata, timestamp: u32, higher: &ArchiveInfo, lower: &ArchiveInfo) -> io::Result<bool> {
+    let lower_interval_start = lower.interval(timestamp);
+    let higher_points = (lower.seconds_per_point / higher.seconds_per_point) as usize;
+
+    let points = try!( read_points(file, higher, lower_interval_start, higher_points) );
+
+    let mut known_values = Vec::with_capacity(higher_points);
+    let mut current_interval = lower_interval_start;
+    for &(point_time, value) in &points {
+        if point_time == current_interval {
+            known_values.push(value);
+        }
+        current_interval += higher.seconds_per_point;
+    }
+
+    if known_values.len() == 0 {
+        return Ok(false);
+    }
+
+    let known_percent = known_values.len() as f32 / points.len() as f32;
+    if known_percent < metadata.x_files_factor {
+        return Ok(false);
+    }
+
+    let aggregate_value = metadata.aggregation_method.aggregate(&known_values, points.len());
+    try!( write_point(file, lower, lower_interval_start, aggregate_value) );
+    Ok(true)
+}
+
+// (from_interval, until_interval, step)
+pub type TimeInfo = (u32, u32, u32);
+
+// Port of whisper.py's `file_fetch`: picks the highest precision archive that
+// still covers `from` and returns one slot per step, `None` where nothing was written.
+pub fn fetch<F: Read + Seek>(file: &mut F, metadata: &Metadata, from: u32, until: u32, now: u32) -> io::Result<Option<(TimeInfo, Vec<Option<f64>>)>> {
+    if from > until {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid time interval: from {} is after until {}", from, until)));
+    }
+
+    let (archive, (from_interval, until_interval, step)) = match fetch_window(&metadata.archives, metadata.max_retention, from, until, now) {
+        Some(window) => window,
+        None => return Ok(None)
+    };
+
+    let slots = ((until_interval - from_interval) / step) as usize;
+    let points = try!( read_points(file, archive, from_interval, slots) );
+
+    let mut values = Vec::with_capacity(slots);
+    let mut current_interval = from_interval;
+    for &(point_time, value) in &points {
+        values.push(if point_time == current_interval { Some(value) } else { None });
+        current_interval += step;
+    }
+    // Asked for more than the archive holds; the rest is unknown.
+    values.resize(slots, None);
+
+    Ok(Some(((from_interval, until_interval, step), values)))
+}
+
+// The archive `fetch` reads and the intervals it returns, None when
+// nothing between `from` and `until` is kept.
+pub fn fetch_window(archives: &[ArchiveInfo], max_retention: u32, from: u32, until: u32, now: u32) -> Option<(&ArchiveInfo, TimeInfo)> {
+    let oldest_time = now.saturating_sub(max_retention);
+    if from > now || until < oldest_time {
+        return None;
+    }
+
+    let from = if from < oldest_time { oldest_time } else { from };
+    let until = if until > now { now } else { until };
+
+    let diff = now - from;
+    let archive = archives.iter()
+                          .find(|archive| archive.retention() >= diff)
+                          .unwrap_or(&archives[archives.len()-1]);
+
+    let step = archive.seconds_per_point;
+    let from_interval = archive.interval(from) + step;
+    let mut until_interval = archive.interval(until) + step;
+    if from_interval == until_interval {
+        until_interval += step;
+    }
+
+    Some((archive, (from_interval, until_interval, step)))
+}
+
+// Reads `count` consecutive slots starting at the slot `interval` lives in,
+// wrapping around the end of the archive. Slots hold whatever timestamp was last
+// written to them; callers compare against the interval they expected.
+pub fn read_points<F: Read + Seek>(file: &mut F, archive: &ArchiveInfo, interval: u32, count: usize) -> io::Result<Vec<(u32, f64)>> {
+    let count = if count > archive.points as usize { archive.points as usize } else { count };
+    let mut points = Vec::with_capacity(count);
+
+    let base_interval = try!( base_interval(file, archive) );
+    let mut slot = if base_interval == 0 {
+        0
+    } else {
+        slot_for(archive, base_interval, interval)
+    };
+
+    try!( file.seek(SeekFrom::Start(archive.offset + slot * POINT_SIZE)) );
+    for _ in 0..count {
+        if slot == archive.points as u64 {
+            slot = 0;
+            try!( file.seek(SeekFrom::Start(archive.offset)) );
+        }
+        let point_time = try!( file.read_u32::<BigEndian>() );
+        let value = try!( file.read_f64::<BigEndian>() );
+        points.push((point_time, value));
+        slot += 1;
+    }
+
+    Ok(points)
+}
+
+// Every slot of an archive in on-disk order, for dumping
+pub fn read_archive<F: Read + Seek>(file: &mut F, archive: &ArchiveInfo) -> io::Result<Vec<(u32, f64)>> {
+    let mut points = Vec::with_capacity(archive.points as usize);
+    try!( file.seek(SeekFrom::Start(archive.offset)) );
+    for _ in 0..archive.points {
+        let point_time = try!( file.read_u32::<BigEndian>() );
+        let value = try!( file.read_f64::<BigEndian>() );
+        points.push((point_time, value));
+    }
+    Ok(points)
+}
+
+pub fn write_point<F: Read + Write + Seek>(file: &mut F, archive: &ArchiveInfo, interval: u32, value: f64) -> io::Result<()> {
+    let base_interval = try!( base_interval(file, archive) );
+
+    // An empty archive gets anchored at its first slot
+    let slot = if base_interval == 0 {
+        0
+    } else {
+        slot_for(archive, base_interval, interval)
+    };
+
+    try!( file.seek(SeekFrom::Start(archive.offset + slot * POINT_SIZE)) );
+    try!( file.write_u32::<BigEndian>(interval) );
+    try!( file.write_f64::<BigEndian>(value) );
+    Ok(())
+}
+
+fn base_interval<F: Read + Seek>(file: &mut F, archive: &ArchiveInfo) -> io::Result<u32> {
+    try!( file.seek(SeekFrom::Start(archive.offset)) );
+    file.read_u32::<BigEndian>()
+}
+
+fn slot_for(archive: &ArchiveInfo, base_interval: u32, interval: u32) -> u64 {
+    let time_distance = interval as i64 - base_interval as i64;
+    let point_distance = floor_div(time_distance, archive.seconds_per_point as i64);
+    py_mod(point_distance, archive.points as i64) as u64
+}
+
+fn floor_div(a: i64, b: i64) -> i64 {
+    let quotient = a / b;
+    if (a % b != 0) && ((a < 0) != (b < 0)) { quotient - 1 } else { quotient }
+}
+
+fn py_mod(a: i64, b: i64) -> i64 {
+    ((a % b) + b) % b
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Cursor;
+    use byteorder::{ WriteBytesExt, BigEndian };
+
+    use super::*;
+
+    // Lays out an empty whisper file in memory, the same bytes
+    // `whisper-create.py` would produce.
+    pub fn empty_file(method: AggregationMethod, xff: f32, archives: &[(u32, u32)]) -> Cursor<Vec<u8>> {
+        let mut buf : Vec<u8> = vec![];
+        let max_retention = archives.iter().map(|&(spp, points)| spp * points).max().unwrap();
+        buf.write_u32::<BigEndian>(method.to_u32()).unwrap();
+        buf.write_u32::<BigEndian>(max_retention).unwrap();
+        buf.write_f32::<BigEndian>(xff).unwrap();
+        buf.write_u32::<BigEndian>(archives.len() as u32).unwrap();
+
+        let mut offset = STATIC_HEADER_SIZE + ARCHIVE_INFO_SIZE * archives.len() as u64;
+        for &(spp, points) in archives {
+            buf.write_u32::<BigEndian>(offset as u32).unwrap();
+            buf.write_u32::<BigEndian>(spp).unwrap();
+            buf.write_u32::<BigEndian>(points).unwrap();
+            offset += points as u64 * POINT_SIZE;
+        }
+        buf.resize(offset as usize, 0);
+        Cursor::new(buf)
+    }
+
+    #[test]
+    fn reads_header(){
+        let mut file = empty_file(AggregationMethod::Sum, 0.1, &[(10, 6), (60, 10)]);
+        let metadata = read_metadata(&mut file).unwrap();
+        assert_eq!(metadata.aggregation_method, AggregationMethod::Sum);
+        assert_eq!(metadata.x_files_factor, 0.1);
+        assert_eq!(metadata.max_retention, 600);
+        assert_eq!(metadata.archives[0], ArchiveInfo { offset: 40, seconds_per_point: 10, points: 6 });
+        assert_eq!(metadata.archives[1].offset, 40 + 6*12);
+    }
+
+    #[test]
+    fn rewrites_aggregation(){
+        let mut file = empty_file(AggregationMethod::Average, 0.5, &[(10, 6)]);
+        write_aggregation(&mut file, AggregationMethod::Max, 0.0).unwrap();
+        let metadata = read_metadata(&mut file).unwrap();
+        assert_eq!(metadata.aggregation_method, AggregationMethod::Max);
+        assert_eq!(metadata.x_files_factor, 0.0);
+    }
+
+    #[test]
+    fn sums_into_lower_archive(){
+        let mut file = empty_file(AggregationMethod::Sum, 0.5, &[(10, 6), (60, 10)]);
+        let metadata = read_metadata(&mut file).unwrap();
+        let now = 1200;
+
+        for &(ts, val) in &[(1140, 1.0), (1150, 2.0), (1160, 3.0)] {
+            update(&mut file, &metadata, now, ts, val).unwrap();
+        }
+
+        let lower = read_points(&mut file, &metadata.archives[1], 1140, 1).unwrap();
+        assert_eq!(lower, vec![(1140, 6.0)]);
+    }
+
+    #[test]
+    fn respects_x_files_factor(){
+        let mut file = empty_file(AggregationMethod::Average, 0.5, &[(10, 6), (60, 10)]);
+        let metadata = read_metadata(&mut file).unwrap();
+        let now = 1200;
+
+        // 2 out of 6 slots known is below 50%
+        update(&mut file, &metadata, now, 1140, 1.0).unwrap();
+        update(&mut file, &metadata, now, 1150, 3.0).unwrap();
+        assert_eq!(read_points(&mut file, &metadata.archives[1], 1140, 1).unwrap(), vec![(0, 0.0)]);
+
+        update(&mut file, &metadata, now, 1160, 5.0).unwrap();
+        assert_eq!(read_points(&mut file, &metadata.archives[1], 1140, 1).unwrap(), vec![(1140, 3.0)]);
+    }
+
+    #[test]
+    fn update_many_matches_one_at_a_time(){
+        let archives = [(10, 6), (60, 10)];
+        let now = 1200;
+        // Out of order, a repeated slot, one only the coarse archive covers,
+        // one from the future and one older than the file
+        let batch = [(1150, 2.0), (1140, 1.0), (1155, 9.0), (1160, 3.0), (700, 4.0), (1300, 5.0), (100, 6.0)];
+
+        let mut batched = empty_file(AggregationMethod::Sum, 0.5, &archives);
+        let metadata = read_metadata(&mut batched).unwrap();
+        assert_eq!(update_many(&mut batched, &metadata, now, &batch).unwrap(), 5);
+
+        // Finest archive's points first, as the batch writes them
+        let mut one_by_one = empty_file(AggregationMethod::Sum, 0.5, &archives);
+        for &(ts, val) in &[(1140, 1.0), (1150, 2.0), (1155, 9.0), (1160, 3.0), (700, 4.0)] {
+            update(&mut one_by_one, &metadata, now, ts, val).unwrap();
+        }
+
+        assert_eq!(batched.into_inner(), one_by_one.into_inner());
+    }
+
+    #[test]
+    fn oldest_point_the_file_covers(){
+        let mut file = empty_file(AggregationMethod::Average, 0.5, &[(10, 6), (60, 10)]);
+        let metadata = read_metadata(&mut file).unwrap();
+
+        // Exactly max_retention old is kept, like whisper.py
+        assert_eq!(update_many(&mut file, &metadata, 1200, &[(600, 7.0), (599, 8.0)]).unwrap(), 1);
+        assert_eq!(read_points(&mut file, &metadata.archives[1], 600, 1).unwrap(), vec![(600, 7.0)]);
+        assert!(update(&mut file, &metadata, 1260, 660, 9.0).is_ok());
+        assert!(update(&mut file, &metadata, 1260, 659, 9.0).is_err());
+    }
+
+    #[test]
+    fn old_points_skip_to_coarser_archive(){
+        let mut file = empty_file(AggregationMethod::Average, 0.5, &[(10, 6), (60, 10)]);
+        let metadata = read_metadata(&mut file).unwrap();
+
+        update(&mut file, &metadata, 1200, 700, 9.0).unwrap();
+        assert_eq!(read_points(&mut file, &metadata.archives[1], 660, 1).unwrap(), vec![(660, 9.0)]);
+        assert!(update(&mut file, &metadata, 1200, 10, 9.0).is_err());
+    }
+
+    #[test]
+    fn wraps_around_archive(){
+        let mut file = empty_file(AggregationMethod::Average, 0.5, &[(10, 3)]);
+        let metadata = read_metadata(&mut file).unwrap();
+
+        for &(ts, val) in &[(100, 1.0), (110, 2.0), (120, 3.0), (130, 4.0)] {
+            update(&mut file, &metadata, ts, ts, val).unwrap();
+        }
+
+        let points = read_points(&mut file, &metadata.archives[0], 110, 3).unwrap();
+        assert_eq!(points, vec![(110, 2.0), (120, 3.0), (130, 4.0)]);
+    }
+
+    #[test]
+    fn fetches_from_best_archive(){
+        let mut file = empty_file(AggregationMethod::Average, 0.5, &[(10, 6), (60, 10)]);
+        let metadata = read_metadata(&mut file).unwrap();
+        let now = 1200;
+
+        for &(ts, val) in &[(1150, 1.0), (1170, 3.0)] {
+            update(&mut file, &metadata, now, ts, val).unwrap();
+        }
+
+        let (time_info, values) = fetch(&mut file, &metadata, 1145, 1185, now).unwrap().unwrap();
+        assert_eq!(time_info, (1150, 1190, 10));
+        assert_eq!(values, vec![Some(1.0), None, Some(3.0), None]);
+
+        // older than archive 0 covers, so the minutely archive answers
+        let (time_info, values) = fetch(&mut file, &metadata, 700, 1000, now).unwrap().unwrap();
+        assert_eq!(time_info.2, 60);
+        assert!(values.iter().all(|v| v.is_none()));
+
+        assert!(fetch(&mut file, &metadata, 1300, 1400, now).unwrap().is_none());
+        assert!(fetch(&mut file, &metadata, 1000, 900, now).is_err());
+    }
+
+    #[test]
+    fn aggregation_methods(){
+        let known = [1.0, -4.0, 3.0];
+        assert_eq!(AggregationMethod::Average.aggregate(&known, 3), 0.0);
+        assert_eq!(AggregationMethod::Sum.aggregate(&known, 3), 0.0);
+        assert_eq!(AggregationMethod::Last.aggregate(&known, 3), 3.0);
+        assert_eq!(AggregationMethod::Max.aggregate(&known, 3), 3.0);
+        assert_eq!(AggregationMethod::Min.aggregate(&known, 3), -4.0);
+        assert_eq!(AggregationMethod::AvgZero.aggregate(&[2.0, 4.0], 4), 1.5);
+        assert_eq!(AggregationMethod::AbsMax.aggregate(&known, 3), -4.0);
+        assert_eq!(AggregationMethod::AbsMin.aggregate(&known, 3), 1.0);
+    }
+}
@@ -0,0 +1,93 @@
+#!/usr/bin/env python3
+# Opens CONNECTIONS connections to carbon's plaintext port and holds them all
+# open at once, sends LINES lines down each, keeps them open for HOLD more
+# seconds and then closes them. Reports how many connections carbon took,
+# how many it hung up on, and how long the sending took. For comparing
+# `--tcp-mode threaded` and `--tcp-mode evented`:
+#
+#   carbon --tcp-mode evented &
+#   test/scripts/tcp_load.py --connections 5000 --lines 100 --hold 5
+#
+# Both ends need a file descriptor per connection, raise `ulimit -n` to suit.
+# Metrics go to load.m<n> for n below METRICS, so the writer stays busy
+# without creating a file per connection.
+
+import argparse
+import errno
+import selectors
+import socket
+import time
+
+parser = argparse.ArgumentParser()
+parser.add_argument("--host", default="127.0.0.1")
+parser.add_argument("--port", type=int, default=2003)
+parser.add_argument("--connections", type=int, default=1000)
+parser.add_argument("--lines", type=int, default=100)
+parser.add_argument("--metrics", type=int, default=100)
+parser.add_argument("--hold", type=float, default=0)
+parser.add_argument("--timeout", type=float, default=30)
+args = parser.parse_args()
+
+now = int(time.time())
+selector = selectors.DefaultSelector()
+
+def body(connection):
+    lines = ("load.m%d %d %d\n" % ((connection * args.lines + line) % args.metrics, line, now) for line in range(args.lines))
+    return "".join(lines).encode()
+
+# Every connect goes out before any is waited on, so they're all pending
+# at once
+start = time.time()
+failed = 0
+pending = {}
+for connection in range(args.connections):
+    sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    sock.setblocking(False)
+    err = sock.connect_ex((args.host, args.port))
+    if err not in (0, errno.EINPROGRESS):
+        failed += 1
+        sock.close()
+        continue
+    pending[sock] = body(connection)
+    selector.register(sock, selectors.EVENT_WRITE)
+
+# Then each is sent its lines as it becomes writable
+held = []
+deadline = time.time() + args.timeout
+while pending and time.time() < deadline:
+    for key, _ in selector.select(timeout=1):
+        sock = key.fileobj
+        try:
+            sent = sock.send(pending[sock])
+        except OSError:
+            failed += 1
+            selector.unregister(sock)
+            sock.close()
+            del pending[sock]
+            continue
+        pending[sock] = pending[sock][sent:]
+        if not pending[sock]:
+            selector.unregister(sock)
+            del pending[sock]
+            held.append(sock)
+elapsed = time.time() - start
+unfinished = len(pending)
+
+# Still connected at the end, rather than hung up on by carbon
+time.sleep(args.hold)
+closed_by_carbon = 0
+for sock in held:
+    try:
+        if sock.recv(1) == b"":
+            closed_by_carbon += 1
+    except BlockingIOError:
+        pass
+    except OSError:
+        closed_by_carbon += 1
+for sock in held + list(pending):
+    sock.close()
+
+print("%d/%d connections held at once, %d failed, %d unfinished, %d closed by carbon" % (
+    len(held), args.connections, failed, unfinished, closed_by_carbon))
+print("%d lines in %.2fs (%.0f lines/s)" % (
+    len(held) * args.lines, elapsed, len(held) * args.lines / max(elapsed, 0.001)))